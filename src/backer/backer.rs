@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -5,18 +7,35 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use job_scheduler::{Job, JobScheduler};
-use log::{error, info};
+use log::{error, info, warn};
 use qiniu_upload_manager::{AutoUploader, AutoUploaderObjectParams, UploadManager, UploadTokenSigner};
 use qiniu_upload_manager::apis::credential::Credential;
 use tokio::{runtime::{Builder, Runtime}, task::JoinHandle};
 
-use crate::config::config::{AliyunOssServer, BackerConfig, BackerServer, QiniuServer, TencentOssServer};
+use crate::backer::state::ChangeGate;
+use crate::backer::summary::JobSummary;
+use crate::catalog::catalog::Catalog;
+use crate::config::config::{AliyunOssServer, BackerConfig, BackerServer, LocalServer, QiniuServer, TencentOssServer};
 use crate::consts;
+use crate::crypto::crypto;
+use crate::hook::hook;
+use crate::index::index;
+use crate::manifest::manifest;
+use crate::manifest::manifest::Manifest;
 use crate::packet::message::{FileBuffer, Message, Protocol};
 use crate::packet::tcp_packet::{Dispatch, Handler, TcpClient};
-use crate::utils::file;
+use crate::repository::repository;
+use crate::repository::repository::Repository;
+use crate::restore::restore;
+use crate::source::source;
+use crate::storage::storage;
+use crate::utils::{file, parallel};
+use crate::utils::staging::Staging;
+use crate::verify::verify;
+use crate::verify::verify::VerifyOptions;
+use crate::volume::volume;
 
 const MAX_BUFFER_LENGTH: usize = 20480;
 
@@ -33,7 +52,6 @@ pub struct Backer {
     state: BackerState,
     completed_state: CompletedState,
     rt: Runtime,
-    threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl Backer {
@@ -41,8 +59,7 @@ impl Backer {
         let state = Arc::new(Mutex::new(State::Running));
         let completed_state = Arc::new(AtomicBool::new(false));
         let rt = Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
-        let threads = Default::default();
-        Ok(Backer { state, completed_state, rt, threads })
+        Ok(Backer { state, completed_state, rt })
     }
 
     pub fn start<P: AsRef<Path>>(&self, config_path: P) -> Result<()> {
@@ -53,19 +70,37 @@ impl Backer {
             }
         };
         let thread_state = self.state.clone();
-        self.run(thread_state, config).unwrap();
-        Ok(())
+        self.run(thread_state, config)
     }
 
     fn run(&self, state: BackerState, cfg: BackerConfig) -> Result<()> {
         info!("==================== Running Backer ====================");
         let cron = cfg.clone().job_cron;
+        // set while a verify job runs, a verify job still running when the next is due is skipped
+        let verifying = Arc::new(AtomicBool::new(false));
         let mut sched = JobScheduler::new();
 
-        sched.add(Job::new(cron.parse().unwrap(), || {
+        let schedule = cron.parse().map_err(|e| anyhow!("job cron invalid: {}", e))?;
+        sched.add(Job::new(schedule, || {
             let thread_cfg = Arc::new(cfg.clone());
             self.backup_job(thread_cfg);
         }));
+        if cfg.verify.enabled {
+            // a verify job fetches whole archives, it runs on its own thread so backups stay on time
+            let schedule = cfg.verify.cron.parse().map_err(|e| anyhow!("verify cron invalid: {}", e))?;
+            sched.add(Job::new(schedule, || {
+                if verifying.swap(true, Ordering::SeqCst) {
+                    warn!("the last verify job is still running, skip this one");
+                    return;
+                }
+                let cfg = Arc::new(cfg.clone());
+                let verifying = verifying.clone();
+                thread::spawn(move || {
+                    Self::verify_job(&cfg);
+                    verifying.store(false, Ordering::SeqCst);
+                });
+            }));
+        }
 
         loop {
             let state = &*state;
@@ -88,100 +123,396 @@ impl Backer {
         let mut state_guard = state.lock().unwrap();
         *state_guard = State::Terminated;
         drop(state_guard);
-        // self.job.take().unwrap().join().unwrap();
-        self.rt.block_on(async move {
-            for t in self.threads.lock().unwrap().drain(..) {
-                let _ = t.await;
-            }
-        });
         info!("Gracefully stopped");
     }
 
     fn backup_job(&self, cfg: Arc<BackerConfig>) {
+        let mut summary = JobSummary::new(cfg.backup_format.as_str(), &cfg.backup_target);
+        if let Err(e) = hook::run_hooks(consts::HOOK_PRE, &cfg.pre_hooks, &summary) {
+            error!("abort backup job: {}", e);
+            summary.finish(consts::JOB_STATUS_FAILURE, e.to_string());
+            let _ = hook::run_hooks(consts::HOOK_ON_FAILURE, &cfg.on_failure, &summary);
+            Self::record_run(&cfg, &summary);
+            summary.log();
+            return;
+        }
+        let res = source::resolve_backup_files(&cfg.backup_files, &cfg.path_mapping).and_then(|backup_files| {
+            // pre-hooks may have changed the sources, so they are only compared now
+            let gate = ChangeGate::check(&cfg, &backup_files);
+            if let Some(backup_time) = gate.unchanged_since() {
+                info!("nothing changed since the backup at {}, skip the backup", backup_time);
+                return Ok(false);
+            }
+            if cfg.backup_format == consts::BACKUP_FORMAT_REPOSITORY {
+                self.repository_backup_job(cfg.clone(), backup_files, &mut summary)?;
+            } else {
+                self.archive_backup_job(cfg.clone(), backup_files, &mut summary)?;
+            }
+            gate.save();
+            Ok(true)
+        });
+        match res {
+            Ok(true) => summary.finish(consts::JOB_STATUS_SUCCESS, String::new()),
+            Ok(false) => summary.finish(consts::JOB_STATUS_UNCHANGED, String::new()),
+            Err(e) => summary.finish(consts::JOB_STATUS_FAILURE, e.to_string()),
+        }
+        let _ = hook::run_hooks(consts::HOOK_POST, &cfg.post_hooks, &summary);
+        if summary.status == consts::JOB_STATUS_FAILURE {
+            let _ = hook::run_hooks(consts::HOOK_ON_FAILURE, &cfg.on_failure, &summary);
+        }
+        Self::record_run(&cfg, &summary);
+        summary.log();
+    }
+
+    // results only go to the log, `backer verify` also has them in its exit code
+    fn verify_job(cfg: &BackerConfig) {
+        info!("Executing verify job.");
+        let mut options = VerifyOptions::new(cfg.backup_target.clone(), cfg.verify.archives.clone());
+        options.public_key = cfg.verify.public_key.clone();
+        match restore::config_identities(cfg) {
+            Ok(identities) => options.identities = identities,
+            Err(e) => warn!("load encryption keys failed, encrypted archives are only hashed: {}", e),
+        }
+        match verify::verify(cfg, &options) {
+            Ok(checks) => {
+                let failed = checks.iter().filter(|check| !check.ok).count();
+                if failed > 0 {
+                    error!("verify job finished, {} of {} checks failed", failed, checks.len());
+                } else {
+                    info!("verify job finished, {} archives are ok", checks.len());
+                }
+            }
+            Err(e) => error!("verify job failed: {}", e),
+        }
+    }
+
+    // a run missing from the catalog doesn't fail the backup
+    fn record_run(cfg: &BackerConfig, summary: &JobSummary) {
+        if !cfg.catalog.enabled {
+            return;
+        }
+        match Catalog::open(cfg.catalog.path.as_str()).and_then(|mut catalog| catalog.record_run(summary)) {
+            Ok(id) => info!("backup run recorded in the catalog as #{}", id),
+            Err(e) => warn!("record backup run in the catalog failed: {}", e),
+        }
+    }
+
+    fn archive_backup_job(&self, cfg: Arc<BackerConfig>, mut backup_files: Vec<file::BackupPath>, summary: &mut JobSummary) -> Result<()> {
         info!("Executing backup job.");
-        let mut compress_mode = file::CompressType::Zip;
+        source::check_archive_names(&backup_files, &cfg.sources)?;
+        let backup_paths = backup_files.iter().map(|p| p.path.clone()).collect::<Vec<String>>();
+        let staging = Self::prepare_staging(&cfg, &backup_paths).map_err(|e| anyhow!("prepare staging dir failed: {}", e))?;
+        let compress_mode = file::CompressType::from_mode(cfg.compress_mode.as_str());
         let now = chrono::Local::now().format("%F_%T").to_string();
 
         let mode = cfg.compress_mode.clone();
-        if mode == consts::COMPRESS_MODE_TAR {
-            compress_mode = file::CompressType::Tar;
-        }
-        let archive_file_name = String::from(format!("Archive-{}.{}", now, mode));
-        let target_path = file::get_archive_dir_path().join(archive_file_name).to_str().unwrap().to_string();
+        let mut compress_options = file::CompressOptions::new(compress_mode);
+        compress_options.threads = parallel::compress_threads(cfg.compress_threads);
+        compress_options.change_retries = cfg.changed_files.retries;
+        compress_options.change_policy = file::ChangePolicy::from_policy(cfg.changed_files.policy.as_str());
+        compress_options.follow_symlinks = cfg.follow_symlinks;
+        compress_options.one_file_system = cfg.one_file_system;
+        compress_options.deterministic = cfg.deterministic;
+        compress_options.store_policy = file::StorePolicy::new(&cfg.store_uncompressed.extensions, cfg.store_uncompressed.entropy_sampling);
+        let archive_file_name = format!("{}-{}.{}", cfg.archive_prefix, now, mode);
+        let target_path = staging.run_dir().join(archive_file_name).to_str().unwrap().to_string();
+        summary.archive = target_path.clone();
 
-        let res = file::compress_files(cfg.backup_files.clone(), target_path.clone(), compress_mode);
+        backup_files.extend(source::snapshot_sources(&cfg.sources, staging.run_dir())?.into_iter().map(file::BackupPath::from_basename));
+        let report = file::compress_files(backup_files, source::command_streams(&cfg.sources), target_path.clone(), &compress_options)
+            .map_err(|e| anyhow!("compress files failed: {}", e))?;
+        summary.add_compress_report(&report);
         info!("Compress files success.");
-        match res {
-            Ok(_) => {
-                let archive_file = file::read_file_info_without_file_data(target_path.clone());
-                match archive_file {
-                    Ok(archive_file_info) => {
-                        for target in cfg.backup_target.clone() {
-                            match target.as_str() {
-                                consts::BACKUP_TARGET_BACKER_SERVER => {
-                                    let archive_file = file::read_file_info(target_path.clone());
-                                    match archive_file {
-                                        Ok(archive_file_info) => {
-                                            let file_info = file::FileInfo::new(archive_file_info.file_name.clone(), archive_file_info.absolute_path.clone(), archive_file_info.file_data.clone());
-                                            let backer_server = cfg.backer_server.clone();
-                                            let completed = self.completed_state.clone();
-                                            self.threads.lock().unwrap().push(self.rt.spawn(async move {
-                                                Self::backup_file_to_backer_server(backer_server.clone(), file_info, completed).await;
-                                            }));
-                                        }
-                                        Err(e) => error!("read archive file failed: {}", e)
-                                    }
-                                }
-                                consts::BACKUP_TARGET_QINIU => {
-                                    let file_info = file::FileInfo::new(archive_file_info.file_name.clone(), archive_file_info.absolute_path.clone(), Default::default());
-                                    let qiniu = cfg.qiniu.clone();
-                                    self.threads.lock().unwrap().push(self.rt.spawn(async move {
-                                        Self::backup_file_to_qiniu(qiniu.clone(), file_info).await;
-                                    }));
-                                }
-                                consts::BACKUP_TARGET_ALIYUN_OSS => {
-                                    let file_info = file::FileInfo::new(archive_file_info.file_name.clone(), archive_file_info.absolute_path.clone(), Default::default());
-                                    let aliyun = cfg.aliyun_oss.clone();
-                                    self.threads.lock().unwrap().push(self.rt.spawn(async move {
-                                        Self::backup_file_to_aliyun_oss(aliyun.clone(), file_info).await;
-                                    }));
-                                }
-                                consts::BACKUP_TARGET_TENCENT_OSS => {
-                                    let file_info = file::FileInfo::new(archive_file_info.file_name.clone(), archive_file_info.absolute_path.clone(), Default::default());
-                                    let tencent = cfg.tencent_oss.clone();
-                                    self.threads.lock().unwrap().push(self.rt.spawn(async move {
-                                        Self::backup_file_to_tencent_oss(tencent.clone(), file_info).await;
-                                    }));
-                                }
-                                _ => {
-                                    error!("can't find target server: [{}]", target.as_str())
-                                }
-                            }
+        // offsets into an encrypted archive don't point at the blocks, it's restored whole
+        let tar_index = report.tar_index.filter(|_| cfg.tar_index && !cfg.encryption.enabled);
+        let entries = if cfg.manifest.enabled {
+            manifest::archive_entries(target_path.as_str(), &compress_mode)
+                .map_err(|e| anyhow!("read archive entries failed: {}", e))?
+        } else {
+            vec![]
+        };
+        let hashes = entries.iter().filter(|entry| !entry.sha256.is_empty())
+            .map(|entry| (entry.path.as_str(), entry.sha256.as_str()))
+            .collect::<HashMap<&str, &str>>();
+        for entry in summary.entries.iter_mut() {
+            if let Some(sha256) = hashes.get(entry.path.as_str()) {
+                entry.sha256 = sha256.to_string();
+            }
+        }
+        let target_path = Self::encrypt_archive(&cfg, target_path.clone())
+            .map_err(|e| anyhow!("encrypt archive failed: {}", e))?;
+        summary.archive = target_path.clone();
+        summary.archive_size = file::file_size(target_path.as_str()).unwrap_or(0);
+        if cfg.catalog.enabled && !cfg.manifest.enabled {
+            summary.archive_sha256 = file::sha256_file(target_path.as_str()).unwrap_or_default();
+        }
+        let mut upload_paths = vec![target_path.clone()];
+        if cfg.volume_size > 0 && file::file_size(target_path.as_str()).unwrap_or(0) > cfg.volume_size {
+            let paths = volume::split_archive(target_path.as_str(), cfg.volume_size)
+                .map_err(|e| anyhow!("split archive failed: {}", e))?;
+            info!("Split archive into {} volumes.", paths.len() - 1);
+            upload_paths = paths;
+        }
+        if cfg.manifest.enabled {
+            let paths = Manifest::new(target_path.as_str(), entries).and_then(|archive_manifest| {
+                summary.archive_sha256 = archive_manifest.archive.sha256.clone();
+                manifest::write_manifest(&archive_manifest, staging.run_dir(), cfg.manifest.signing_key_file.as_str())
+            }).map_err(|e| anyhow!("write manifest failed: {}", e))?;
+            upload_paths.extend(paths);
+        }
+        if let Some(mut tar_index) = tar_index {
+            tar_index.archive = file::get_file_name(target_path.as_str()).map_err(|e| anyhow!("{}", e))?;
+            let path = tar_index.write(staging.run_dir()).map_err(|e| anyhow!("write tar index failed: {}", e))?;
+            info!("Write {} with {} entries.", index::index_file_name(tar_index.archive.as_str()), tar_index.entries.len());
+            upload_paths.push(path);
+        }
+        let res = self.upload_files(&cfg, &upload_paths, summary);
+        // the run dir and everything left in it is removed with the staging
+        drop(staging);
+        info!("remove archive file");
+        res
+    }
+
+    // upload the archive and its sidecar files to every target
+    fn upload_files(&self, cfg: &BackerConfig, upload_paths: &[String], summary: &mut JobSummary) -> Result<()> {
+        let mut archive_files = vec![];
+        for path in upload_paths {
+            let archive_file_info = file::read_file_info_without_file_data(path.as_str())
+                .map_err(|e| anyhow!("read archive file failed: {}", e))?;
+            archive_files.push(archive_file_info);
+        }
+        let mut uploads: Vec<(String, JoinHandle<Result<()>>)> = vec![];
+        for target in cfg.backup_target.clone() {
+            let files = archive_files.clone();
+            match target.as_str() {
+                consts::BACKUP_TARGET_BACKER_SERVER => {
+                    let backer_server = cfg.backer_server.clone();
+                    let completed = self.completed_state.clone();
+                    uploads.push((target, self.rt.spawn(async move {
+                        if Self::backup_files_to_backer_server(backer_server.clone(), files, completed).await {
+                            Ok(())
+                        } else {
+                            Err(anyhow!("upload to backer server failed"))
                         }
-                        self.rt.block_on(async move {
-                            for t in self.threads.lock().unwrap().drain(..) {
-                                let _ = t.await;
-                            }
-                        });
-                    }
-                    Err(e) => error!("read archive file failed: {}", e)
+                    })));
+                }
+                consts::BACKUP_TARGET_QINIU => {
+                    let qiniu = cfg.qiniu.clone();
+                    uploads.push((target, self.rt.spawn(async move {
+                        Self::backup_files_to_qiniu(qiniu.clone(), files).await
+                    })));
+                }
+                consts::BACKUP_TARGET_ALIYUN_OSS => {
+                    let aliyun = cfg.aliyun_oss.clone();
+                    uploads.push((target, self.rt.spawn(async move {
+                        Self::backup_files_to_aliyun_oss(aliyun.clone(), files).await
+                    })));
+                }
+                consts::BACKUP_TARGET_TENCENT_OSS => {
+                    let tencent = cfg.tencent_oss.clone();
+                    uploads.push((target, self.rt.spawn(async move {
+                        Self::backup_files_to_tencent_oss(tencent.clone(), files).await
+                    })));
+                }
+                consts::BACKUP_TARGET_LOCAL => {
+                    let local = cfg.local.clone();
+                    uploads.push((target, self.rt.spawn(async move {
+                        Self::backup_files_to_local(local.clone(), files).await
+                    })));
+                }
+                _ => {
+                    error!("can't find target server: [{}]", target.as_str())
                 }
-                // remove compress file
-                file::rm_file(target_path.clone()).unwrap();
-                info!("remove archive file");
             }
-            Err(e) => {
-                error!("compress files failed: {}", e);
+        }
+        let results = self.rt.block_on(async move {
+            let mut results = vec![];
+            for (target, upload) in uploads {
+                let res = upload.await.unwrap_or_else(|e| Err(anyhow!("{}", e)));
+                results.push((target, res));
+            }
+            results
+        });
+        let mut failed = vec![];
+        for (target, res) in results {
+            if let Err(e) = &res {
+                error!("backup to [{}] failed: {}", target, e);
+                failed.push(target.clone());
             }
+            summary.add_target_result(target.as_str(), &res);
         }
+        if !failed.is_empty() {
+            return Err(anyhow!("backup to [{}] failed", failed.join(", ")));
+        }
+        Ok(())
     }
 
-    async fn backup_file_to_backer_server(cfg: BackerServer, archive_file: file::FileInfo, completed: Arc<AtomicBool>) {
+    // lock the staging dir, clean up after crashed runs and make sure the archive fits
+    fn prepare_staging(cfg: &BackerConfig, backup_files: &[String]) -> Result<Staging> {
+        let staging = Staging::prepare(cfg.staging_dir.as_str())?;
+        let mut copies = 1;
+        if cfg.backup_format == consts::BACKUP_FORMAT_ARCHIVE {
+            if cfg.encryption.enabled {
+                copies += 1;
+            }
+            if cfg.volume_size > 0 {
+                copies += 1;
+            }
+        }
+        let source_paths = cfg.sources.iter().map(|source| source.path.clone()).collect::<Vec<String>>();
+        let mut size = file::estimate_size(&source_paths);
+        // a repository only stages the snapshots of the sources and the packs of new chunks, which
+        // aren't known before chunking, so the backup files aren't counted
+        if cfg.backup_format == consts::BACKUP_FORMAT_ARCHIVE {
+            size += file::estimate_size(backup_files);
+        }
+        staging.check_free_space(size * copies)?;
+        Ok(staging)
+    }
+
+    // encrypt the archive when configured, returns the path of the file to upload
+    fn encrypt_archive(cfg: &BackerConfig, archive_path: String) -> Result<String> {
+        if !cfg.encryption.enabled {
+            return Ok(archive_path);
+        }
+        let encrypted_path = format!("{}.{}", archive_path, consts::ENCRYPTED_ARCHIVE_SUFFIX);
+        let res = crypto::encrypt_file(archive_path.as_str(), encrypted_path.as_str(), &cfg.encryption);
+        file::rm_file(archive_path.as_str())?;
+        if let Err(e) = res {
+            let _ = file::rm_file(encrypted_path.as_str());
+            return Err(e);
+        }
+        info!("Encrypt archive success.");
+        Ok(encrypted_path)
+    }
+
+    fn repository_backup_job(&self, cfg: Arc<BackerConfig>, mut backup_files: Vec<file::BackupPath>, summary: &mut JobSummary) -> Result<()> {
+        info!("Executing repository backup job.");
+        let paths = backup_files.iter().map(|p| p.path.clone()).collect::<Vec<String>>();
+        let staging = Self::prepare_staging(&cfg, &paths).map_err(|e| anyhow!("prepare staging dir failed: {}", e))?;
+        backup_files.extend(source::snapshot_sources(&cfg.sources, staging.run_dir())?.into_iter().map(file::BackupPath::from_basename));
+        let mut failed = vec![];
+        for target in cfg.backup_target.clone() {
+            let res = match target.as_str() {
+                consts::BACKUP_TARGET_LOCAL => {
+                    let root = Path::new(cfg.local.path.as_str()).join(cfg.repository.name.as_str());
+                    Repository::load_known_chunks(&root).and_then(|known| {
+                        let mut repository = Repository::new(&root, cfg.repository.clone(), known)?;
+                        let id = repository.backup(&backup_files)?;
+                        info!("repository snapshot [{}] stored to local, {} new files", id, repository.written_files().len());
+                        Ok(())
+                    })
+                }
+                consts::BACKUP_TARGET_BACKER_SERVER => {
+                    self.repository_backup_to_backer_server(cfg.clone(), &staging, &backup_files)
+                }
+                _ => {
+                    Err(anyhow!("repository format is not supported by target"))
+                }
+            };
+            if let Err(e) = &res {
+                error!("repository backup to [{}] failed: {}", target, e);
+                failed.push(target.clone());
+            }
+            summary.add_target_result(target.as_str(), &res);
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!("repository backup to [{}] failed", failed.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn repository_backup_to_backer_server(&self, cfg: Arc<BackerConfig>, staging: &Staging, backup_files: &[file::BackupPath]) -> Result<()> {
+        let cache_dir = file::get_repository_cache_dir_path()
+            .join(format!("{}_{}_{}", cfg.backer_server.ip, cfg.backer_server.port, cfg.repository.name));
+        let known = Self::backer_server_known_chunks(&cfg, &cache_dir)
+            .map_err(|e| anyhow!("list the chunks on backer server failed: {}", e))?;
+        let staging_dir = staging.run_dir().join(cfg.repository.name.as_str());
+        let mut repository = Repository::new(&staging_dir, cfg.repository.clone(), known)?;
+        let id = repository.backup(backup_files)?;
+        let files = repository.written_files().iter().map(|name| {
+            let path = staging_dir.join(name).to_string_lossy().to_string();
+            file::FileInfo::new(format!("{}/{}", cfg.repository.name, name), path, Default::default())
+        }).collect::<Vec<file::FileInfo>>();
+        info!("repository snapshot [{}] created, {} new files", id, files.len());
+        let succeeded = self.rt.block_on(Self::backup_files_to_backer_server(cfg.backer_server.clone(), files, self.completed_state.clone()));
+        if !succeeded {
+            return Err(anyhow!("upload to backer server failed"));
+        }
+        // the indexes just uploaded needn't be fetched by the next backup
+        for name in repository.written_files().iter().filter(|name| name.starts_with(format!("{}/", repository::INDEX_DIR).as_str())) {
+            let res = file::create_dir(cache_dir.join(repository::INDEX_DIR))
+                .and_then(|_| file::copy_file(staging_dir.join(name), cache_dir.join(name)));
+            if let Err(e) = res {
+                error!("save repository cache failed: {}", e);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // the chunks stored on backer-server, rebuilt from its listing before every upload so chunks
+    // of lost packs are uploaded again. packs never change, so the index of a pack is fetched once
+    // into `cache_dir`, after the server hashed the pack to check it matches its id. a cached pack
+    // is only checked by its size, the indexes of packs gone from the server are dropped.
+    fn backer_server_known_chunks(cfg: &BackerConfig, cache_dir: &Path) -> Result<HashSet<String>> {
+        let prefix = format!("{}/{}/", cfg.repository.name, repository::PACKS_DIR);
+        let packs = storage::list_files(cfg, consts::BACKUP_TARGET_BACKER_SERVER, prefix.as_str())?.into_iter()
+            .map(|f| (f.name.rsplit('/').next().unwrap_or_default().to_string(), f))
+            .collect::<HashMap<String, storage::TargetFile>>();
+        let index_dir = cache_dir.join(repository::INDEX_DIR);
+        file::create_dir(&index_dir)?;
+        for entry in fs::read_dir(&index_dir)? {
+            let entry = entry?;
+            if !packs.contains_key(entry.file_name().to_string_lossy().as_ref()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        let mut known = HashSet::new();
+        for (pack, stored) in packs.iter() {
+            let index = match Repository::load_index(cache_dir, pack.as_str()) {
+                Ok(index) if index.pack_size() == stored.size => index,
+                _ => {
+                    let _ = fs::remove_file(index_dir.join(pack.as_str()));
+                    match Self::fetch_pack_index(cfg, cache_dir, pack.as_str(), stored) {
+                        Ok(index) => index,
+                        Err(e) => {
+                            warn!("pack [{}] on backer server is unusable, its chunks will be uploaded again: {}", stored.name, e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            known.extend(index.blobs.into_iter().map(|blob| blob.id));
+        }
+        Ok(known)
+    }
+
+    // the index of a pack the cache doesn't know yet, once the server hashed the pack
+    fn fetch_pack_index(cfg: &BackerConfig, cache_dir: &Path, pack: &str, stored: &storage::TargetFile) -> Result<repository::PackIndex> {
+        let hashed = storage::list_hashed_files(cfg, consts::BACKUP_TARGET_BACKER_SERVER, stored.name.as_str())?;
+        if !hashed.iter().any(|f| f.name == stored.name && f.sha256 == pack) {
+            return Err(anyhow!("its sha256 doesn't match its id"));
+        }
+        let name = format!("{}/{}/{}", cfg.repository.name, repository::INDEX_DIR, pack);
+        let path = cache_dir.join(repository::INDEX_DIR).join(pack);
+        storage::fetch_file(cfg, consts::BACKUP_TARGET_BACKER_SERVER, name.as_str(), &path)?;
+        let index = Repository::load_index(cache_dir, pack);
+        if !matches!(&index, Ok(index) if index.pack_size() == stored.size) {
+            let _ = fs::remove_file(&path);
+            return Err(anyhow!("its index doesn't match it"));
+        }
+        index
+    }
+
+    async fn backup_files_to_backer_server(cfg: BackerServer, archive_files: Vec<file::FileInfo>, completed: Arc<AtomicBool>) -> bool {
         info!("start backup_file_to_backer_server");
+        completed.store(false, Ordering::Relaxed);
+        let succeeded = Arc::new(AtomicBool::new(false));
         let addr: SocketAddr = format!("{}:{}", cfg.ip, cfg.port).parse().unwrap();
         let tcp_handler = Dispatch::new_for_client();
         let handle_completed = completed.clone();
-        tcp_handler.add_handle(String::from("backer_handle"), Box::new(BackerHandle::new(archive_file, handle_completed)));
+        tcp_handler.add_handle(String::from("backer_handle"), Box::new(BackerHandle::new(archive_files, handle_completed, succeeded.clone())));
         let mut client = TcpClient::new(addr, tcp_handler);
         client.start();
         client.send_message(Message::Auth(cfg.secret));
@@ -190,44 +521,81 @@ impl Backer {
                 break;
             }
         }
+        client.close();
         info!("end backup_file_to_backer_server");
+        succeeded.load(Ordering::Relaxed)
     }
 
-    async fn backup_file_to_qiniu(cfg: QiniuServer, archive_file: file::FileInfo) {
+    async fn backup_files_to_local(cfg: LocalServer, archive_files: Vec<file::FileInfo>) -> Result<()> {
+        info!("start backup_file_to_local");
+        for archive_file in archive_files {
+            let target = Path::new(cfg.path.as_str()).join(archive_file.file_name.as_str());
+            file::copy_file(archive_file.absolute_path.as_str(), target)
+                .map_err(|e| anyhow!("backup file [{}] to local failed: {}", archive_file.file_name, e))?;
+        }
+        info!("end backup_file_to_local");
+        Ok(())
+    }
+
+    async fn backup_files_to_qiniu(cfg: QiniuServer, archive_files: Vec<file::FileInfo>) -> Result<()> {
         info!("start backup_file_to_qiniu");
         let upload_manager = UploadManager::builder(UploadTokenSigner::new_credential_provider(
             Credential::new(cfg.access_key.as_str(), cfg.secret_key.as_str()),
             cfg.bucket_name.as_str(),
             Duration::from_secs(3600),
         )).build();
-        let params = AutoUploaderObjectParams::builder().object_name(archive_file.file_name.clone()).file_name(archive_file.file_name.clone()).build();
         let uploader: AutoUploader = upload_manager.auto_uploader();
-        let res = uploader.upload_path(archive_file.absolute_path.clone(), params).unwrap();
-        info!("end backup_file_to_qiniu. response: {:?}", res);
+        for archive_file in archive_files {
+            let params = AutoUploaderObjectParams::builder().object_name(archive_file.file_name.clone()).file_name(archive_file.file_name.clone()).build();
+            let res = uploader.upload_path(archive_file.absolute_path.clone(), params)?;
+            info!("upload [{}] to qiniu. response: {:?}", archive_file.file_name, res);
+        }
+        info!("end backup_file_to_qiniu");
+        Ok(())
     }
 
     // TODO
-    async fn backup_file_to_aliyun_oss(_cfg: AliyunOssServer, _archive_file: file::FileInfo) {
+    async fn backup_files_to_aliyun_oss(_cfg: AliyunOssServer, _archive_files: Vec<file::FileInfo>) -> Result<()> {
         info!("start backup_file_to_aliyun_oss");
         info!("end backup_file_to_aliyun_oss");
+        Ok(())
     }
 
     // TODO
-    async fn backup_file_to_tencent_oss(_cfg: TencentOssServer, _archive_file: file::FileInfo) {
+    async fn backup_files_to_tencent_oss(_cfg: TencentOssServer, _archive_files: Vec<file::FileInfo>) -> Result<()> {
         info!("start backup_file_to_tencent_oss");
         info!("end backup_file_to_tencent_oss");
+        Ok(())
     }
 }
 
 
 struct BackerHandle {
-    archive_file: file::FileInfo,
+    archive_files: Vec<file::FileInfo>,
     completed: Arc<AtomicBool>,
+    succeeded: Arc<AtomicBool>,
 }
 
 impl BackerHandle {
-    pub fn new(archive_file: file::FileInfo, completed: Arc<AtomicBool>) -> Self {
-        Self { archive_file, completed }
+    pub fn new(archive_files: Vec<file::FileInfo>, completed: Arc<AtomicBool>, succeeded: Arc<AtomicBool>) -> Self {
+        Self { archive_files, completed, succeeded }
+    }
+
+    fn send_file(&self, archive_file: &file::FileInfo, protocol: &mut Protocol) -> Result<()> {
+        let file_data = file::read_file(archive_file.absolute_path.as_str()).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let fb = FileBuffer::new(archive_file.file_name.clone(), file_data);
+        let fb_size = fb.get_buffer_length() as f64;
+        let mut completed_buf_size: f64 = 0.0;
+        let buffers = fb.cut_file_buff(MAX_BUFFER_LENGTH);
+        for buffer in buffers {
+            completed_buf_size += buffer.get_buffer_length() as f64;
+            let msg = Message::FileBuffer(buffer);
+            protocol.send_message(msg)?;
+            let percents = format!("{:.0}", (completed_buf_size / fb_size) * 100.0);
+            print!("\rback up file {}: {}%", archive_file.file_name, percents);
+        }
+        println!();
+        Ok(())
     }
 }
 
@@ -240,22 +608,14 @@ impl Handler for BackerHandle {
             Message::Authorize(authorize) => {
                 if *authorize {
                     info!("Authorize success, start sync file.");
-                    let fb = FileBuffer::new(self.archive_file.file_name.clone(), self.archive_file.file_data.to_vec());
-                    let fb_size = fb.get_buffer_length() as f64;
-                    let mut completed_buf_size: f64 = 0.0;
-                    let buffers = fb.cut_file_buff(MAX_BUFFER_LENGTH);
-                    for buffer in buffers {
-                        completed_buf_size += buffer.get_buffer_length() as f64;
-                        let msg = Message::FileBuffer(buffer);
-                        let res = protocol.send_message(msg);
-                        if let Err(e) = res {
+                    for archive_file in self.archive_files.iter() {
+                        if let Err(e) = self.send_file(archive_file, protocol) {
                             error!("send file buffer failed: {}", e);
+                            self.completed.store(true, Ordering::Relaxed);
                             return;
                         }
-                        let percents = format!("{:.0}", (completed_buf_size / fb_size) * 100.0);
-                        print!("\rback up file: {}%", percents);
                     }
-                    println!();
+                    self.succeeded.store(true, Ordering::Relaxed);
                     info!("end sync file.");
                 } else {
                     error!("Authorize failed!");
@@ -1 +1,3 @@
-pub mod backer;
\ No newline at end of file
+pub mod backer;
+pub mod state;
+pub mod summary;
\ No newline at end of file
@@ -0,0 +1,205 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::config::config::BackerConfig;
+use crate::consts;
+use crate::utils::file;
+
+/// The last successful backup of a job, kept in the state dir.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct JobState {
+    fingerprint: String,
+    backup_time: String,
+}
+
+/// Tells whether the sources changed since the last successful backup of the job. The fingerprint
+/// covers the paths, types, sizes and mtimes below every backup path, the file sources and the
+/// config, so any of them changing makes the next run a backup again.
+pub struct ChangeGate {
+    path: PathBuf,
+    fingerprint: Option<String>,
+    last: Option<JobState>,
+    full_every_days: u32,
+}
+
+impl ChangeGate {
+    pub fn check(cfg: &BackerConfig, backup_files: &[file::BackupPath]) -> Self {
+        let path = Path::new(cfg.state_dir.as_str()).join(format!("job-{}.json", job_key(cfg)));
+        let fingerprint = if !cfg.skip_unchanged.enabled {
+            None
+        } else if cfg.sources.iter().any(|source| source.source_type == consts::SOURCE_TYPE_COMMAND) {
+            // the output of a command is only known once it ran
+            info!("command sources can't be fingerprinted, skip-unchanged doesn't apply");
+            None
+        } else {
+            Some(fingerprint(cfg, backup_files))
+        };
+        let last = fingerprint.as_ref().and_then(|_| {
+            file::read_file(&path).ok().and_then(|bytes| serde_json::from_slice::<JobState>(&bytes).ok())
+        });
+        Self { path, fingerprint, last, full_every_days: cfg.skip_unchanged.full_every_days }
+    }
+
+    /// The time of the last backup when nothing changed since, and it isn't time for a full one.
+    pub fn unchanged_since(&self) -> Option<&str> {
+        let last = match (&self.fingerprint, &self.last) {
+            (Some(fingerprint), Some(last)) if *fingerprint == last.fingerprint => last,
+            _ => return None,
+        };
+        if self.full_every_days > 0 {
+            let backup_time = chrono::DateTime::parse_from_rfc3339(last.backup_time.as_str()).ok()?;
+            let age = chrono::Local::now().signed_duration_since(backup_time);
+            if age >= chrono::Duration::days(self.full_every_days as i64) {
+                info!("last backup is {} days old, back up the unchanged sources anyway", age.num_days());
+                return None;
+            }
+        }
+        Some(last.backup_time.as_str())
+    }
+
+    /// Record a successful backup. A failure is only logged, the next run backs up again.
+    pub fn save(&self) {
+        let fingerprint = match &self.fingerprint {
+            Some(fingerprint) => fingerprint.clone(),
+            None => return,
+        };
+        let state = JobState { fingerprint, backup_time: chrono::Local::now().to_rfc3339() };
+        let tmp_path = self.path.with_extension("json.tmp");
+        let res = serde_json::to_vec(&state).map_err(|e| e.into())
+            .and_then(|bytes| {
+                file::create_dir(self.path.parent().unwrap_or(Path::new(".")))?;
+                file::create_write_file(&tmp_path, &bytes)
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.path).map_err(|e| e.into()));
+        if let Err(e) = res {
+            warn!("save job state failed: {}", e);
+        }
+    }
+}
+
+// jobs sharing a state dir are told apart by their targets and backup files
+fn job_key(cfg: &BackerConfig) -> String {
+    let key = format!("{:?}{:?}", cfg.backup_target, cfg.backup_files);
+    format!("{:x}", Sha256::digest(key.as_bytes()))[..16].to_string()
+}
+
+fn fingerprint(cfg: &BackerConfig, backup_files: &[file::BackupPath]) -> String {
+    let mut hasher = Sha256::new();
+    // a changed config (targets, compression, mapping, ...) gives a different archive
+    hasher.update(format!("{:?}", cfg).as_bytes());
+    for backup_path in backup_files {
+        hasher.update(format!("path {} {}\n", backup_path.name, backup_path.path).as_bytes());
+        let walk_dir = WalkDir::new(backup_path.path.as_str())
+            .follow_links(cfg.follow_symlinks)
+            .same_file_system(cfg.one_file_system)
+            .sort_by_file_name();
+        for entry in walk_dir {
+            match entry {
+                Ok(entry) => hash_metadata(&mut hasher, entry.path(), entry.metadata().ok()),
+                Err(e) => hasher.update(format!("error {}\n", e).as_bytes()),
+            }
+        }
+    }
+    for source in cfg.sources.iter() {
+        hash_metadata(&mut hasher, Path::new(source.path.as_str()), fs::metadata(source.path.as_str()).ok());
+        // sqlite databases in WAL mode are written to the -wal file first
+        let wal = format!("{}-wal", source.path);
+        hash_metadata(&mut hasher, Path::new(wal.as_str()), fs::metadata(wal.as_str()).ok());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_metadata(hasher: &mut Sha256, path: &Path, metadata: Option<fs::Metadata>) {
+    let line = match metadata {
+        Some(metadata) => {
+            let mtime = metadata.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_nanos())
+                .unwrap_or(0);
+            format!("{} {:?} {} {}\n", path.display(), metadata.file_type(), metadata.len(), mtime)
+        }
+        None => format!("{} missing\n", path.display()),
+    };
+    hasher.update(line.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::SourceConfig;
+    use crate::utils::testing::test_dir;
+
+    // a test dir with a file to back up in data
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = test_dir(name);
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data/a.txt"), b"a").unwrap();
+        dir
+    }
+
+    fn config(dir: &Path) -> BackerConfig {
+        let mut cfg = BackerConfig::default();
+        cfg.state_dir = dir.join("state").to_string_lossy().to_string();
+        cfg.skip_unchanged.enabled = true;
+        cfg
+    }
+
+    fn backup_files(dir: &Path) -> Vec<file::BackupPath> {
+        vec![file::BackupPath::from_basename(dir.join("data").to_string_lossy().to_string())]
+    }
+
+    #[test]
+    fn changes_open_the_gate() {
+        let dir = data_dir("state-changes");
+        let cfg = config(&dir);
+        let gate = ChangeGate::check(&cfg, &backup_files(&dir));
+        assert!(gate.unchanged_since().is_none());
+        gate.save();
+        assert!(ChangeGate::check(&cfg, &backup_files(&dir)).unchanged_since().is_some());
+        fs::write(dir.join("data/b.txt"), b"b").unwrap();
+        assert!(ChangeGate::check(&cfg, &backup_files(&dir)).unchanged_since().is_none());
+        // a changed config backs up again too
+        ChangeGate::check(&cfg, &backup_files(&dir)).save();
+        let mut changed = cfg.clone();
+        changed.compress_mode = consts::COMPRESS_MODE_ZIP.to_string();
+        assert!(ChangeGate::check(&changed, &backup_files(&dir)).unchanged_since().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gate_stays_open_when_disabled_or_unknowable() {
+        let dir = data_dir("state-open");
+        let mut cfg = config(&dir);
+        cfg.skip_unchanged.enabled = false;
+        ChangeGate::check(&cfg, &backup_files(&dir)).save();
+        assert!(ChangeGate::check(&cfg, &backup_files(&dir)).unchanged_since().is_none());
+        let mut cfg = config(&dir);
+        cfg.sources = vec![SourceConfig { source_type: consts::SOURCE_TYPE_COMMAND.to_string(), command: "true".to_string(), ..Default::default() }];
+        ChangeGate::check(&cfg, &backup_files(&dir)).save();
+        assert!(ChangeGate::check(&cfg, &backup_files(&dir)).unchanged_since().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn old_backups_are_repeated() {
+        let dir = data_dir("state-full");
+        let mut cfg = config(&dir);
+        cfg.skip_unchanged.full_every_days = 1;
+        let gate = ChangeGate::check(&cfg, &backup_files(&dir));
+        gate.save();
+        assert!(ChangeGate::check(&cfg, &backup_files(&dir)).unchanged_since().is_some());
+        let old = JobState {
+            fingerprint: gate.fingerprint.clone().unwrap(),
+            backup_time: (chrono::Local::now() - chrono::Duration::days(2)).to_rfc3339(),
+        };
+        fs::write(&gate.path, serde_json::to_vec(&old).unwrap()).unwrap();
+        assert!(ChangeGate::check(&cfg, &backup_files(&dir)).unchanged_since().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
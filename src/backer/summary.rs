@@ -0,0 +1,116 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::consts;
+use crate::utils::file::{ArchivedEntry, CompressReport};
+
+/// Outcome of one backup job, logged when the job ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub started: String,
+    pub finished: String,
+    pub status: String,
+    pub backup_format: String,
+    pub archive: String,
+    pub archive_size: u64,
+    pub targets: Vec<String>,
+    pub files: u64,
+    pub bytes: u64,
+    pub changed_files: Vec<String>,
+    pub inconsistent_files: Vec<String>,
+    #[serde(default)]
+    pub stored_files: u64,
+    #[serde(default)]
+    pub stored_bytes: u64,
+    #[serde(default)]
+    pub archive_sha256: String,
+    #[serde(default)]
+    pub target_results: Vec<TargetResult>,
+    // the archive's entry list only goes to the catalog
+    #[serde(skip)]
+    pub entries: Vec<ArchivedEntry>,
+    pub error: String,
+}
+
+/// Outcome of the upload to one target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetResult {
+    pub target: String,
+    pub status: String,
+    pub error: String,
+}
+
+impl JobSummary {
+    pub fn new(backup_format: &str, targets: &[String]) -> Self {
+        Self {
+            started: chrono::Local::now().to_rfc3339(),
+            finished: String::new(),
+            status: consts::JOB_STATUS_RUNNING.to_string(),
+            backup_format: backup_format.to_string(),
+            archive: String::new(),
+            archive_size: 0,
+            targets: targets.to_vec(),
+            files: 0,
+            bytes: 0,
+            changed_files: vec![],
+            inconsistent_files: vec![],
+            stored_files: 0,
+            stored_bytes: 0,
+            archive_sha256: String::new(),
+            target_results: vec![],
+            entries: vec![],
+            error: String::new(),
+        }
+    }
+
+    pub fn add_compress_report(&mut self, report: &CompressReport) {
+        self.files += report.files;
+        self.bytes += report.bytes;
+        self.changed_files.extend(report.changed_files.iter().cloned());
+        self.inconsistent_files.extend(report.inconsistent_files.iter().cloned());
+        self.stored_files += report.stored_files;
+        self.stored_bytes += report.stored_bytes;
+        self.entries.extend(report.entries.iter().cloned());
+    }
+
+    pub fn add_target_result(&mut self, target: &str, res: &anyhow::Result<()>) {
+        let (status, error) = match res {
+            Ok(()) => (consts::JOB_STATUS_SUCCESS, String::new()),
+            Err(e) => (consts::JOB_STATUS_FAILURE, e.to_string()),
+        };
+        self.target_results.push(TargetResult { target: target.to_string(), status: status.to_string(), error });
+    }
+
+    pub fn finish(&mut self, status: &str, error: String) {
+        self.finished = chrono::Local::now().to_rfc3339();
+        self.status = status.to_string();
+        self.error = error;
+    }
+
+    pub fn log(&self) {
+        info!("==================== Backup Summary ====================");
+        info!("status: {}", self.status);
+        info!("started: {}, finished: {}", self.started, self.finished);
+        info!("format: {}, targets: {}", self.backup_format, self.targets.join(", "));
+        if !self.archive.is_empty() {
+            info!("archive: {} ({} bytes)", self.archive, self.archive_size);
+        }
+        info!("files: {} ({} bytes)", self.files, self.bytes);
+        if self.stored_files > 0 {
+            info!("stored without compression: {} files ({} bytes not compressed again)", self.stored_files, self.stored_bytes);
+        }
+        if !self.changed_files.is_empty() {
+            warn!("files changed while being archived: {}", self.changed_files.len());
+            for name in self.changed_files.iter() {
+                if self.inconsistent_files.contains(name) {
+                    warn!("  {} (marked inconsistent)", name);
+                } else {
+                    warn!("  {}", name);
+                }
+            }
+        }
+        if !self.error.is_empty() {
+            error!("error: {}", self.error);
+        }
+    }
+}
@@ -1,16 +1,19 @@
 use std::thread;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
 
 use clap::{ArgAction, Parser};
 use home;
 use log::{debug, error, info};
 use signal_hook::{consts::TERM_SIGNALS, iterator::Signals};
+use walkdir::WalkDir;
 
 use backer::init::init::init;
-use backer::packet::message::{Message, Protocol};
+use backer::packet::message::{FetchRequest, FileBuffer, FileList, ListRequest, Message, Protocol, StoredFile};
 use backer::packet::tcp_packet::{Dispatch, Handler, TcpServer};
 use backer::utils::file;
 use backer::version;
@@ -35,7 +38,7 @@ struct Opts {
     version: bool,
 }
 
-const VERSION_INFO: &'static version::VersionInfo = &version::VersionInfo {
+const VERSION_INFO: &version::VersionInfo = &version::VersionInfo {
     name: "Backer Server",
     version: version::BACKER_SERVER_VERSION,
     compiler: env!("RUSTC_VERSION"),
@@ -51,6 +54,8 @@ fn wait_on_signals() {
 
 // const CURRENT_FILE: Mutex<Option<File>> = Mutex::new(None);
 
+const MAX_BUFFER_LENGTH: usize = 20480;
+
 struct BackerServerHandle {
     backup_dir: String,
     secret: String,
@@ -70,6 +75,7 @@ impl Handler for BackerServerHandle {
                 debug!("receive echo message: {}", secret);
                 if self.secret.eq(secret) {
                     debug!("Authorize success!");
+                    protocol.set_authorized(true);
                     let _ = protocol.send_message(Message::Authorize(true));
                 } else {
                     debug!("Authorize failed!");
@@ -78,8 +84,19 @@ impl Handler for BackerServerHandle {
                 }
             }
             Message::FileBuffer(file_buff) => {
+                if !protocol.is_authorized() {
+                    error!("reject file buffer of '{}', the client isn't authorized", file_buff.file_name);
+                    return;
+                }
                 if file_buff.is_begin {
+                    if !file::is_safe_relative_path(file_buff.file_name.as_str()) {
+                        error!("reject file name: '{}'", file_buff.file_name);
+                        return;
+                    }
                     let path = format!("{}/{}", self.backup_dir, file_buff.file_name);
+                    if let Some(parent) = Path::new(path.as_str()).parent() {
+                        let _ = file::create_dir(parent);
+                    }
                     let file = file::create_file(path.clone());
                     match file {
                         Ok(file) => {
@@ -107,11 +124,157 @@ impl Handler for BackerServerHandle {
                     }
                 }
             }
+            Message::Fetch(request) => {
+                if !protocol.is_authorized() {
+                    error!("reject fetch of '{}', the client isn't authorized", request.file_name);
+                    let _ = protocol.send_message(Message::Complete(false));
+                    return;
+                }
+                if let Err(e) = self.send_file(request, protocol) {
+                    error!("send file '{}' failed: {}", request.file_name, e);
+                    let _ = protocol.send_message(Message::Complete(false));
+                }
+            }
+            Message::List(request) => {
+                if !protocol.is_authorized() {
+                    error!("reject listing of '{}', the client isn't authorized", request.prefix);
+                    let _ = protocol.send_message(Message::Complete(false));
+                    return;
+                }
+                if let Err(e) = self.send_file_list(request, protocol) {
+                    error!("send file list failed: {}", e);
+                    let _ = protocol.send_message(Message::Complete(false));
+                }
+            }
+            Message::Delete(file_name) => {
+                if !protocol.is_authorized() {
+                    error!("reject delete of '{}', the client isn't authorized", file_name);
+                    let _ = protocol.send_message(Message::Complete(false));
+                    return;
+                }
+                match self.delete_file(file_name) {
+                    Ok(_) => {
+                        info!("success delete file!  file name: '{}'", file_name);
+                        let _ = protocol.send_message(Message::Complete(true));
+                    }
+                    Err(e) => {
+                        error!("delete file '{}' failed: {}", file_name, e);
+                        let _ = protocol.send_message(Message::Complete(false));
+                    }
+                }
+            }
             _ => {}
         }
     }
 }
 
+impl BackerServerHandle {
+    // stream a stored file, or the requested range of it, back as file buffers, the last one is
+    // marked as the end. a range reaching past the end of the file is refused.
+    fn send_file(&self, request: &FetchRequest, protocol: &mut Protocol) -> std::io::Result<()> {
+        let file_name = request.file_name.as_str();
+        if !file::is_safe_relative_path(file_name) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid file name"));
+        }
+        let path = format!("{}/{}", self.backup_dir, file_name);
+        let mut file = File::open(path.as_str())?;
+        let size = file.metadata()?.len();
+        let end = match request.length {
+            Some(length) => request.offset.checked_add(length).filter(|end| *end <= size),
+            None => Some(size).filter(|size| request.offset <= *size),
+        };
+        let end = end.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("range is outside of the file of {} bytes", size)))?;
+        file.seek(SeekFrom::Start(request.offset))?;
+        info!("start send file!  file name: '{}', file path: [{}], range: {}-{}", file_name, path, request.offset, end);
+        let mut remaining = end - request.offset;
+        let mut is_begin = true;
+        let mut buffer = vec![0u8; MAX_BUFFER_LENGTH];
+        loop {
+            let n = remaining.min(MAX_BUFFER_LENGTH as u64) as usize;
+            file.read_exact(&mut buffer[..n])?;
+            remaining -= n as u64;
+            let mut file_buff = FileBuffer::new(file_name.to_string(), buffer[..n].to_vec());
+            file_buff.is_begin = is_begin;
+            file_buff.is_end = remaining == 0;
+            protocol.send_message(Message::FileBuffer(file_buff))?;
+            is_begin = false;
+            if remaining == 0 {
+                break;
+            }
+        }
+        info!("success send file!  file name: '{}'", file_name);
+        Ok(())
+    }
+
+    fn delete_file(&self, file_name: &str) -> std::io::Result<()> {
+        if !file::is_safe_relative_path(file_name) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid file name"));
+        }
+        // the upload would go on into the deleted file
+        if self.backup_files.lock().unwrap().contains_key(file_name) {
+            return Err(std::io::Error::other("the file is still being uploaded"));
+        }
+        std::fs::remove_file(format!("{}/{}", self.backup_dir, file_name))
+    }
+
+    // the files below the backup dir, in pages that fit in a message. hashes are computed when
+    // asked for, by reading every listed file. files still being uploaded aren't listed, nor
+    // files deleted while the listing runs.
+    fn send_file_list(&self, request: &ListRequest, protocol: &mut Protocol) -> std::io::Result<()> {
+        let prefix = request.prefix.as_str();
+        let mut files = vec![];
+        let mut page_size = 0;
+        for entry in WalkDir::new(self.backup_dir.as_str()).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if is_not_found(e.io_error()) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let name = match entry.path().strip_prefix(self.backup_dir.as_str()) {
+                Ok(name) => name.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+            if !name.starts_with(prefix) || self.backup_files.lock().unwrap().contains_key(name.as_str()) {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) if is_not_found(e.io_error()) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let modified = metadata.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            let sha256 = if request.with_hash {
+                match file::sha256_file(entry.path()) {
+                    Ok(sha256) => sha256,
+                    Err(e) if is_not_found(Some(&e)) => continue,
+                    Err(e) => return Err(e),
+                }
+            } else {
+                String::new()
+            };
+            let entry_size = name.len() + sha256.len() + 40;
+            if page_size + entry_size > MAX_BUFFER_LENGTH {
+                protocol.send_message(Message::FileList(FileList::new(files, false)))?;
+                files = vec![];
+                page_size = 0;
+            }
+            page_size += entry_size;
+            files.push(StoredFile { name, size: metadata.len(), modified, sha256 });
+        }
+        protocol.send_message(Message::FileList(FileList::new(files, true)))
+    }
+}
+
+fn is_not_found(e: Option<&std::io::Error>) -> bool {
+    e.map(|e| e.kind() == std::io::ErrorKind::NotFound).unwrap_or(false)
+}
+
 fn main() {
     let mut opts = Opts::parse();
 
@@ -120,7 +283,7 @@ fn main() {
         return;
     }
 
-    if opts.secret.len() == 0 {
+    if opts.secret.is_empty() {
         println!("backer server secret can't empty!");
         return;
     }
@@ -1,10 +1,31 @@
-use anyhow::Result;
-use clap::{ArgAction, Parser};
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use chrono::TimeZone;
+use clap::{ArgAction, Parser, Subcommand};
+use log::LevelFilter;
 use signal_hook::{consts::TERM_SIGNALS, iterator::Signals};
 
 use backer::backer::backer::Backer;
-use backer::init::init::init;
+use backer::catalog::catalog::{Catalog, CatalogRun};
+use backer::config::config::BackerConfig;
+use backer::consts;
+use backer::crypto::crypto;
+use backer::diff::diff;
+use backer::find::find;
+use backer::find::find::{FindOptions, FoundEntry};
+use backer::init::init::{init, init_with_level};
+use backer::manifest::manifest;
+use backer::restore::restore;
+use backer::restore::restore::{ConflictPolicy, RestoreOptions};
+use backer::storage::storage;
+use backer::storage::storage::StoredArchive;
+use backer::utils::file;
+use backer::verify::verify;
+use backer::verify::verify::{ArchiveCheck, VerifyOptions};
 use backer::version;
+use backer::volume::volume;
 
 #[derive(Parser)]
 struct Opts {
@@ -15,9 +36,201 @@ struct Opts {
     /// Display the version
     #[clap(short, long, action = ArgAction::SetTrue)]
     version: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-const VERSION_INFO: &'static version::VersionInfo = &version::VersionInfo {
+#[derive(Subcommand)]
+enum Command {
+    /// Generate an encryption key file, a recovery key pair with --recovery or a manifest signing key pair with --signing
+    Keygen {
+        /// Where to write the key file, the recovery secret key or the signing key
+        output: String,
+
+        /// Generate a recovery key pair, the public key goes to encryption.recipients
+        #[clap(long, action = ArgAction::SetTrue)]
+        recovery: bool,
+
+        /// Generate a manifest signing key pair, the public key verifies the uploaded manifests
+        #[clap(long, action = ArgAction::SetTrue, conflicts_with = "recovery")]
+        signing: bool,
+    },
+    /// Decrypt an encrypted archive
+    Decrypt {
+        /// Encrypted archive
+        input: String,
+
+        /// Where to write the decrypted archive
+        output: String,
+
+        /// Key file or recovery secret key, can be given multiple times
+        #[clap(short = 'k', long)]
+        key: Vec<String>,
+
+        /// Read the passphrase from this environment variable
+        #[clap(long, default_value = "BACKER_PASSPHRASE")]
+        passphrase_env: String,
+    },
+    /// Join the volumes of a split archive, the parts are read from the dir of the index
+    Join {
+        /// Volume index, <archive>.volumes
+        index: String,
+
+        /// Where to write the joined archive
+        output: String,
+    },
+    /// Fetch an archive from a backup target and extract it
+    Restore {
+        /// Archive name, e.g. Archive-2023-01-31_00:00:00.tar.gz, or latest for the newest one
+        archive: String,
+
+        /// Dir the archive is extracted to, entries are restored below it without the archive/ prefix
+        destination: String,
+
+        /// Backup target to fetch from, default is the first of backup-target
+        #[clap(short = 't', long)]
+        target: Option<String>,
+
+        /// Restore the entries below PREFIX to DIR instead, as PREFIX=DIR, can be given multiple times
+        #[clap(long)]
+        map: Vec<String>,
+
+        /// Only restore entries matching this glob, or below a match, can be given multiple times.
+        /// Only the matching entries are read from targets serving byte ranges, when the archive
+        /// isn't encrypted
+        #[clap(long)]
+        include: Vec<String>,
+
+        /// Don't restore entries matching this glob, or below a match, can be given multiple times
+        #[clap(long)]
+        exclude: Vec<String>,
+
+        /// What to do with paths that already exist
+        #[clap(long, default_value = consts::CONFLICT_SKIP, value_parser = [consts::CONFLICT_OVERWRITE, consts::CONFLICT_SKIP, consts::CONFLICT_RENAME])]
+        conflict: String,
+
+        /// Only list what would be restored
+        #[clap(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+
+        /// Key file or recovery secret key of an encrypted archive, can be given multiple times
+        #[clap(short = 'k', long)]
+        key: Vec<String>,
+
+        /// Read the passphrase from this environment variable
+        #[clap(long, default_value = "BACKER_PASSPHRASE")]
+        passphrase_env: String,
+    },
+    /// List the archives stored on the backup targets
+    List {
+        /// Only list this backup target, default is every target of backup-target
+        #[clap(short = 't', long)]
+        target: Option<String>,
+
+        /// Print the archives as JSON
+        #[clap(long, action = ArgAction::SetTrue)]
+        json: bool,
+    },
+    /// Delete a stored archive with its volumes and sidecar files, from the local and backer-server targets
+    Delete {
+        /// Archive name, e.g. Archive-2023-01-31_00:00:00.tar.gz
+        archive: String,
+
+        /// Only delete it from this backup target, default is every target of backup-target
+        #[clap(short = 't', long)]
+        target: Option<String>,
+
+        /// Only list the files that would be deleted
+        #[clap(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+    },
+    /// Fetch archives back from the backup targets, check them against the hashes recorded at upload and read every entry
+    Verify {
+        /// Archive name, latest for the newest archive of each target or all, default is verify.archives
+        archive: Option<String>,
+
+        /// Only verify this backup target, default is every target of backup-target
+        #[clap(short = 't', long)]
+        target: Option<String>,
+
+        /// Key file or recovery secret key of encrypted archives, can be given multiple times
+        #[clap(short = 'k', long)]
+        key: Vec<String>,
+
+        /// Read the passphrase from this environment variable
+        #[clap(long, default_value = "BACKER_PASSPHRASE")]
+        passphrase_env: String,
+
+        /// Public key the manifests have to be signed with, default is verify.public-key
+        #[clap(long)]
+        public_key: Option<String>,
+
+        /// Print the results as JSON
+        #[clap(long, action = ArgAction::SetTrue)]
+        json: bool,
+    },
+    /// Compare the entries of two backups: added, removed, modified and metadata changed files
+    Diff {
+        /// The older backup, a run of the catalog as #ID, or an archive name (or latest) on the target
+        from: String,
+
+        /// The newer backup, like FROM
+        to: String,
+
+        /// Backup target the archives are fetched from, default is the first of backup-target
+        #[clap(short = 't', long)]
+        target: Option<String>,
+
+        /// Hash the content of the entries of archives, files of the same size are compared by their metadata otherwise
+        #[clap(long, action = ArgAction::SetTrue)]
+        content: bool,
+
+        /// Key file or recovery secret key of encrypted archives, can be given multiple times
+        #[clap(short = 'k', long)]
+        key: Vec<String>,
+
+        /// Read the passphrase from this environment variable
+        #[clap(long, default_value = "BACKER_PASSPHRASE")]
+        passphrase_env: String,
+
+        /// Print the differences as JSON
+        #[clap(long, action = ArgAction::SetTrue)]
+        json: bool,
+    },
+    /// Search the file lists of every backup, in the catalog and the sidecar files on the targets, for entries matching a glob
+    Find {
+        /// Glob matched against the path of entries below the backup root, or against the file name when it has no slash
+        pattern: String,
+
+        /// Only search the archives on this backup target, default is every target of backup-target
+        #[clap(short = 't', long)]
+        target: Option<String>,
+
+        /// Only search the runs recorded in the catalog
+        #[clap(long, action = ArgAction::SetTrue, conflicts_with = "target")]
+        catalog_only: bool,
+
+        /// Print the matching entries as JSON
+        #[clap(long, action = ArgAction::SetTrue)]
+        json: bool,
+    },
+    /// Show the backup runs recorded in the catalog, or the details of one run
+    Catalog {
+        /// Id of the run to show with its targets and archive entries
+        run: Option<i64>,
+
+        /// Number of runs to list, newest first, 0 lists every run
+        #[clap(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Print the runs as JSON
+        #[clap(long, action = ArgAction::SetTrue)]
+        json: bool,
+    },
+}
+
+const VERSION_INFO: &version::VersionInfo = &version::VersionInfo {
     name: "Backer",
     version: version::BACKER_VERSION,
     compiler: env!("RUSTC_VERSION"),
@@ -38,6 +251,101 @@ fn main() -> Result<()> {
         println!("{}", VERSION_INFO);
         return Ok(());
     }
+    match opts.command {
+        Some(Command::Keygen { output, recovery, signing }) => return keygen(output, recovery, signing),
+        Some(Command::Decrypt { input, output, key, passphrase_env }) => return decrypt(input, output, key, passphrase_env),
+        Some(Command::Join { index, output }) => return volume::join_volumes_from_index(index, output),
+        Some(Command::Restore { archive, destination, target, map, include, exclude, conflict, dry_run, key, passphrase_env }) => {
+            init();
+            let cfg = BackerConfig::load_from_file(opts.config_file.as_str())?;
+            let target = match target {
+                Some(target) => target,
+                None => cfg.backup_target.first().cloned().ok_or_else(|| anyhow!("no backup target configured"))?,
+            };
+            let mut options = RestoreOptions::new(target, archive, PathBuf::from(destination));
+            for mapping in map {
+                options.mappings.push(restore::parse_mapping(mapping.as_str())?);
+            }
+            options.includes = patterns(include)?;
+            options.excludes = patterns(exclude)?;
+            options.conflict = ConflictPolicy::from_policy(conflict.as_str());
+            options.dry_run = dry_run;
+            options.identities = restore::config_identities(&cfg)?;
+            options.identities.extend(identities(key, passphrase_env)?);
+            let report = restore::restore(&cfg, &options)?;
+            report.print(dry_run);
+            return Ok(());
+        }
+        Some(Command::List { target, json }) => return list(opts.config_file.as_str(), target, json),
+        Some(Command::Delete { archive, target, dry_run }) => return delete(opts.config_file.as_str(), archive, target, dry_run),
+        Some(Command::Verify { archive, target, key, passphrase_env, public_key, json }) => {
+            if !json {
+                init();
+            }
+            let cfg = BackerConfig::load_from_file(opts.config_file.as_str())?;
+            let targets = match target {
+                Some(target) => vec![target],
+                None => cfg.backup_target.clone(),
+            };
+            let mut options = VerifyOptions::new(targets, archive.unwrap_or_else(|| cfg.verify.archives.clone()));
+            options.public_key = public_key.unwrap_or_else(|| cfg.verify.public_key.clone());
+            options.identities = restore::config_identities(&cfg)?;
+            options.identities.extend(identities(key, passphrase_env)?);
+            let checks = verify::verify(&cfg, &options)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&checks)?);
+            } else {
+                print_checks(&checks);
+            }
+            let failed = checks.iter().filter(|check| !check.ok).count();
+            if failed > 0 {
+                return Err(anyhow!("{} of {} checks failed", failed, checks.len()));
+            }
+            return Ok(());
+        }
+        Some(Command::Diff { from, to, target, content, key, passphrase_env, json }) => {
+            if !json {
+                init_with_level(LevelFilter::Warn);
+            }
+            let cfg = BackerConfig::load_from_file(opts.config_file.as_str())?;
+            let target = match target {
+                Some(target) => target,
+                None => cfg.backup_target.first().cloned().ok_or_else(|| anyhow!("no backup target configured"))?,
+            };
+            let mut identities_of_archives = restore::config_identities(&cfg)?;
+            identities_of_archives.extend(identities(key, passphrase_env)?);
+            let from = diff::load_snapshot(&cfg, from.as_str(), target.as_str(), content, &identities_of_archives)?;
+            let to = diff::load_snapshot(&cfg, to.as_str(), target.as_str(), content, &identities_of_archives)?;
+            let snapshot_diff = diff::diff(&from, &to);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&snapshot_diff)?);
+            } else {
+                snapshot_diff.print();
+            }
+            return Ok(());
+        }
+        Some(Command::Find { pattern, target, catalog_only, json }) => {
+            if !json {
+                init_with_level(LevelFilter::Warn);
+            }
+            let cfg = BackerConfig::load_from_file(opts.config_file.as_str())?;
+            let targets = match target {
+                Some(target) => vec![target],
+                None if catalog_only => vec![],
+                None => cfg.backup_target.clone(),
+            };
+            let pattern = patterns(vec![pattern])?.remove(0);
+            let found = find::find(&cfg, &FindOptions::new(pattern, targets))?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&found)?);
+            } else {
+                print_found(&found);
+            }
+            return Ok(());
+        }
+        Some(Command::Catalog { run, limit, json }) => return catalog(opts.config_file.as_str(), run, limit, json),
+        None => {}
+    }
     init();
 
     let backer = Backer::new()?;
@@ -46,3 +354,234 @@ fn main() -> Result<()> {
     backer.stop();
     Ok(())
 }
+
+fn keygen(output: String, recovery: bool, signing: bool) -> Result<()> {
+    // checked before a key is generated, create_secret_file refuses an existing file as well
+    if file::is_exist(output.as_str()) {
+        return Err(anyhow!("[{}] already exists", output));
+    }
+    if signing {
+        let (secret, public) = manifest::generate_signing_key();
+        file::create_secret_file(output.as_str(), format!("{}\n", secret).as_bytes()).map_err(|e| anyhow!("{}", e))?;
+        println!("signing key written to {}", output);
+        println!("public key: {}", public);
+    } else if recovery {
+        let (secret, public) = crypto::generate_key_pair();
+        file::create_secret_file(output.as_str(), format!("{}\n", secret).as_bytes()).map_err(|e| anyhow!("{}", e))?;
+        println!("recovery secret key written to {}, keep it offline.", output);
+        println!("public key: {}", public);
+    } else {
+        file::create_secret_file(output.as_str(), format!("{}\n", crypto::generate_key()).as_bytes()).map_err(|e| anyhow!("{}", e))?;
+        println!("key file written to {}", output);
+    }
+    Ok(())
+}
+
+fn decrypt(input: String, output: String, keys: Vec<String>, passphrase_env: String) -> Result<()> {
+    let identities = identities(keys, passphrase_env)?;
+    if identities.is_empty() {
+        return Err(anyhow!("no key or passphrase given"));
+    }
+    crypto::decrypt_file(input, output, &identities)
+}
+
+// key files given with -k and the passphrase in the environment variable
+fn identities(keys: Vec<String>, passphrase_env: String) -> Result<Vec<crypto::Identity>> {
+    let mut identities = vec![];
+    for key in keys {
+        identities.push(crypto::Identity::load(key)?);
+    }
+    if let Ok(passphrase) = env::var(passphrase_env) {
+        identities.push(crypto::Identity::Passphrase(passphrase));
+    }
+    Ok(identities)
+}
+
+fn list(config_file: &str, target: Option<String>, json: bool) -> Result<()> {
+    // json output stays parseable, failures are returned after the archives that could be listed
+    if !json {
+        init_with_level(LevelFilter::Warn);
+    }
+    let cfg = BackerConfig::load_from_file(config_file)?;
+    let targets = match target {
+        Some(target) => vec![target],
+        None => cfg.backup_target.clone(),
+    };
+    let mut archives = vec![];
+    let mut failed = vec![];
+    for target in targets {
+        match storage::list_archives(&cfg, target.as_str()) {
+            Ok(stored) => archives.extend(stored),
+            Err(e) => failed.push(format!("[{}]: {}", target, e)),
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&archives)?);
+    } else {
+        print_archives(&archives);
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!("list archives failed, {}", failed.join("; ")));
+    }
+    Ok(())
+}
+
+fn delete(config_file: &str, archive: String, target: Option<String>, dry_run: bool) -> Result<()> {
+    init();
+    let cfg = BackerConfig::load_from_file(config_file)?;
+    let targets = match target {
+        Some(target) => vec![target],
+        None => cfg.backup_target.clone(),
+    };
+    // the name of an encrypted archive, a volume or a sidecar file names the archive as well
+    let name = storage::archive_of(cfg.archive_prefix.as_str(), archive.as_str()).unwrap_or(archive);
+    let mut found = false;
+    let mut failed = vec![];
+    for target in targets {
+        let stored = match storage::list_archives(&cfg, target.as_str()) {
+            Ok(archives) => archives.into_iter().find(|stored| stored.name == name),
+            Err(e) => {
+                failed.push(format!("[{}]: {}", target, e));
+                continue;
+            }
+        };
+        let stored = match stored {
+            Some(stored) => stored,
+            None => continue,
+        };
+        found = true;
+        for file_name in stored.files.iter() {
+            if dry_run {
+                println!("would delete {} from {}", file_name, target);
+                continue;
+            }
+            match storage::delete_file(&cfg, target.as_str(), file_name.as_str()) {
+                Ok(()) => println!("deleted {} from {}", file_name, target),
+                Err(e) => failed.push(format!("[{}] on [{}]: {}", file_name, target, e)),
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!("delete failed, {}", failed.join("; ")));
+    }
+    if !found {
+        return Err(anyhow!("[{}] isn't stored on any target", name));
+    }
+    Ok(())
+}
+
+fn print_archives(archives: &[StoredArchive]) {
+    let rows = archives.iter().map(|archive| {
+        let host = if archive.host.is_empty() { "-" } else { archive.host.as_str() };
+        vec![archive.target.clone(), archive.name.clone(), archive.size.to_string(), archive.date.clone(), host.to_string()]
+    }).collect::<Vec<Vec<String>>>();
+    print_table(&["TARGET", "NAME", "SIZE", "DATE", "HOST"], &[2], &rows);
+}
+
+fn print_checks(checks: &[ArchiveCheck]) {
+    let rows = checks.iter().map(|check| {
+        let status = if check.ok { "ok" } else { "FAILED" };
+        let checked_against = if check.checked_against.is_empty() { "-" } else { check.checked_against.as_str() };
+        vec![check.target.clone(), check.archive.clone(), status.to_string(), check.entries.to_string(), checked_against.to_string(), check.error.clone()]
+    }).collect::<Vec<Vec<String>>>();
+    print_table(&["TARGET", "ARCHIVE", "STATUS", "ENTRIES", "HASH", "ERROR"], &[3], &rows);
+}
+
+fn print_found(found: &[FoundEntry]) {
+    if found.is_empty() {
+        println!("no matching entry");
+        return;
+    }
+    let rows = found.iter().map(|entry| {
+        let archive = if entry.archive.is_empty() { "-" } else { entry.archive.as_str() };
+        let source = if entry.run > 0 { format!("run #{}", entry.run) } else { entry.source.clone() };
+        let mtime = chrono::Local.timestamp_opt(entry.mtime as i64, 0).single()
+            .filter(|_| entry.mtime > 0)
+            .map(|time| time.format("%F %T").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        vec![entry.date.clone(), archive.to_string(), source, entry.path.clone(), entry.size.to_string(), mtime]
+    }).collect::<Vec<Vec<String>>>();
+    print_table(&["DATE", "ARCHIVE", "SOURCE", "PATH", "SIZE", "MTIME"], &[4], &rows);
+}
+
+fn catalog(config_file: &str, run: Option<i64>, limit: usize, json: bool) -> Result<()> {
+    let cfg = BackerConfig::load_from_file(config_file)?;
+    let catalog = Catalog::open(cfg.catalog.path.as_str())?;
+    let id = match run {
+        Some(id) => id,
+        None => {
+            let runs = catalog.runs(limit)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&runs)?);
+            } else {
+                print_runs(&runs);
+            }
+            return Ok(());
+        }
+    };
+    let run = catalog.run(id)?.ok_or_else(|| anyhow!("no run #{} in the catalog", id))?;
+    let targets = catalog.targets(id)?;
+    let files = catalog.files(id)?;
+    if json {
+        let details = serde_json::json!({ "run": run, "targets": targets, "files": files });
+        println!("{}", serde_json::to_string_pretty(&details)?);
+        return Ok(());
+    }
+    print_runs(&[run.clone()]);
+    if !run.archive_sha256.is_empty() {
+        println!("\nsha256: {}", run.archive_sha256);
+    }
+    if !run.error.is_empty() {
+        println!("\nerror: {}", run.error);
+    }
+    if !targets.is_empty() {
+        println!();
+        let rows = targets.iter()
+            .map(|t| vec![t.target.clone(), t.status.clone(), t.error.clone()])
+            .collect::<Vec<Vec<String>>>();
+        print_table(&["TARGET", "STATUS", "ERROR"], &[], &rows);
+    }
+    if !files.is_empty() {
+        println!();
+        let rows = files.iter().map(|f| {
+            let mtime = chrono::Local.timestamp_opt(f.mtime as i64, 0).single()
+                .map(|time| time.format("%F %T").to_string())
+                .unwrap_or_default();
+            vec![f.kind.clone(), f.size.to_string(), mtime, f.path.clone()]
+        }).collect::<Vec<Vec<String>>>();
+        print_table(&["KIND", "SIZE", "MTIME", "PATH"], &[1], &rows);
+    }
+    Ok(())
+}
+
+fn print_runs(runs: &[CatalogRun]) {
+    let rows = runs.iter().map(|run| {
+        let archive = if run.archive.is_empty() { "-" } else { run.archive.as_str() };
+        let started = chrono::DateTime::parse_from_rfc3339(run.started.as_str())
+            .map(|time| time.format("%F %T").to_string())
+            .unwrap_or_else(|_| run.started.clone());
+        vec![run.id.to_string(), started, run.status.clone(), run.files.to_string(), run.archive_size.to_string(), archive.to_string()]
+    }).collect::<Vec<Vec<String>>>();
+    print_table(&["ID", "STARTED", "STATUS", "FILES", "SIZE", "ARCHIVE"], &[0, 3, 4], &rows);
+}
+
+// columns padded to their widest value, the numeric ones in `right` aligned to the right
+fn print_table(header: &[&str], right: &[usize], rows: &[Vec<String>]) {
+    let header = header.iter().map(|column| column.to_string()).collect::<Vec<String>>();
+    let mut widths = header.iter().map(|column| column.len()).collect::<Vec<usize>>();
+    for row in rows.iter() {
+        for (i, column) in row.iter().enumerate() {
+            widths[i] = widths[i].max(column.len());
+        }
+    }
+    for row in [header].iter().chain(rows.iter()) {
+        let line = row.iter().enumerate()
+            .map(|(i, column)| if right.contains(&i) { format!("{:>w$}", column, w = widths[i]) } else { format!("{:<w$}", column, w = widths[i]) })
+            .collect::<Vec<String>>();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+fn patterns(globs: Vec<String>) -> Result<Vec<glob::Pattern>> {
+    globs.iter().map(|g| glob::Pattern::new(g.as_str()).map_err(|e| anyhow!("invalid pattern [{}]: {}", g, e))).collect()
+}
@@ -0,0 +1,197 @@
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+
+use crate::backer::summary::{JobSummary, TargetResult};
+use crate::utils::file;
+use crate::utils::file::ArchivedEntry;
+use crate::utils::host;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started TEXT NOT NULL,
+    finished TEXT NOT NULL,
+    status TEXT NOT NULL,
+    host TEXT NOT NULL,
+    backup_format TEXT NOT NULL,
+    archive TEXT NOT NULL,
+    archive_size INTEGER NOT NULL,
+    archive_sha256 TEXT NOT NULL,
+    files INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    error TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS run_targets (
+    run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+    target TEXT NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS run_files (
+    run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    sha256 TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS run_targets_run_id ON run_targets(run_id);
+CREATE INDEX IF NOT EXISTS run_files_run_id ON run_files(run_id);
+CREATE INDEX IF NOT EXISTS run_files_path ON run_files(path);
+";
+
+const RUN_COLUMNS: &str = "id, started, finished, status, host, backup_format, archive, archive_size, archive_sha256, files, bytes, error";
+
+/// A backup run as recorded in the catalog.
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogRun {
+    pub id: i64,
+    pub started: String,
+    pub finished: String,
+    pub status: String,
+    pub host: String,
+    pub backup_format: String,
+    // file name of the uploaded archive, empty for repository backups
+    pub archive: String,
+    pub archive_size: u64,
+    pub archive_sha256: String,
+    pub files: u64,
+    pub bytes: u64,
+    pub error: String,
+}
+
+impl CatalogRun {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            started: row.get(1)?,
+            finished: row.get(2)?,
+            status: row.get(3)?,
+            host: row.get(4)?,
+            backup_format: row.get(5)?,
+            archive: row.get(6)?,
+            archive_size: row.get::<_, i64>(7)? as u64,
+            archive_sha256: row.get(8)?,
+            files: row.get::<_, i64>(9)? as u64,
+            bytes: row.get::<_, i64>(10)? as u64,
+            error: row.get(11)?,
+        })
+    }
+}
+
+/// The sqlite database in the state dir recording every backup run: its times and status, the
+/// archive, the result of each target and the entries of the archive.
+pub struct Catalog {
+    conn: Connection,
+}
+
+impl Catalog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            file::create_dir(parent)?;
+        }
+        let conn = Connection::open(path.as_ref())?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Record a finished run, returns its id.
+    pub fn record_run(&mut self, summary: &JobSummary) -> Result<i64> {
+        let archive = Path::new(summary.archive.as_str()).file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO runs (started, finished, status, host, backup_format, archive, archive_size, archive_sha256, files, bytes, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                summary.started, summary.finished, summary.status, host::hostname(), summary.backup_format, archive,
+                summary.archive_size as i64, summary.archive_sha256, summary.files as i64, summary.bytes as i64, summary.error,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare("INSERT INTO run_targets (run_id, target, status, error) VALUES (?1, ?2, ?3, ?4)")?;
+            for result in summary.target_results.iter() {
+                insert.execute(params![id, result.target, result.status, result.error])?;
+            }
+            let mut insert = tx.prepare("INSERT INTO run_files (run_id, path, kind, size, mtime, sha256) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+            for entry in summary.entries.iter() {
+                insert.execute(params![id, entry.path, entry.kind, entry.size as i64, entry.mtime as i64, entry.sha256])?;
+            }
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// The last `limit` runs, newest first. A limit of 0 returns every run.
+    pub fn runs(&self, limit: usize) -> Result<Vec<CatalogRun>> {
+        let limit = if limit == 0 { -1 } else { limit as i64 };
+        let mut stmt = self.conn.prepare(format!("SELECT {} FROM runs ORDER BY id DESC LIMIT ?1", RUN_COLUMNS).as_str())?;
+        let runs = stmt.query_map(params![limit], CatalogRun::from_row)?.collect::<rusqlite::Result<Vec<CatalogRun>>>()?;
+        Ok(runs)
+    }
+
+    pub fn run(&self, id: i64) -> Result<Option<CatalogRun>> {
+        let run = self.conn.query_row(format!("SELECT {} FROM runs WHERE id = ?1", RUN_COLUMNS).as_str(), params![id], CatalogRun::from_row)
+            .optional()?;
+        Ok(run)
+    }
+
+    /// The sha256 recorded for the last upload of `archive`, the file name as stored.
+    pub fn archive_sha256(&self, archive: &str) -> Result<Option<String>> {
+        let sha256 = self.conn.query_row(
+            "SELECT archive_sha256 FROM runs WHERE archive = ?1 AND archive_sha256 != '' ORDER BY id DESC LIMIT 1",
+            params![archive],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(sha256)
+    }
+
+    pub fn targets(&self, run_id: i64) -> Result<Vec<TargetResult>> {
+        let mut stmt = self.conn.prepare("SELECT target, status, error FROM run_targets WHERE run_id = ?1 ORDER BY rowid")?;
+        let targets = stmt.query_map(params![run_id], |row| {
+            Ok(TargetResult { target: row.get(0)?, status: row.get(1)?, error: row.get(2)? })
+        })?.collect::<rusqlite::Result<Vec<TargetResult>>>()?;
+        Ok(targets)
+    }
+
+    /// The entries of the archive of a run, in archive order.
+    pub fn files(&self, run_id: i64) -> Result<Vec<ArchivedEntry>> {
+        let mut stmt = self.conn.prepare("SELECT path, kind, size, mtime, sha256 FROM run_files WHERE run_id = ?1 ORDER BY rowid")?;
+        let files = stmt.query_map(params![run_id], |row| entry_from_row(row, 0))?
+            .collect::<rusqlite::Result<Vec<ArchivedEntry>>>()?;
+        Ok(files)
+    }
+
+    /// The entries of every run whose path matches the SQLite GLOB `path_glob` and that `matches`
+    /// accepts, with the id of their run, by run and in archive order. The glob narrows the rows
+    /// read, `matches` decides.
+    pub fn find_files<F: Fn(&str) -> bool>(&self, path_glob: &str, matches: F) -> Result<Vec<(i64, ArchivedEntry)>> {
+        let mut stmt = self.conn.prepare("SELECT run_id, path, kind, size, mtime, sha256 FROM run_files WHERE path GLOB ?1 ORDER BY run_id, rowid")?;
+        let mut rows = stmt.query([path_glob])?;
+        let mut files = vec![];
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(1)?;
+            if matches(path.as_str()) {
+                files.push((row.get(0)?, entry_from_row(row, 1)?));
+            }
+        }
+        Ok(files)
+    }
+}
+
+// an entry of run_files from the columns path, kind, size, mtime and sha256 starting at `first`
+fn entry_from_row(row: &Row, first: usize) -> rusqlite::Result<ArchivedEntry> {
+    Ok(ArchivedEntry {
+        path: row.get(first)?,
+        kind: row.get(first + 1)?,
+        size: row.get::<_, i64>(first + 2)? as u64,
+        mtime: row.get::<_, i64>(first + 3)? as u64,
+        sha256: row.get(first + 4)?,
+    })
+}
@@ -2,10 +2,11 @@ use std::fs;
 use std::net::{IpAddr, ToSocketAddrs};
 use std::path::Path;
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use thiserror::Error;
 
 use crate::consts;
+use crate::utils::file;
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -27,13 +28,71 @@ pub enum ConfigError {
     QiniuSecretKeyEmpty,
     #[error("qiniu bucket name is empty")]
     QiniuBucketNameEmpty,
+    #[error("local path is empty")]
+    LocalPathEmpty,
+    #[error("compress mode invalid: {0}, supported zip, tar.gz, tar.zst")]
+    CompressModeInvalid(String),
+    #[error("backup format invalid: {0}")]
+    BackupFormatInvalid(String),
+    #[error("repository chunk size invalid: 0 < min-chunk-size < avg-chunk-size < max-chunk-size and avg-chunk-size >= 64 are required")]
+    RepositoryChunkSizeInvalid,
+    #[error("encryption is enabled but no passphrase, key-file or recipients are configured")]
+    EncryptionKeyEmpty,
+    #[error("encryption chunk size invalid")]
+    EncryptionChunkSizeInvalid,
+    #[error("encryption is only supported by the archive format")]
+    EncryptionUnsupported,
+    #[error("manifest is enabled but signing-key-file is empty")]
+    ManifestSigningKeyEmpty,
+    #[error("changed files policy invalid: {0}")]
+    ChangedFilesPolicyInvalid(String),
+    #[error("hook command is empty")]
+    HookCommandEmpty,
+    #[error("source type invalid: {0}")]
+    SourceTypeInvalid(String),
+    #[error("source path is empty")]
+    SourcePathEmpty,
+    #[error("source command is empty")]
+    SourceCommandEmpty,
+    #[error("command sources are only supported by the archive format")]
+    SourceCommandUnsupported,
+    #[error("source name invalid: {0}")]
+    SourceNameInvalid(String),
+    #[error("backup-files entry needs either path or files-from")]
+    BackupFileInvalid,
+    #[error("missing path policy invalid: {0}")]
+    MissingPathPolicyInvalid(String),
+    #[error("backup-files alias invalid: {0}, it needs a plain path and a relative name")]
+    BackupFileAliasInvalid(String),
+    #[error("cron invalid: {0}")]
+    CronInvalid(String),
+    #[error("verify archives invalid: {0}")]
+    VerifyArchivesInvalid(String),
+    #[error("path mapping mode invalid: {0}")]
+    PathMappingModeInvalid(String),
+    #[error("path mapping root is empty, the relative mode needs it")]
+    PathMappingRootEmpty,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct BackerConfig {
-    pub backup_files: Vec<String>,
+    #[serde(deserialize_with = "deserialize_backup_files")]
+    pub backup_files: Vec<BackupFileConfig>,
+    // default policy of backup-files entries that match nothing or can't be read
+    pub missing_paths: String,
+    pub path_mapping: PathMappingConfig,
+    pub sources: Vec<SourceConfig>,
     pub compress_mode: String,
+    pub compress_threads: usize,
+    pub backup_format: String,
+    pub volume_size: u64,
+    pub staging_dir: String,
+    // state kept between runs, e.g. the fingerprint of the last backup
+    pub state_dir: String,
+    pub skip_unchanged: SkipUnchangedConfig,
+    pub catalog: CatalogConfig,
+    pub verify: VerifyConfig,
     pub archive_prefix: String,
     pub job_cron: String,
     pub backup_target: Vec<String>,
@@ -41,6 +100,19 @@ pub struct BackerConfig {
     pub qiniu: QiniuServer,
     pub aliyun_oss: AliyunOssServer,
     pub tencent_oss: TencentOssServer,
+    pub local: LocalServer,
+    pub repository: RepositoryConfig,
+    pub encryption: EncryptionConfig,
+    pub manifest: ManifestConfig,
+    pub changed_files: ChangedFilesConfig,
+    pub follow_symlinks: bool,
+    pub one_file_system: bool,
+    pub deterministic: bool,
+    pub tar_index: bool,
+    pub store_uncompressed: StoreUncompressedConfig,
+    pub pre_hooks: Vec<HookConfig>,
+    pub post_hooks: Vec<HookConfig>,
+    pub on_failure: Vec<HookConfig>,
 }
 
 impl BackerConfig {
@@ -52,7 +124,7 @@ impl BackerConfig {
 
     pub fn load<C: AsRef<str>>(contents: C) -> Result<Self, ConfigError> {
         let contents = contents.as_ref();
-        if contents.len() == 0 {
+        if contents.is_empty() {
             // parsing empty string leads to EOF error
             Ok(Self::default())
         } else {
@@ -61,15 +133,130 @@ impl BackerConfig {
             if cfg.backup_target.len() <= 0 {
                 return Err(ConfigError::TargetEmpty);
             }
-            if cfg.compress_mode.len() == 0 {
+            if cfg.compress_mode.is_empty() {
                 cfg.compress_mode = consts::COMPRESS_MODE_ZIP.to_string();
             }
-            if cfg.archive_prefix.len() == 0 {
+            if ![consts::COMPRESS_MODE_ZIP, consts::COMPRESS_MODE_TAR, consts::COMPRESS_MODE_TAR_ZSTD].contains(&cfg.compress_mode.as_str()) {
+                return Err(ConfigError::CompressModeInvalid(cfg.compress_mode));
+            }
+            if cfg.backup_format.is_empty() {
+                cfg.backup_format = consts::BACKUP_FORMAT_ARCHIVE.to_string();
+            }
+            if cfg.backup_format != consts::BACKUP_FORMAT_ARCHIVE && cfg.backup_format != consts::BACKUP_FORMAT_REPOSITORY {
+                return Err(ConfigError::BackupFormatInvalid(cfg.backup_format));
+            }
+            if cfg.repository.name.is_empty() {
+                cfg.repository.name = consts::DEFAULT_REPOSITORY_NAME.to_string();
+            }
+            if cfg.repository.min_chunk_size == 0 || cfg.repository.avg_chunk_size < consts::MIN_AVG_CHUNK_SIZE
+                || cfg.repository.min_chunk_size >= cfg.repository.avg_chunk_size || cfg.repository.avg_chunk_size >= cfg.repository.max_chunk_size {
+                return Err(ConfigError::RepositoryChunkSizeInvalid);
+            }
+            if cfg.encryption.enabled {
+                if cfg.encryption.passphrase.is_empty() && cfg.encryption.passphrase_file.is_empty()
+                    && cfg.encryption.key_file.is_empty() && cfg.encryption.recipients.is_empty() {
+                    return Err(ConfigError::EncryptionKeyEmpty);
+                }
+                if cfg.encryption.chunk_size == 0 || cfg.encryption.chunk_size > consts::MAX_ENCRYPTION_CHUNK_SIZE {
+                    return Err(ConfigError::EncryptionChunkSizeInvalid);
+                }
+                // chunks of the repository are stored as they are
+                if cfg.backup_format == consts::BACKUP_FORMAT_REPOSITORY {
+                    return Err(ConfigError::EncryptionUnsupported);
+                }
+            }
+            if cfg.manifest.enabled && cfg.manifest.signing_key_file.is_empty() {
+                return Err(ConfigError::ManifestSigningKeyEmpty);
+            }
+            for source in cfg.sources.iter_mut() {
+                if source.source_type == consts::SOURCE_TYPE_COMMAND {
+                    if source.command.trim().is_empty() {
+                        return Err(ConfigError::SourceCommandEmpty);
+                    }
+                    // the command runs once, while the archive is written
+                    if cfg.backup_format == consts::BACKUP_FORMAT_REPOSITORY {
+                        return Err(ConfigError::SourceCommandUnsupported);
+                    }
+                } else if source.source_type != consts::SOURCE_TYPE_SQLITE {
+                    return Err(ConfigError::SourceTypeInvalid(source.source_type.clone()));
+                } else if source.path.is_empty() {
+                    return Err(ConfigError::SourcePathEmpty);
+                }
+                if source.name.is_empty() {
+                    source.name = Path::new(source.path.as_str()).file_name()
+                        .map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                }
+                // the snapshot is archived as archive/<name>
+                if Path::new(source.name.as_str()).components().count() != 1 || !file::is_safe_relative_path(source.name.as_str()) {
+                    return Err(ConfigError::SourceNameInvalid(source.name.clone()));
+                }
+            }
+            if cfg.missing_paths.is_empty() {
+                cfg.missing_paths = consts::MISSING_PATH_ERROR.to_string();
+            }
+            for entry in cfg.backup_files.iter_mut() {
+                if entry.path.is_empty() == entry.files_from.is_empty() {
+                    return Err(ConfigError::BackupFileInvalid);
+                }
+                if entry.missing.is_empty() {
+                    entry.missing = cfg.missing_paths.clone();
+                }
+                if !entry.alias.is_empty() && (entry.path.is_empty() || entry.path.contains(['*', '?', '['])
+                    || !file::is_safe_relative_path(entry.alias.as_str())) {
+                    return Err(ConfigError::BackupFileAliasInvalid(entry.alias.clone()));
+                }
+            }
+            if cfg.path_mapping.mode.is_empty() {
+                cfg.path_mapping.mode = consts::PATH_MAPPING_BASENAME.to_string();
+            }
+            if ![consts::PATH_MAPPING_BASENAME, consts::PATH_MAPPING_ABSOLUTE, consts::PATH_MAPPING_RELATIVE].contains(&cfg.path_mapping.mode.as_str()) {
+                return Err(ConfigError::PathMappingModeInvalid(cfg.path_mapping.mode));
+            }
+            if cfg.path_mapping.mode == consts::PATH_MAPPING_RELATIVE && cfg.path_mapping.root.is_empty() {
+                return Err(ConfigError::PathMappingRootEmpty);
+            }
+            for policy in cfg.backup_files.iter().map(|entry| &entry.missing).chain([&cfg.missing_paths]) {
+                if ![consts::MISSING_PATH_ERROR, consts::MISSING_PATH_WARN, consts::MISSING_PATH_IGNORE].contains(&policy.as_str()) {
+                    return Err(ConfigError::MissingPathPolicyInvalid(policy.clone()));
+                }
+            }
+            if cfg.changed_files.policy.is_empty() {
+                cfg.changed_files.policy = consts::CHANGE_POLICY_WARN.to_string();
+            }
+            if ![consts::CHANGE_POLICY_WARN, consts::CHANGE_POLICY_FAIL, consts::CHANGE_POLICY_MARK_INCONSISTENT].contains(&cfg.changed_files.policy.as_str()) {
+                return Err(ConfigError::ChangedFilesPolicyInvalid(cfg.changed_files.policy));
+            }
+            for hook in cfg.pre_hooks.iter().chain(cfg.post_hooks.iter()).chain(cfg.on_failure.iter()) {
+                if hook.command.trim().is_empty() {
+                    return Err(ConfigError::HookCommandEmpty);
+                }
+            }
+            if cfg.staging_dir.is_empty() {
+                cfg.staging_dir = file::get_archive_dir_path().to_string_lossy().to_string();
+            }
+            if cfg.state_dir.is_empty() {
+                cfg.state_dir = file::get_state_dir_path().to_string_lossy().to_string();
+            }
+            if cfg.catalog.path.is_empty() {
+                cfg.catalog.path = Path::new(cfg.state_dir.as_str()).join(consts::CATALOG_FILE).to_string_lossy().to_string();
+            }
+            if cfg.archive_prefix.is_empty() {
                 cfg.archive_prefix = consts::DEFAULT_ARCHIVE_PREFIX.to_string();
             }
-            if cfg.job_cron.len() == 0 {
+            if cfg.job_cron.is_empty() {
                 cfg.job_cron = consts::DEFAULT_CRON.to_string();
             }
+            if cfg.verify.cron.is_empty() {
+                cfg.verify.cron = consts::DEFAULT_VERIFY_CRON.to_string();
+            }
+            for cron in [&cfg.job_cron, &cfg.verify.cron] {
+                if let Err(e) = cron.parse::<job_scheduler::Schedule>() {
+                    return Err(ConfigError::CronInvalid(format!("{}: {}", cron, e)));
+                }
+            }
+            if ![consts::ARCHIVE_LATEST, consts::ARCHIVES_ALL].contains(&cfg.verify.archives.as_str()) {
+                return Err(ConfigError::VerifyArchivesInvalid(cfg.verify.archives));
+            }
             for i in 0..cfg.backup_target.len() {
                 if cfg.backup_target[i] == consts::TARGET_BACKER_SERVER {
                     if cfg.backer_server.ip.parse::<IpAddr>().is_err() {
@@ -79,15 +266,17 @@ impl BackerConfig {
                         }
                     }
                 } else if cfg.backup_target[i] == consts::TARGET_QINIU {
-                    if cfg.qiniu.access_key.len() == 0 {
+                    if cfg.qiniu.access_key.is_empty() {
                         return Err(ConfigError::QiniuAccessKeyEmpty);
                     }
-                    if cfg.qiniu.secret_key.len() == 0 {
+                    if cfg.qiniu.secret_key.is_empty() {
                         return Err(ConfigError::QiniuSecretKeyEmpty);
                     }
-                    if cfg.qiniu.bucket_name.len() == 0 {
+                    if cfg.qiniu.bucket_name.is_empty() {
                         return Err(ConfigError::QiniuBucketNameEmpty);
                     }
+                } else if cfg.backup_target[i] == consts::TARGET_LOCAL && cfg.local.path.is_empty() {
+                    return Err(ConfigError::LocalPathEmpty);
                 }
             }
 
@@ -100,7 +289,18 @@ impl Default for BackerConfig {
     fn default() -> Self {
         Self {
             backup_files: vec![],
+            missing_paths: String::from(consts::MISSING_PATH_ERROR),
+            path_mapping: PathMappingConfig::default(),
+            sources: vec![],
             compress_mode: String::from("tar.gz"),
+            compress_threads: 0,
+            backup_format: String::from("archive"),
+            volume_size: 0,
+            staging_dir: String::from(""),
+            state_dir: String::from(""),
+            skip_unchanged: SkipUnchangedConfig::default(),
+            catalog: CatalogConfig::default(),
+            verify: VerifyConfig::default(),
             archive_prefix: String::from("Archive"),
             job_cron: String::from("0 0 0 * * *"),
             backup_target: vec![],
@@ -108,6 +308,19 @@ impl Default for BackerConfig {
             qiniu: QiniuServer::default(),
             aliyun_oss: AliyunOssServer::default(),
             tencent_oss: TencentOssServer::default(),
+            local: LocalServer::default(),
+            repository: RepositoryConfig::default(),
+            encryption: EncryptionConfig::default(),
+            manifest: ManifestConfig::default(),
+            changed_files: ChangedFilesConfig::default(),
+            follow_symlinks: false,
+            one_file_system: false,
+            deterministic: false,
+            tar_index: true,
+            store_uncompressed: StoreUncompressedConfig::default(),
+            pre_hooks: vec![],
+            post_hooks: vec![],
+            on_failure: vec![],
         }
     }
 }
@@ -136,6 +349,7 @@ pub struct QiniuServer {
     pub access_key: String,
     pub secret_key: String,
     pub bucket_name: String,
+    pub download_domain: String,
 }
 
 impl Default for QiniuServer {
@@ -144,6 +358,7 @@ impl Default for QiniuServer {
             access_key: String::from(""),
             secret_key: String::from(""),
             bucket_name: String::from(""),
+            download_domain: String::from(""),
         }
     }
 }
@@ -178,6 +393,245 @@ impl Default for TencentOssServer {
     }
 }
 
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LocalServer {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RepositoryConfig {
+    pub name: String,
+    pub min_chunk_size: usize,
+    pub avg_chunk_size: usize,
+    pub max_chunk_size: usize,
+    pub pack_size: usize,
+}
+
+impl Default for RepositoryConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from(consts::DEFAULT_REPOSITORY_NAME),
+            min_chunk_size: consts::DEFAULT_MIN_CHUNK_SIZE,
+            avg_chunk_size: consts::DEFAULT_AVG_CHUNK_SIZE,
+            max_chunk_size: consts::DEFAULT_MAX_CHUNK_SIZE,
+            pack_size: consts::DEFAULT_PACK_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    pub passphrase: String,
+    pub passphrase_file: String,
+    pub key_file: String,
+    pub recipients: Vec<String>,
+    pub chunk_size: usize,
+}
+
+impl EncryptionConfig {
+    // passphrase from the config, or the first line of passphrase-file
+    pub fn load_passphrase(&self) -> std::io::Result<Option<String>> {
+        if !self.passphrase.is_empty() {
+            return Ok(Some(self.passphrase.clone()));
+        }
+        if !self.passphrase_file.is_empty() {
+            let contents = fs::read_to_string(self.passphrase_file.as_str())?;
+            return Ok(Some(contents.lines().next().unwrap_or("").to_string()));
+        }
+        Ok(None)
+    }
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            passphrase: String::from(""),
+            passphrase_file: String::from(""),
+            key_file: String::from(""),
+            recipients: vec![],
+            chunk_size: consts::DEFAULT_ENCRYPTION_CHUNK_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ManifestConfig {
+    pub enabled: bool,
+    pub signing_key_file: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ChangedFilesConfig {
+    // times a file that changed while being read is read again
+    pub retries: u32,
+    pub policy: String,
+}
+
+impl Default for ChangedFilesConfig {
+    fn default() -> Self {
+        Self {
+            retries: consts::DEFAULT_CHANGE_RETRIES,
+            policy: String::from(consts::CHANGE_POLICY_WARN),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BackupFileConfig {
+    // a path or a glob pattern
+    pub path: String,
+    // a file listing one path or pattern per line
+    pub files_from: String,
+    // error, warn or ignore when nothing matches or a path can't be read
+    pub missing: String,
+    // name below archive/, instead of the one given by path-mapping
+    pub alias: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PathMappingConfig {
+    // basename, absolute or relative (to root)
+    pub mode: String,
+    pub root: String,
+}
+
+impl Default for PathMappingConfig {
+    fn default() -> Self {
+        Self {
+            mode: String::from(consts::PATH_MAPPING_BASENAME),
+            root: String::from(""),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackupFileValue {
+    Path(String),
+    Entry(BackupFileConfig),
+}
+
+// a backup-files entry is either a plain path or pattern, or a map with its options
+fn deserialize_backup_files<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<BackupFileConfig>, D::Error> {
+    let values = Vec::<BackupFileValue>::deserialize(deserializer)?;
+    Ok(values.into_iter().map(|value| match value {
+        BackupFileValue::Path(path) => BackupFileConfig { path, ..Default::default() },
+        BackupFileValue::Entry(entry) => entry,
+    }).collect())
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SkipUnchangedConfig {
+    pub enabled: bool,
+    // back up unchanged sources anyway once the last backup is this old, 0 never does
+    pub full_every_days: u32,
+}
+
+impl Default for SkipUnchangedConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            full_every_days: consts::DEFAULT_FULL_EVERY_DAYS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct CatalogConfig {
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: String::from(""),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct VerifyConfig {
+    pub enabled: bool,
+    pub cron: String,
+    pub archives: String,
+    pub public_key: String,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cron: String::from(consts::DEFAULT_VERIFY_CRON),
+            archives: String::from(consts::ARCHIVE_LATEST),
+            public_key: String::from(""),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct StoreUncompressedConfig {
+    // file extensions archived without compression, without the leading dot
+    pub extensions: Vec<String>,
+    // also store files whose first block looks compressed already
+    pub entropy_sampling: bool,
+}
+
+impl Default for StoreUncompressedConfig {
+    fn default() -> Self {
+        Self {
+            extensions: consts::DEFAULT_STORE_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            entropy_sampling: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SourceConfig {
+    #[serde(rename = "type")]
+    pub source_type: String,
+    pub path: String,
+    // command whose stdout is archived, for the command type
+    pub command: String,
+    // file name of the source in the archive, default is the file name of path
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct HookConfig {
+    pub command: String,
+    // seconds, 0 means no timeout
+    pub timeout: u64,
+    // only used by pre-hooks: abort the backup job when the hook fails
+    pub abort_on_failure: bool,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            command: String::from(""),
+            timeout: consts::DEFAULT_HOOK_TIMEOUT,
+            abort_on_failure: true,
+        }
+    }
+}
+
 // resolve domain name (without port) to ip address
 fn resolve_domain(addr: &str) -> Option<String> {
     match format!("{}:1", addr).to_socket_addrs() {
@@ -190,4 +644,60 @@ fn resolve_domain(addr: &str) -> Option<String> {
             None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "backup-target: [local]\nlocal:\n  path: /tmp/backer\n";
+
+    fn load(extra: &str) -> Result<BackerConfig, ConfigError> {
+        BackerConfig::load(format!("{}{}", BASE, extra))
+    }
+
+    #[test]
+    fn defaults_are_filled() {
+        let cfg = load("").unwrap();
+        assert_eq!(cfg.backup_format, consts::BACKUP_FORMAT_ARCHIVE);
+    }
+
+    #[test]
+    fn unknown_compress_modes_are_rejected() {
+        for mode in ["zip", "tar.gz", "tar.zst"] {
+            assert!(load(&format!("compress-mode: {}\n", mode)).is_ok(), "{}", mode);
+        }
+        let err = load("compress-mode: tar.xz\n").unwrap_err();
+        assert!(matches!(err, ConfigError::CompressModeInvalid(_)));
+        assert!(err.to_string().contains("zip, tar.gz, tar.zst"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_crons_are_rejected() {
+        assert!(load("job-cron: \"0 0 3 * * *\"\n").is_ok());
+        assert!(matches!(load("job-cron: every night\n"), Err(ConfigError::CronInvalid(_))));
+        assert!(matches!(load("verify:\n  enabled: true\n  cron: every week\n"), Err(ConfigError::CronInvalid(_))));
+    }
+
+    #[test]
+    fn encrypted_repositories_are_rejected() {
+        let encryption = "encryption:\n  enabled: true\n  passphrase: secret\n";
+        assert!(load(encryption).is_ok());
+        assert!(matches!(load(&format!("backup-format: repository\n{}", encryption)), Err(ConfigError::EncryptionUnsupported)));
+    }
+
+    #[test]
+    fn manifests_need_a_signing_key() {
+        assert!(matches!(load("manifest:\n  enabled: true\n"), Err(ConfigError::ManifestSigningKeyEmpty)));
+        assert!(load("manifest:\n  enabled: true\n  signing-key-file: /etc/backer/signing.key\n").is_ok());
+    }
+
+    #[test]
+    fn tiny_chunk_sizes_are_rejected() {
+        let repository = |min, avg, max| load(&format!("repository:\n  min-chunk-size: {}\n  avg-chunk-size: {}\n  max-chunk-size: {}\n", min, avg, max));
+        assert!(repository(16, 64, 256).is_ok());
+        assert!(matches!(repository(0, 64, 256), Err(ConfigError::RepositoryChunkSizeInvalid)));
+        assert!(matches!(repository(1, 32, 256), Err(ConfigError::RepositoryChunkSizeInvalid)));
+        assert!(matches!(repository(64, 64, 256), Err(ConfigError::RepositoryChunkSizeInvalid)));
+    }
+}
@@ -1,19 +1,98 @@
-pub const ARCHIVE_DIR_SUFFIX: &'static str = ".backer/archive";
+pub const ARCHIVE_DIR_SUFFIX: &str = ".backer/archive";
+pub const STAGING_RUN_PREFIX: &str = "backer-run-";
+pub const STAGING_LOCK_FILE: &str = ".backer.lock";
 
-pub const TARGET_BACKER_SERVER: &'static str = "backer-server";
+pub const TARGET_BACKER_SERVER: &str = "backer-server";
 
-pub const TARGET_QINIU: &'static str = "qiniu";
-pub const TARGET_ALIYUN_OSS: &'static str = "aliyun-oss";
-pub const TARGET_TENCENT_OSS: &'static str = "tencent-oss";
+pub const TARGET_QINIU: &str = "qiniu";
+pub const TARGET_ALIYUN_OSS: &str = "aliyun-oss";
+pub const TARGET_TENCENT_OSS: &str = "tencent-oss";
+pub const TARGET_LOCAL: &str = "local";
 
-pub const COMPRESS_MODE_ZIP: &'static str = "zip";
-pub const COMPRESS_MODE_TAR: &'static str = "tar.gz";
+pub const COMPRESS_MODE_ZIP: &str = "zip";
+pub const COMPRESS_MODE_TAR: &str = "tar.gz";
+pub const COMPRESS_MODE_TAR_ZSTD: &str = "tar.zst";
 
-pub const DEFAULT_ARCHIVE_PREFIX: &'static str = "Archive";
+pub const BACKUP_FORMAT_ARCHIVE: &str = "archive";
+pub const BACKUP_FORMAT_REPOSITORY: &str = "repository";
 
-pub const DEFAULT_CRON: &'static str = "0 0 0 * * *";
+pub const REPOSITORY_CACHE_DIR_SUFFIX: &str = ".backer/repository";
+pub const STATE_DIR_SUFFIX: &str = ".backer/state";
+pub const DEFAULT_FULL_EVERY_DAYS: u32 = 7;
+pub const CATALOG_FILE: &str = "catalog.db";
+pub const DEFAULT_REPOSITORY_NAME: &str = "backer-repository";
+pub const DEFAULT_MIN_CHUNK_SIZE: usize = 512 * 1024;
+pub const DEFAULT_AVG_CHUNK_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+pub const MIN_AVG_CHUNK_SIZE: usize = 64;
+pub const DEFAULT_PACK_SIZE: usize = 16 * 1024 * 1024;
 
-pub const BACKUP_TARGET_BACKER_SERVER: &'static str = "backer-server";
-pub const BACKUP_TARGET_QINIU: &'static str = "qiniu";
-pub const BACKUP_TARGET_ALIYUN_OSS: &'static str = "aliyun-oss";
-pub const BACKUP_TARGET_TENCENT_OSS: &'static str = "tencent-oss";
\ No newline at end of file
+pub const ENCRYPTED_ARCHIVE_SUFFIX: &str = "enc";
+pub const DEFAULT_ENCRYPTION_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_ENCRYPTION_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+pub const MANIFEST_SUFFIX: &str = "manifest";
+pub const SIGNATURE_SUFFIX: &str = "sig";
+pub const VOLUME_INDEX_SUFFIX: &str = "volumes";
+pub const TAR_INDEX_SUFFIX: &str = "index";
+
+pub const SOURCE_TYPE_SQLITE: &str = "sqlite";
+pub const SOURCE_TYPE_COMMAND: &str = "command";
+pub const SOURCES_DIR: &str = "sources";
+
+pub const CHANGE_POLICY_WARN: &str = "warn";
+pub const CHANGE_POLICY_FAIL: &str = "fail";
+pub const CHANGE_POLICY_MARK_INCONSISTENT: &str = "mark-inconsistent";
+pub const PATH_MAPPING_BASENAME: &str = "basename";
+pub const PATH_MAPPING_ABSOLUTE: &str = "absolute";
+pub const PATH_MAPPING_RELATIVE: &str = "relative";
+
+pub const MISSING_PATH_ERROR: &str = "error";
+pub const MISSING_PATH_WARN: &str = "warn";
+pub const MISSING_PATH_IGNORE: &str = "ignore";
+
+pub const DEFAULT_CHANGE_RETRIES: u32 = 3;
+pub const INCONSISTENT_FILES_ENTRY: &str = ".backer-inconsistent";
+
+pub const ENTRY_KIND_FILE: &str = "file";
+pub const ENTRY_KIND_DIR: &str = "dir";
+pub const ENTRY_KIND_SYMLINK: &str = "symlink";
+
+// already compressed formats, compressing them again only costs cpu
+pub const DEFAULT_STORE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "ogg", "opus", "flac", "m4a",
+    "mp4", "mkv", "mov", "avi", "webm", "m4v",
+    "gz", "tgz", "bz2", "xz", "zst", "lz4", "zip", "7z", "rar", "jar", "apk",
+    "docx", "xlsx", "pptx", "odt", "pdf",
+];
+
+pub const HOOK_PRE: &str = "pre";
+pub const HOOK_POST: &str = "post";
+pub const HOOK_ON_FAILURE: &str = "on-failure";
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+pub const HOOK_OUTPUT_GRACE_SECONDS: u64 = 5;
+
+pub const JOB_STATUS_RUNNING: &str = "running";
+pub const JOB_STATUS_SUCCESS: &str = "success";
+pub const JOB_STATUS_FAILURE: &str = "failure";
+pub const JOB_STATUS_UNCHANGED: &str = "unchanged";
+
+pub const DEFAULT_ARCHIVE_PREFIX: &str = "Archive";
+pub const ARCHIVE_LATEST: &str = "latest";
+pub const ARCHIVES_ALL: &str = "all";
+
+pub const WORK_DIR_PREFIX: &str = "backer-work-";
+pub const RESTORED_SUFFIX: &str = "restored";
+pub const CONFLICT_OVERWRITE: &str = "overwrite";
+pub const CONFLICT_SKIP: &str = "skip";
+pub const CONFLICT_RENAME: &str = "rename";
+
+pub const DEFAULT_CRON: &str = "0 0 0 * * *";
+pub const DEFAULT_VERIFY_CRON: &str = "0 0 4 * * Sun";
+
+pub const BACKUP_TARGET_BACKER_SERVER: &str = "backer-server";
+pub const BACKUP_TARGET_QINIU: &str = "qiniu";
+pub const BACKUP_TARGET_ALIYUN_OSS: &str = "aliyun-oss";
+pub const BACKUP_TARGET_TENCENT_OSS: &str = "tencent-oss";
+pub const BACKUP_TARGET_LOCAL: &str = "local";
\ No newline at end of file
@@ -0,0 +1,559 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::config::config::EncryptionConfig;
+use crate::consts;
+use crate::utils::file;
+
+pub const MAGIC: &[u8; 8] = b"BACKER\x00\x01";
+
+pub const STANZA_PASSPHRASE: &str = "passphrase";
+pub const STANZA_KEY_FILE: &str = "key-file";
+pub const STANZA_X25519: &str = "x25519";
+
+pub const SECRET_KEY_PREFIX: &str = "backer-secret-key:";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_PREFIX_LENGTH: usize = 19;
+const TAG_LENGTH: usize = 16;
+
+const ARGON2_M_COST: u32 = 64 * 1024;
+const ARGON2_T_COST: u32 = 3;
+const ARGON2_P_COST: u32 = 1;
+// bounds on what a header may ask for, so a corrupted or hostile archive can't exhaust memory or cpu
+const MAX_HEADER_LENGTH: u32 = 64 * 1024;
+const MAX_ARGON2_M_COST: u32 = 1024 * 1024;
+const MAX_ARGON2_T_COST: u32 = 16;
+const MAX_ARGON2_P_COST: u32 = 16;
+
+/// File key wrapped for one way of decrypting the archive.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stanza {
+    pub kind: String,
+    pub key_id: String,
+    pub salt: Vec<u8>,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub ephemeral_public: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub chunk_size: u32,
+    pub nonce_prefix: Vec<u8>,
+    pub stanzas: Vec<Stanza>,
+}
+
+/// Something that can unwrap the file key of an encrypted archive.
+pub enum Identity {
+    Passphrase(String),
+    KeyFile([u8; KEY_LENGTH]),
+    X25519(StaticSecret),
+}
+
+impl Identity {
+    /// Load a key file written by `generate_key` or a recovery key written by `generate_key_pair`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = String::from_utf8(file::read_file(path.as_ref()).map_err(|e| anyhow!("{}", e))?)?;
+        let contents = contents.trim();
+        if let Some(secret) = contents.strip_prefix(SECRET_KEY_PREFIX) {
+            Ok(Identity::X25519(StaticSecret::from(decode_key(secret)?)))
+        } else {
+            Ok(Identity::KeyFile(decode_key(contents)?))
+        }
+    }
+}
+
+/// Generate a random key for a key file, hex encoded.
+pub fn generate_key() -> String {
+    let mut key = [0u8; KEY_LENGTH];
+    OsRng.fill_bytes(&mut key);
+    hex::encode(key)
+}
+
+/// Generate a recovery key pair. Returns the secret key (to keep offline) and the public key
+/// (to add to `encryption.recipients`).
+pub fn generate_key_pair() -> (String, String) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    (format!("{}{}", SECRET_KEY_PREFIX, hex::encode(secret.to_bytes())), hex::encode(public.as_bytes()))
+}
+
+pub fn encrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, cfg: &EncryptionConfig) -> Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let writer = BufWriter::new(File::create(dst)?);
+    let mut encryptor = Encryptor::new(writer, cfg)?;
+    io::copy(&mut reader, &mut encryptor)?;
+    encryptor.finish()?.flush()?;
+    Ok(())
+}
+
+pub fn decrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, identities: &[Identity]) -> Result<()> {
+    let reader = BufReader::new(File::open(src)?);
+    let mut decryptor = Decryptor::new(reader, identities)?;
+    let mut writer = BufWriter::new(File::create(dst)?);
+    io::copy(&mut decryptor, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Streaming XChaCha20-Poly1305 encryption in fixed size chunks.
+///
+/// Every chunk is sealed with a nonce made of a random prefix, the chunk counter and a flag for
+/// the last chunk, so reordered, dropped or truncated chunks fail to decrypt. The header is used
+/// as associated data of every chunk.
+pub struct Encryptor<W: Write> {
+    writer: W,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    aad: Vec<u8>,
+    counter: u32,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    pub fn new(mut writer: W, cfg: &EncryptionConfig) -> Result<Self> {
+        let mut file_key = [0u8; KEY_LENGTH];
+        OsRng.fill_bytes(&mut file_key);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LENGTH];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let mut stanzas = vec![];
+        let passphrase = cfg.load_passphrase().map_err(|e| anyhow!("read passphrase failed: {}", e))?;
+        if let Some(passphrase) = passphrase {
+            stanzas.push(passphrase_stanza(passphrase.as_str(), &file_key)?);
+        }
+        if !cfg.key_file.is_empty() {
+            match Identity::load(cfg.key_file.as_str())? {
+                Identity::KeyFile(key) => stanzas.push(key_file_stanza(&key, &file_key)?),
+                _ => return Err(anyhow!("key file [{}] is not a symmetric key", cfg.key_file)),
+            }
+        }
+        for recipient in cfg.recipients.iter() {
+            stanzas.push(x25519_stanza(recipient.as_str(), &file_key)?);
+        }
+        if stanzas.is_empty() {
+            return Err(anyhow!("no encryption key configured"));
+        }
+
+        let header = Header {
+            chunk_size: cfg.chunk_size as u32,
+            nonce_prefix: nonce_prefix.to_vec(),
+            stanzas,
+        };
+        let aad = bincode::serialize(&header)?;
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<NetworkEndian>(aad.len() as u32)?;
+        writer.write_all(&aad)?;
+        Ok(Self {
+            writer,
+            cipher: XChaCha20Poly1305::new_from_slice(&file_key).map_err(|_| anyhow!("invalid file key"))?,
+            nonce_prefix,
+            aad,
+            counter: 0,
+            chunk_size: cfg.chunk_size,
+            buffer: Vec::with_capacity(cfg.chunk_size),
+        })
+    }
+
+    /// Seal the remaining data as the last chunk and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let last = std::mem::take(&mut self.buffer);
+        self.seal(&last, true)?;
+        Ok(self.writer)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let sealed = self.cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: chunk, aad: &self.aad })
+            .map_err(|_| io::Error::other("encrypt chunk failed"))?;
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::other("too many chunks"))?;
+        self.writer.write_all(&sealed)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // keep at least one full chunk buffered, the last chunk is only known on finish
+        while self.buffer.len() > self.chunk_size {
+            let chunk: Vec<u8> = self.buffer.drain(..self.chunk_size).collect();
+            self.seal(&chunk, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct Decryptor<R: Read> {
+    reader: R,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    aad: Vec<u8>,
+    counter: u32,
+    chunk_size: usize,
+    plain: Vec<u8>,
+    pos: usize,
+    next: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    pub fn new(mut reader: R, identities: &[Identity]) -> Result<Self> {
+        let header = read_header(&mut reader)?;
+        let aad = bincode::serialize(&header)?;
+        if header.nonce_prefix.len() != NONCE_PREFIX_LENGTH {
+            return Err(anyhow!("invalid encryption header"));
+        }
+        if header.chunk_size == 0 || header.chunk_size as usize > consts::MAX_ENCRYPTION_CHUNK_SIZE {
+            return Err(anyhow!("invalid encryption header, chunk size {} is out of range", header.chunk_size));
+        }
+        let file_key = unwrap_file_key(&header, identities)?;
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LENGTH];
+        nonce_prefix.copy_from_slice(&header.nonce_prefix);
+        let mut decryptor = Self {
+            reader,
+            cipher: XChaCha20Poly1305::new_from_slice(&file_key).map_err(|_| anyhow!("invalid file key"))?,
+            nonce_prefix,
+            aad,
+            counter: 0,
+            chunk_size: header.chunk_size as usize,
+            plain: vec![],
+            pos: 0,
+            next: vec![],
+            done: false,
+        };
+        decryptor.next = decryptor.read_sealed_chunk()?;
+        Ok(decryptor)
+    }
+
+    fn read_sealed_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = vec![0u8; self.chunk_size + TAG_LENGTH];
+        let mut filled = 0;
+        while filled < chunk.len() {
+            let n = self.reader.read(&mut chunk[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        chunk.truncate(filled);
+        Ok(chunk)
+    }
+
+    fn open_next(&mut self) -> io::Result<()> {
+        let sealed = std::mem::take(&mut self.next);
+        if sealed.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "encrypted archive is truncated"));
+        }
+        self.next = self.read_sealed_chunk()?;
+        let last = self.next.is_empty();
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        self.plain = self.cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: &sealed, aad: &self.aad })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decrypt chunk failed, archive is corrupted or truncated"))?;
+        self.pos = 0;
+        self.counter += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Read the header of an encrypted archive, e.g. to show which keys can open it.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<Header> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow!("not an encrypted backer archive"));
+    }
+    let header_len = reader.read_u32::<NetworkEndian>()?;
+    if header_len > MAX_HEADER_LENGTH {
+        return Err(anyhow!("invalid encryption header, length {} is out of range", header_len));
+    }
+    let mut bytes = vec![0u8; header_len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LENGTH], counter: u32, last: bool) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce[..NONCE_PREFIX_LENGTH].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LENGTH..NONCE_PREFIX_LENGTH + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    nonce
+}
+
+fn key_id(bytes: &[u8]) -> String {
+    hex::encode(&Sha256::digest(bytes)[..8])
+}
+
+fn decode_key(key: &str) -> Result<[u8; KEY_LENGTH]> {
+    let bytes = hex::decode(key.trim())?;
+    if bytes.len() != KEY_LENGTH {
+        return Err(anyhow!("key must be {} bytes", KEY_LENGTH));
+    }
+    let mut key = [0u8; KEY_LENGTH];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn wrap_key(kek: &[u8], file_key: &[u8; KEY_LENGTH]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let cipher = XChaCha20Poly1305::new_from_slice(kek).map_err(|_| anyhow!("invalid key"))?;
+    let mut nonce = [0u8; 24];
+    OsRng.fill_bytes(&mut nonce);
+    let wrapped = cipher.encrypt(XNonce::from_slice(&nonce), file_key.as_slice()).map_err(|_| anyhow!("wrap file key failed"))?;
+    Ok((nonce.to_vec(), wrapped))
+}
+
+fn unwrap_key(kek: &[u8], stanza: &Stanza) -> Option<[u8; KEY_LENGTH]> {
+    let cipher = XChaCha20Poly1305::new_from_slice(kek).ok()?;
+    if stanza.nonce.len() != 24 {
+        return None;
+    }
+    let key = cipher.decrypt(XNonce::from_slice(&stanza.nonce), stanza.wrapped_key.as_slice()).ok()?;
+    key.try_into().ok()
+}
+
+fn derive_passphrase_key(passphrase: &str, salt: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> Result<[u8; KEY_LENGTH]> {
+    if m_cost > MAX_ARGON2_M_COST || t_cost > MAX_ARGON2_T_COST || p_cost > MAX_ARGON2_P_COST {
+        return Err(anyhow!("argon2 costs m={} t={} p={} exceed the maximum of m={} t={} p={}",
+            m_cost, t_cost, p_cost, MAX_ARGON2_M_COST, MAX_ARGON2_T_COST, MAX_ARGON2_P_COST));
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LENGTH)).map_err(|e| anyhow!("{}", e))?;
+    let mut kek = [0u8; KEY_LENGTH];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut kek)
+        .map_err(|e| anyhow!("{}", e))?;
+    Ok(kek)
+}
+
+fn x25519_kek(shared: &[u8], ephemeral_public: &[u8], recipient: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut hasher = Sha256::new();
+    hasher.update(shared);
+    hasher.update(ephemeral_public);
+    hasher.update(recipient);
+    hasher.finalize().into()
+}
+
+fn passphrase_stanza(passphrase: &str, file_key: &[u8; KEY_LENGTH]) -> Result<Stanza> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let kek = derive_passphrase_key(passphrase, &salt, ARGON2_M_COST, ARGON2_T_COST, ARGON2_P_COST)?;
+    let (nonce, wrapped_key) = wrap_key(&kek, file_key)?;
+    Ok(Stanza {
+        kind: STANZA_PASSPHRASE.to_string(),
+        key_id: key_id(&kek),
+        salt: salt.to_vec(),
+        m_cost: ARGON2_M_COST,
+        t_cost: ARGON2_T_COST,
+        p_cost: ARGON2_P_COST,
+        nonce,
+        wrapped_key,
+        ..Default::default()
+    })
+}
+
+fn key_file_stanza(key: &[u8; KEY_LENGTH], file_key: &[u8; KEY_LENGTH]) -> Result<Stanza> {
+    let (nonce, wrapped_key) = wrap_key(key, file_key)?;
+    Ok(Stanza {
+        kind: STANZA_KEY_FILE.to_string(),
+        key_id: key_id(key),
+        nonce,
+        wrapped_key,
+        ..Default::default()
+    })
+}
+
+fn x25519_stanza(recipient: &str, file_key: &[u8; KEY_LENGTH]) -> Result<Stanza> {
+    let recipient = PublicKey::from(decode_key(recipient).map_err(|e| anyhow!("invalid recipient [{}]: {}", recipient, e))?);
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+    let kek = x25519_kek(shared.as_bytes(), ephemeral_public.as_bytes(), recipient.as_bytes());
+    let (nonce, wrapped_key) = wrap_key(&kek, file_key)?;
+    Ok(Stanza {
+        kind: STANZA_X25519.to_string(),
+        key_id: key_id(recipient.as_bytes()),
+        ephemeral_public: ephemeral_public.as_bytes().to_vec(),
+        nonce,
+        wrapped_key,
+        ..Default::default()
+    })
+}
+
+fn unwrap_file_key(header: &Header, identities: &[Identity]) -> Result<[u8; KEY_LENGTH]> {
+    for identity in identities {
+        for stanza in header.stanzas.iter() {
+            let file_key = match (identity, stanza.kind.as_str()) {
+                // a stanza with argon2 costs out of range is skipped like one that doesn't authenticate
+                (Identity::Passphrase(passphrase), STANZA_PASSPHRASE) => {
+                    derive_passphrase_key(passphrase, &stanza.salt, stanza.m_cost, stanza.t_cost, stanza.p_cost)
+                        .ok()
+                        .and_then(|kek| unwrap_key(&kek, stanza))
+                }
+                (Identity::KeyFile(key), STANZA_KEY_FILE) if key_id(key) == stanza.key_id => unwrap_key(key, stanza),
+                (Identity::X25519(secret), STANZA_X25519) => {
+                    let public = PublicKey::from(secret);
+                    if key_id(public.as_bytes()) != stanza.key_id || stanza.ephemeral_public.len() != KEY_LENGTH {
+                        continue;
+                    }
+                    let mut ephemeral_public = [0u8; KEY_LENGTH];
+                    ephemeral_public.copy_from_slice(&stanza.ephemeral_public);
+                    let shared = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+                    let kek = x25519_kek(shared.as_bytes(), &ephemeral_public, public.as_bytes());
+                    unwrap_key(&kek, stanza)
+                }
+                _ => None,
+            };
+            if let Some(file_key) = file_key {
+                return Ok(file_key);
+            }
+        }
+    }
+    let key_ids = header.stanzas.iter().map(|s| format!("{}:{}", s.kind, s.key_id)).collect::<Vec<String>>();
+    Err(anyhow!("no identity can decrypt this archive, it was encrypted for: {}", key_ids.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(data: &[u8], recipient: &str, chunk_size: usize) -> Vec<u8> {
+        let cfg = EncryptionConfig {
+            enabled: true,
+            recipients: vec![recipient.to_string()],
+            chunk_size,
+            ..Default::default()
+        };
+        let mut encryptor = Encryptor::new(vec![], &cfg).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(sealed: &[u8], identity: Identity) -> Result<Vec<u8>> {
+        let mut decryptor = Decryptor::new(sealed, &[identity])?;
+        let mut plain = vec![];
+        decryptor.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    fn recovery_identity(secret: &str) -> Identity {
+        Identity::X25519(StaticSecret::from(decode_key(secret.strip_prefix(SECRET_KEY_PREFIX).unwrap()).unwrap()))
+    }
+
+    // a sealed stream with its header replaced
+    fn with_header(sealed: &[u8], header: &Header) -> Vec<u8> {
+        let header_len = u32::from_be_bytes(sealed[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap()) as usize;
+        let bytes = bincode::serialize(header).unwrap();
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        out.extend_from_slice(&bytes);
+        out.extend_from_slice(&sealed[MAGIC.len() + 4 + header_len..]);
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let (secret, public) = generate_key_pair();
+        // empty, shorter than a chunk, exactly one chunk and several chunks
+        for len in [0usize, 10, 64, 1000] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+            let sealed = encrypt(&data, public.as_str(), 64);
+            assert_eq!(decrypt(&sealed, recovery_identity(secret.as_str())).unwrap(), data, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn wrong_key_and_truncation_fail() {
+        let (secret, public) = generate_key_pair();
+        let (other, _) = generate_key_pair();
+        let sealed = encrypt(&[7u8; 300], public.as_str(), 64);
+        assert!(decrypt(&sealed, recovery_identity(other.as_str())).is_err());
+        assert!(decrypt(&sealed[..sealed.len() - 1], recovery_identity(secret.as_str())).is_err());
+        // dropping the whole last chunk leaves a stream that ends on a non-last chunk
+        assert!(decrypt(&sealed[..sealed.len() - (300 % 64 + TAG_LENGTH)], recovery_identity(secret.as_str())).is_err());
+    }
+
+    #[test]
+    fn oversized_header_is_rejected() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(MAX_HEADER_LENGTH + 1).to_be_bytes());
+        let err = read_header(&mut bytes.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+    }
+
+    #[test]
+    fn header_chunk_size_is_bounded() {
+        let (secret, public) = generate_key_pair();
+        let sealed = encrypt(&[1u8; 10], public.as_str(), 64);
+        let header = read_header(&mut sealed.as_slice()).unwrap();
+        for chunk_size in [0u32, consts::MAX_ENCRYPTION_CHUNK_SIZE as u32 + 1, u32::MAX] {
+            let hostile = with_header(&sealed, &Header { chunk_size, ..header.clone() });
+            let err = decrypt(&hostile, recovery_identity(secret.as_str())).unwrap_err();
+            assert!(err.to_string().contains("chunk size"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn argon2_costs_are_bounded() {
+        let salt = [0u8; 16];
+        for (m, t, p) in [(MAX_ARGON2_M_COST + 1, 1, 1), (8, MAX_ARGON2_T_COST + 1, 1), (8, 1, MAX_ARGON2_P_COST + 1)] {
+            assert!(derive_passphrase_key("secret", &salt, m, t, p).is_err(), "m={} t={} p={}", m, t, p);
+        }
+        assert!(derive_passphrase_key("secret", &salt, 8, 1, 1).is_ok());
+    }
+
+    #[test]
+    fn hostile_passphrase_stanza_is_skipped() {
+        let (secret, public) = generate_key_pair();
+        let cfg = EncryptionConfig {
+            enabled: true,
+            passphrase: "secret".to_string(),
+            recipients: vec![public],
+            chunk_size: 64,
+            ..Default::default()
+        };
+        let sealed = Encryptor::new(vec![], &cfg).unwrap().finish().unwrap();
+        let mut header = read_header(&mut sealed.as_slice()).unwrap();
+        let file_key = unwrap_file_key(&header, &[recovery_identity(secret.as_str())]).unwrap();
+        for stanza in header.stanzas.iter_mut().filter(|s| s.kind == STANZA_PASSPHRASE) {
+            stanza.m_cost = MAX_ARGON2_M_COST + 1;
+        }
+        let identities = [Identity::Passphrase("secret".to_string()), recovery_identity(secret.as_str())];
+        assert_eq!(unwrap_file_key(&header, &identities).unwrap(), file_key);
+    }
+}
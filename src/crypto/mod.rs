@@ -0,0 +1 @@
+pub mod crypto;
\ No newline at end of file
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::catalog::catalog::Catalog;
+use crate::config::config::BackerConfig;
+use crate::consts;
+use crate::crypto::crypto::Identity;
+use crate::restore::restore;
+use crate::utils::file;
+use crate::utils::file::CompressType;
+use crate::utils::staging::WorkDir;
+
+/// An entry of a snapshot, by its path below `archive/`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotEntry {
+    pub kind: String,
+    pub size: u64,
+    pub mtime: u64,
+    // not recorded in the catalog
+    pub mode: Option<u32>,
+    // empty when the content wasn't hashed
+    pub sha256: String,
+}
+
+/// The entries of a backup, from a run of the catalog or an archive on a target.
+pub struct Snapshot {
+    pub name: String,
+    pub entries: BTreeMap<String, SnapshotEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffEntry {
+    pub path: String,
+    pub kind: String,
+    pub size: u64,
+    // size in the second snapshot minus size in the first
+    pub size_delta: i64,
+    // what changed for modified and metadata changed entries: kind, size, content, mtime, mode
+    pub changes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SnapshotDiff {
+    pub from: String,
+    pub to: String,
+    // whether the content of entries of the same size was compared
+    pub content_compared: bool,
+    pub added: Vec<DiffEntry>,
+    pub removed: Vec<DiffEntry>,
+    pub modified: Vec<DiffEntry>,
+    pub metadata_changed: Vec<DiffEntry>,
+    pub size_delta: i64,
+}
+
+/// Load the snapshot `spec`: `#<id>` or `<id>` is a run of the catalog, anything else an archive
+/// (or `latest`) on `target`. The content of the entries of an archive is hashed when `content`
+/// is set, runs have the hashes of their manifest.
+pub fn load_snapshot(cfg: &BackerConfig, spec: &str, target: &str, content: bool, identities: &[Identity]) -> Result<Snapshot> {
+    if let Ok(id) = spec.trim_start_matches('#').parse::<i64>() {
+        return load_run(cfg, id);
+    }
+    if cfg.backup_format == consts::BACKUP_FORMAT_REPOSITORY {
+        return Err(anyhow!("archives of the repository format can't be compared, compare runs of the catalog instead"));
+    }
+    let archive = restore::resolve_archive(cfg, target, spec)?;
+    let compress_type = restore::compress_type_of(archive.as_str())?;
+    let work_dir = WorkDir::create(cfg.staging_dir.as_str())?;
+    let name = format!("{} on {}", archive, target);
+    let path = restore::fetch_archive(cfg, target, identities, archive.as_str(), work_dir.dir())?;
+    let entries = match compress_type {
+        CompressType::Zip => zip_entries(File::open(&path)?, content)?,
+        _ => tar_entries(&path, &compress_type, content)?,
+    };
+    Ok(Snapshot { name, entries })
+}
+
+fn load_run(cfg: &BackerConfig, id: i64) -> Result<Snapshot> {
+    if !cfg.catalog.enabled || !file::is_exist(cfg.catalog.path.as_str()) {
+        return Err(anyhow!("no catalog at [{}]", cfg.catalog.path));
+    }
+    let catalog = Catalog::open(cfg.catalog.path.as_str())?;
+    let run = catalog.run(id)?.ok_or_else(|| anyhow!("no run #{} in the catalog", id))?;
+    let mut entries = BTreeMap::new();
+    for f in catalog.files(id)? {
+        if let Some(path) = file::archive_entry_path(f.path.as_str()) {
+            entries.insert(path, SnapshotEntry { kind: f.kind, size: f.size, mtime: f.mtime, mode: None, sha256: f.sha256 });
+        }
+    }
+    let name = if run.archive.is_empty() { format!("run #{}", id) } else { format!("run #{} ({})", id, run.archive) };
+    Ok(Snapshot { name, entries })
+}
+
+fn hash_reader<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn zip_entries(reader: File, content: bool) -> Result<BTreeMap<String, SnapshotEntry>> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let mut entries = BTreeMap::new();
+    for i in 0..zip.len() {
+        // the data of a raw entry isn't decompressed, it's only read for the hash
+        let mut entry = if content { zip.by_index(i)? } else { zip.by_index_raw(i)? };
+        let path = match file::archive_entry_path(entry.name()) {
+            Some(path) => path,
+            None => continue,
+        };
+        let mode = entry.unix_mode();
+        let kind = if entry.is_dir() {
+            consts::ENTRY_KIND_DIR
+        } else if mode.map(|mode| mode & 0o170000 == 0o120000).unwrap_or(false) {
+            consts::ENTRY_KIND_SYMLINK
+        } else {
+            consts::ENTRY_KIND_FILE
+        };
+        let mtime = entry.last_modified().to_time().map(|time| time.unix_timestamp().max(0) as u64).unwrap_or(0);
+        let sha256 = if content && kind == consts::ENTRY_KIND_FILE { hash_reader(&mut entry)? } else { String::new() };
+        let size = if kind == consts::ENTRY_KIND_FILE { entry.size() } else { 0 };
+        entries.insert(path, SnapshotEntry { kind: kind.to_string(), size, mtime, mode: mode.map(|mode| mode & 0o7777), sha256 });
+    }
+    Ok(entries)
+}
+
+// a file that changed while archived is in the archive once per read, the last copy wins
+fn tar_entries(path: &Path, compress_type: &CompressType, content: bool) -> Result<BTreeMap<String, SnapshotEntry>> {
+    let mut archive = tar::Archive::new(file::tar_decoder(File::open(path)?, compress_type)?);
+    let mut entries = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = match file::archive_entry_path(entry.path()?.to_string_lossy().as_ref()) {
+            Some(path) => path,
+            None => continue,
+        };
+        let entry_type = entry.header().entry_type();
+        let kind = if entry_type.is_dir() {
+            consts::ENTRY_KIND_DIR
+        } else if entry_type.is_symlink() {
+            consts::ENTRY_KIND_SYMLINK
+        } else {
+            consts::ENTRY_KIND_FILE
+        };
+        let size = if kind == consts::ENTRY_KIND_FILE { entry.header().size().unwrap_or(0) } else { 0 };
+        let mtime = entry.header().mtime().unwrap_or(0);
+        let mode = entry.header().mode().ok().map(|mode| mode & 0o7777);
+        let sha256 = if content && kind == consts::ENTRY_KIND_FILE { hash_reader(&mut entry)? } else { String::new() };
+        entries.insert(path, SnapshotEntry { kind: kind.to_string(), size, mtime, mode, sha256 });
+    }
+    Ok(entries)
+}
+
+/// Compare two snapshots. Entries that differ in kind, size or content are modified; entries
+/// that only differ in mtime or mode have their metadata changed. Content is compared where both
+/// snapshots have the hash of an entry.
+pub fn diff(from: &Snapshot, to: &Snapshot) -> SnapshotDiff {
+    let mut diff = SnapshotDiff { from: from.name.clone(), to: to.name.clone(), ..Default::default() };
+    let hashed = |snapshot: &Snapshot| snapshot.entries.values().any(|entry| !entry.sha256.is_empty());
+    diff.content_compared = hashed(from) && hashed(to);
+    if (hashed(from) || hashed(to)) && !diff.content_compared {
+        warn!("only one of the snapshots has content hashes, entries are compared by size and metadata");
+    }
+    for (path, old) in from.entries.iter() {
+        let new = match to.entries.get(path) {
+            Some(new) => new,
+            None => {
+                diff.size_delta -= old.size as i64;
+                diff.removed.push(diff_entry(path, old, -(old.size as i64), vec![]));
+                continue;
+            }
+        };
+        let mut changes = vec![];
+        if old.kind != new.kind {
+            changes.push("kind");
+        }
+        if old.size != new.size {
+            changes.push("size");
+        } else if !old.sha256.is_empty() && !new.sha256.is_empty() && old.sha256 != new.sha256 {
+            changes.push("content");
+        }
+        let modified = !changes.is_empty();
+        if old.mtime != new.mtime {
+            changes.push("mtime");
+        }
+        if old.mode.is_some() && new.mode.is_some() && old.mode != new.mode {
+            changes.push("mode");
+        }
+        let size_delta = new.size as i64 - old.size as i64;
+        diff.size_delta += size_delta;
+        let changes = changes.into_iter().map(String::from).collect::<Vec<String>>();
+        if modified {
+            diff.modified.push(diff_entry(path, new, size_delta, changes));
+        } else if !changes.is_empty() {
+            diff.metadata_changed.push(diff_entry(path, new, 0, changes));
+        }
+    }
+    for (path, new) in to.entries.iter() {
+        if !from.entries.contains_key(path) {
+            diff.size_delta += new.size as i64;
+            diff.added.push(diff_entry(path, new, new.size as i64, vec![]));
+        }
+    }
+    diff
+}
+
+fn diff_entry(path: &str, entry: &SnapshotEntry, size_delta: i64, changes: Vec<String>) -> DiffEntry {
+    DiffEntry { path: path.to_string(), kind: entry.kind.clone(), size: entry.size, size_delta, changes }
+}
+
+impl SnapshotDiff {
+    pub fn print(&self) {
+        println!("--- {}", self.from);
+        println!("+++ {}", self.to);
+        for entry in self.added.iter() {
+            println!("A {} ({})", entry.path, signed(entry.size_delta));
+        }
+        for entry in self.removed.iter() {
+            println!("D {} ({})", entry.path, signed(entry.size_delta));
+        }
+        for entry in self.modified.iter() {
+            println!("M {} ({}, {})", entry.path, signed(entry.size_delta), entry.changes.join(", "));
+        }
+        for entry in self.metadata_changed.iter() {
+            println!("m {} ({})", entry.path, entry.changes.join(", "));
+        }
+        let added = self.added.iter().map(|entry| entry.size_delta).sum::<i64>();
+        let removed = self.removed.iter().map(|entry| entry.size_delta).sum::<i64>();
+        let modified = self.modified.iter().map(|entry| entry.size_delta).sum::<i64>();
+        println!("{} added ({} bytes), {} removed ({} bytes), {} modified ({} bytes), {} metadata changed, {} bytes in total",
+                 self.added.len(), signed(added), self.removed.len(), signed(removed), self.modified.len(), signed(modified),
+                 self.metadata_changed.len(), signed(self.size_delta));
+        if !self.content_compared {
+            println!("content wasn't compared, files of the same size are only told apart by their metadata");
+        }
+    }
+}
+
+fn signed(n: i64) -> String {
+    if n > 0 { format!("+{}", n) } else { n.to_string() }
+}
@@ -0,0 +1 @@
+pub mod diff;
\ No newline at end of file
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::catalog::catalog::{Catalog, CatalogRun};
+use crate::config::config::BackerConfig;
+use crate::consts;
+use crate::index::index;
+use crate::index::index::TarIndex;
+use crate::manifest::manifest;
+use crate::manifest::manifest::Manifest;
+use crate::storage::storage;
+use crate::storage::storage::StoredArchive;
+use crate::utils::file;
+use crate::utils::staging::WorkDir;
+
+pub struct FindOptions {
+    pub pattern: glob::Pattern,
+    // targets whose archives missing from the catalog are searched through their sidecar files
+    pub targets: Vec<String>,
+}
+
+impl FindOptions {
+    pub fn new(pattern: glob::Pattern, targets: Vec<String>) -> Self {
+        Self { pattern, targets }
+    }
+}
+
+/// A version of an entry matching the pattern, in a run of the catalog or in an archive stored
+/// on a target.
+#[derive(Debug, Clone, Serialize)]
+pub struct FoundEntry {
+    // `catalog`, or the target whose sidecar file listed the entry
+    pub source: String,
+    // id of the run, 0 for archives found on targets
+    pub run: i64,
+    // empty for runs of the repository format
+    pub archive: String,
+    // local time of the backup
+    pub date: String,
+    // below archive/
+    pub path: String,
+    // empty when the source doesn't tell
+    pub kind: String,
+    pub size: u64,
+    // unix seconds, 0 when the source doesn't record it
+    pub mtime: u64,
+}
+
+/// Whether the entry `path`, below archive/, matches: a pattern with a slash matches the whole
+/// path, any other the file name.
+pub fn matches(pattern: &glob::Pattern, path: &str) -> bool {
+    if pattern.as_str().contains('/') {
+        return pattern.matches(path);
+    }
+    Path::new(path).file_name().map(|name| pattern.matches(name.to_string_lossy().as_ref())).unwrap_or(false)
+}
+
+// an SQLite GLOB matching at least the catalog paths (archive/...) that `matches` accepts. GLOB
+// shares the wildcards of the pattern, except that negated classes are written [^...] instead of
+// [!...] and its * already spans dirs, also none for **/.
+fn catalog_glob(pattern: &glob::Pattern) -> String {
+    let pattern = pattern.as_str().replace("[!", "[^").replace("**/", "*");
+    if pattern.contains('/') {
+        format!("archive/{}*", pattern)
+    } else {
+        format!("*/{}*", pattern)
+    }
+}
+
+/// Search the file lists of every backup for entries matching the pattern: the successful runs
+/// of the catalog first, then the archives on the targets that aren't in the catalog, through
+/// their tar index or else their manifest. Archives with neither can't be searched. Versions are
+/// sorted by the time of their backup.
+pub fn find(cfg: &BackerConfig, options: &FindOptions) -> Result<Vec<FoundEntry>> {
+    let mut found = vec![];
+    // archives already searched by target, by their name without the .enc suffix
+    let mut searched: HashSet<(String, String)> = HashSet::new();
+    let mut sources = 0;
+    if cfg.catalog.enabled && file::is_exist(cfg.catalog.path.as_str()) {
+        let catalog = Catalog::open(cfg.catalog.path.as_str())?;
+        let runs = catalog.runs(0)?.into_iter()
+            .filter(|run| run.status == consts::JOB_STATUS_SUCCESS)
+            .map(|run| (run.id, run))
+            .collect::<HashMap<i64, CatalogRun>>();
+        for run in runs.values() {
+            let archive = match storage::archive_of(cfg.archive_prefix.as_str(), run.archive.as_str()) {
+                Some(archive) => archive,
+                None => continue,
+            };
+            // only the targets the run reached have the archive
+            for target in catalog.targets(run.id)? {
+                if target.status == consts::JOB_STATUS_SUCCESS {
+                    searched.insert((target.target, archive.clone()));
+                }
+            }
+        }
+        let files = catalog.find_files(catalog_glob(&options.pattern).as_str(),
+            |path| file::archive_entry_path(path).map(|path| matches(&options.pattern, path.as_str())).unwrap_or(false))?;
+        for (id, entry) in files {
+            let (run, path) = match (runs.get(&id), file::archive_entry_path(entry.path.as_str())) {
+                (Some(run), Some(path)) => (run, path),
+                _ => continue,
+            };
+            found.push(FoundEntry {
+                source: "catalog".to_string(),
+                run: id,
+                archive: run.archive.clone(),
+                date: chrono::DateTime::parse_from_rfc3339(run.started.as_str())
+                    .map(|time| time.format("%F %T").to_string())
+                    .unwrap_or_else(|_| run.started.clone()),
+                path,
+                kind: entry.kind,
+                size: entry.size,
+                mtime: entry.mtime,
+            });
+        }
+        sources += 1;
+    }
+    let work_dir = WorkDir::create(cfg.staging_dir.as_str())?;
+    for target in options.targets.iter() {
+        let archives = match storage::list_archives(cfg, target.as_str()) {
+            Ok(archives) => archives,
+            Err(e) => {
+                warn!("list archives of [{}] failed: {}", target, e);
+                continue;
+            }
+        };
+        sources += 1;
+        for archive in archives.iter() {
+            let key = (target.clone(), archive.name.clone());
+            if searched.contains(&key) {
+                continue;
+            }
+            match search_archive(cfg, archive, &options.pattern, work_dir.dir()) {
+                Ok(Some(entries)) => {
+                    found.extend(entries);
+                    searched.insert(key);
+                }
+                Ok(None) => info!("[{}] on [{}] has no index or manifest, it isn't searched", archive.name, target),
+                Err(e) => warn!("search [{}] on [{}] failed: {}", archive.name, target, e),
+            }
+        }
+    }
+    if sources == 0 {
+        return Err(anyhow!("nothing to search, no catalog at [{}] and no target could be listed", cfg.catalog.path));
+    }
+    found.sort_by(|a, b| (a.date.as_str(), a.path.as_str()).cmp(&(b.date.as_str(), b.path.as_str())));
+    Ok(found)
+}
+
+// the matching entries of a stored archive from its sidecar files, none when it has neither an
+// index nor a manifest. manifests don't record mtimes, nor the kind of entries other than files.
+fn search_archive(cfg: &BackerConfig, archive: &StoredArchive, pattern: &glob::Pattern, dir: &Path) -> Result<Option<Vec<FoundEntry>>> {
+    let found = |path: String, kind: String, size: u64, mtime: u64| FoundEntry {
+        source: archive.target.clone(),
+        run: 0,
+        archive: archive.name.clone(),
+        date: archive.date.clone(),
+        path,
+        kind,
+        size,
+        mtime,
+    };
+    let index_name = index::index_file_name(archive.name.as_str());
+    if archive.files.contains(&index_name) {
+        let path = dir.join(index_name.as_str());
+        storage::fetch_file(cfg, archive.target.as_str(), index_name.as_str(), &path)?;
+        let tar_index = TarIndex::load(&path)?;
+        let entries = tar_index.entries.into_iter()
+            .filter_map(|entry| file::archive_entry_path(entry.path.as_str()).map(|path| (path, entry)))
+            .filter(|(path, _)| matches(pattern, path.as_str()))
+            .map(|(path, entry)| found(path, entry.kind, entry.size, entry.mtime))
+            .collect::<Vec<FoundEntry>>();
+        return Ok(Some(last_copies(entries)));
+    }
+    let manifest_name = manifest::manifest_file_name(archive.name.as_str());
+    if archive.files.contains(&manifest_name) {
+        let path = dir.join(manifest_name.as_str());
+        storage::fetch_file(cfg, archive.target.as_str(), manifest_name.as_str(), &path)?;
+        let archive_manifest = Manifest::from_bytes(&file::read_file(&path).map_err(|e| anyhow!("{}", e))?)?;
+        let entries = archive_manifest.entries.into_iter()
+            .filter_map(|entry| {
+                let kind = if !entry.sha256.is_empty() {
+                    consts::ENTRY_KIND_FILE
+                } else if entry.path.ends_with('/') {
+                    consts::ENTRY_KIND_DIR
+                } else {
+                    ""
+                };
+                file::archive_entry_path(entry.path.as_str()).map(|path| (path, kind, entry.size))
+            })
+            .filter(|(path, _, _)| matches(pattern, path.as_str()))
+            .map(|(path, kind, size)| found(path, kind.to_string(), size, 0))
+            .collect::<Vec<FoundEntry>>();
+        return Ok(Some(last_copies(entries)));
+    }
+    Ok(None)
+}
+
+// a file that changed while archived is in the archive once per read, the last copy wins
+fn last_copies(entries: Vec<FoundEntry>) -> Vec<FoundEntry> {
+    let mut seen = HashSet::new();
+    let mut entries = entries.into_iter().rev().filter(|entry| seen.insert(entry.path.clone())).collect::<Vec<FoundEntry>>();
+    entries.reverse();
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SQLite decides GLOB, the way the catalog query does
+    fn sqlite_glob(path: &str, glob: &str) -> bool {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.query_row("SELECT ?1 GLOB ?2", [path, glob], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn name_and_path_patterns() {
+        let pattern = glob::Pattern::new("*.txt").unwrap();
+        assert!(matches(&pattern, "data/sub/a.txt"));
+        assert!(!matches(&pattern, "data/a.txt.bak"));
+        let pattern = glob::Pattern::new("data/*.txt").unwrap();
+        assert!(matches(&pattern, "data/a.txt"));
+        assert!(!matches(&pattern, "other/a.txt"));
+    }
+
+    #[test]
+    fn catalog_glob_keeps_every_match() {
+        let patterns = ["a.txt", "*.txt", "a?txt", "[ab].txt", "[!b].txt", "data/*.txt", "**/a.txt", "data/**/a.txt", "data/**"];
+        let paths = ["data/a.txt", "data/sub/a.txt", "a.txt", "data/b.txt", "data/sub/", "other/a.txt.bak", "data/sub/deep/a.txt"];
+        for pattern in patterns {
+            let compiled = glob::Pattern::new(pattern).unwrap();
+            let glob = catalog_glob(&compiled);
+            for path in paths {
+                let catalog_path = format!("archive/{}", path);
+                if file::archive_entry_path(catalog_path.as_str()).map(|path| matches(&compiled, path.as_str())).unwrap_or(false) {
+                    assert!(sqlite_glob(catalog_path.as_str(), glob.as_str()), "{} as {} drops {}", pattern, glob, catalog_path);
+                }
+            }
+        }
+        // the glob still narrows the rows
+        assert!(!sqlite_glob("archive/data/b.txt", catalog_glob(&glob::Pattern::new("a.txt").unwrap()).as_str()));
+    }
+}
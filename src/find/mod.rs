@@ -0,0 +1 @@
+pub mod find;
\ No newline at end of file
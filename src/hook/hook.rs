@@ -0,0 +1,160 @@
+use std::io::{BufRead, BufReader, Read};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+
+use crate::backer::summary::JobSummary;
+use crate::config::config::HookConfig;
+use crate::consts;
+use crate::utils::host;
+
+// job context passed to hooks as BACKER_* environment variables
+fn envs(kind: &str, summary: &JobSummary) -> Vec<(&'static str, String)> {
+    vec![
+        ("BACKER_HOOK", kind.to_string()),
+        ("BACKER_STATUS", summary.status.clone()),
+        // the staged archive is removed once uploaded, hooks get the name it's stored under
+        ("BACKER_ARCHIVE", Path::new(summary.archive.as_str()).file_name()
+            .map(|name| name.to_string_lossy().to_string()).unwrap_or_default()),
+        ("BACKER_TARGETS", summary.targets.join(",")),
+        ("BACKER_BACKUP_FORMAT", summary.backup_format.clone()),
+        ("BACKER_ERROR", summary.error.clone()),
+    ]
+}
+
+/// Run `hooks` in order. A failing pre-hook with `abort-on-failure` stops the remaining hooks and
+/// returns the error; every other failure is logged and the next hook runs.
+pub fn run_hooks(kind: &str, hooks: &[HookConfig], summary: &JobSummary) -> Result<()> {
+    for hook in hooks {
+        info!("run {} hook: {}", kind, hook.command);
+        match run_hook(kind, hook, summary) {
+            Ok(()) => info!("{} hook finished: {}", kind, hook.command),
+            Err(e) => {
+                if kind == consts::HOOK_PRE && hook.abort_on_failure {
+                    return Err(anyhow!("{} hook [{}] failed: {}", kind, hook.command, e));
+                }
+                error!("{} hook [{}] failed: {}", kind, hook.command, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_hook(kind: &str, hook: &HookConfig, summary: &JobSummary) -> Result<()> {
+    let mut command = host::shell(hook.command.as_str());
+    // the hook leads a process group of its own, so a timeout kills everything it started
+    #[cfg(unix)]
+    command.process_group(0);
+    let mut child = command
+        .envs(envs(kind, summary))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // drain the pipes while waiting, a chatty hook would block on a full pipe otherwise
+    let readers = [child.stdout.take().map(|out| log_output(out, false)), child.stderr.take().map(|err| log_output(err, true))];
+    let res = match wait_timeout(&mut child, hook.timeout) {
+        Ok(Some(status)) if status.success() => Ok(()),
+        Ok(Some(status)) => Err(anyhow!("exited with {}", status)),
+        Ok(None) => {
+            kill_hook(&mut child);
+            Err(anyhow!("timed out after {} seconds", hook.timeout))
+        }
+        Err(e) => {
+            kill_hook(&mut child);
+            Err(e.into())
+        }
+    };
+    // the pipes close once every process of the group is gone, only one that left the group
+    // (e.g. a daemon) can keep them open
+    let deadline = Instant::now() + Duration::from_secs(consts::HOOK_OUTPUT_GRACE_SECONDS);
+    for reader in readers.into_iter().flatten() {
+        while !reader.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        if reader.is_finished() {
+            let _ = reader.join();
+        } else {
+            warn!("output of hook [{}] is still open, a process it started outlived it", hook.command);
+        }
+    }
+    res
+}
+
+// kill the hook with every process of its group, then reap it
+fn kill_hook(child: &mut std::process::Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// wait for the child, `Ok(None)` when it's still running after `timeout` seconds (0 waits forever)
+fn wait_timeout(child: &mut std::process::Child, timeout: u64) -> std::io::Result<Option<ExitStatus>> {
+    if timeout == 0 {
+        return child.wait().map(Some);
+    }
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn log_output<R: Read + Send + 'static>(reader: R, is_stderr: bool) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines().map_while(|l| l.ok()) {
+            if is_stderr {
+                warn!("hook: {}", line);
+            } else {
+                info!("hook: {}", line);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(command: &str, timeout: u64) -> HookConfig {
+        HookConfig { command: command.to_string(), timeout, ..Default::default() }
+    }
+
+    #[test]
+    fn exit_status_is_reported() {
+        let summary = JobSummary::new(consts::BACKUP_FORMAT_ARCHIVE, &[]);
+        assert!(run_hook(consts::HOOK_POST, &hook("echo ok", 10), &summary).is_ok());
+        assert!(run_hook(consts::HOOK_POST, &hook("exit 3", 10), &summary).is_err());
+    }
+
+    #[test]
+    fn env_has_the_stored_archive_name() {
+        let mut summary = JobSummary::new(consts::BACKUP_FORMAT_ARCHIVE, &[]);
+        summary.archive = "/staging/backer-run-1/backup.tar.gz".to_string();
+        assert!(run_hook(consts::HOOK_POST, &hook("test \"$BACKER_ARCHIVE\" = backup.tar.gz", 10), &summary).is_ok());
+    }
+
+    #[test]
+    fn timeout_kills_the_process_group() {
+        let summary = JobSummary::new(consts::BACKUP_FORMAT_ARCHIVE, &[]);
+        let started = Instant::now();
+        // the background sleep holds the pipes, it has to die with the shell
+        let res = run_hook(consts::HOOK_POST, &hook("sleep 30 & sleep 30", 1), &summary);
+        assert!(res.unwrap_err().to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(1 + consts::HOOK_OUTPUT_GRACE_SECONDS), "{:?}", started.elapsed());
+    }
+}
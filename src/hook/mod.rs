@@ -0,0 +1 @@
+pub mod hook;
\ No newline at end of file
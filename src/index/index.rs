@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::consts;
+use crate::utils::file;
+use crate::utils::parallel::{Block, Codec};
+
+pub const INDEX_VERSION: u32 = 1;
+
+/// Where an entry is in the uncompressed tar stream, from its first header (long names come in
+/// headers of their own) to the end of its padded data. Kind, size and mtime let the file list
+/// be searched without the archive, indexes written before they were added don't have them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: String,
+    pub start: u64,
+    pub end: u64,
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default)]
+    pub size: u64,
+    // unix seconds
+    #[serde(default)]
+    pub mtime: u64,
+}
+
+/// Index of a tar archive, uploaded as `<archive>.index` next to an archive that isn't encrypted.
+/// The blocks of the archive decompress on their own, so an entry is read by fetching the blocks
+/// holding it instead of the whole archive. Offsets are those of the archive before it's split
+/// into volumes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TarIndex {
+    pub version: u32,
+    pub archive: String,
+    pub blocks: Vec<Block>,
+    pub entries: Vec<IndexEntry>,
+}
+
+pub fn index_file_name(archive_file_name: &str) -> String {
+    format!("{}.{}", archive_file_name, consts::TAR_INDEX_SUFFIX)
+}
+
+impl TarIndex {
+    pub fn new(blocks: Vec<Block>, entries: Vec<IndexEntry>) -> Self {
+        Self { version: INDEX_VERSION, archive: String::new(), blocks, entries }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = file::read_file(path.as_ref()).map_err(|e| anyhow!("{}", e))?;
+        let index: TarIndex = serde_json::from_slice(&bytes)?;
+        if index.version != INDEX_VERSION {
+            return Err(anyhow!("unsupported index version {}", index.version));
+        }
+        Ok(index)
+    }
+
+    /// Write `<archive>.index` to `dir`, returns its path.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<String> {
+        let path = dir.as_ref().join(index_file_name(self.archive.as_str()));
+        file::create_write_file(&path, &serde_json::to_vec(self)?).map_err(|e| anyhow!("{}", e))?;
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// The uncompressed ranges of the entries whose path `wanted` accepts, in archive order with
+    /// adjacent ranges merged.
+    pub fn spans<F: Fn(&str) -> bool>(&self, wanted: F) -> Vec<(u64, u64)> {
+        let mut spans: Vec<(u64, u64)> = vec![];
+        for entry in self.entries.iter().filter(|entry| wanted(entry.path.as_str())) {
+            match spans.last_mut() {
+                Some(last) if last.1 >= entry.start => last.1 = last.1.max(entry.end),
+                _ => spans.push((entry.start, entry.end)),
+            }
+        }
+        spans
+    }
+}
+
+/// Reads the uncompressed `spans` of a tar archive from `archive`, which only has to serve the
+/// blocks holding them. The spans start at entry headers, so what's read is a tar stream of their
+/// entries.
+pub struct SpanReader<R: Read + Seek> {
+    archive: R,
+    codec: Codec,
+    blocks: Vec<Block>,
+    spans: VecDeque<(u64, u64)>,
+    // the last decompressed block, spans of small entries often share one
+    current: Option<(usize, Vec<u8>)>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read + Seek> SpanReader<R> {
+    pub fn new(archive: R, codec: Codec, blocks: Vec<Block>, spans: Vec<(u64, u64)>) -> Self {
+        Self { archive, codec, blocks, spans: spans.into(), current: None, buffer: vec![], pos: 0 }
+    }
+
+    // the data of the next block of the spans, empty once they're all read
+    fn next_buffer(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.pos = 0;
+        while let Some((start, end)) = self.spans.pop_front() {
+            if start >= end {
+                continue;
+            }
+            let i = self.blocks.partition_point(|block| block.raw_offset + block.raw_size <= start);
+            let block = self.blocks.get(i).cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "index points past the end of the archive"))?;
+            if self.current.as_ref().map(|(current, _)| *current != i).unwrap_or(true) {
+                let mut compressed = vec![0u8; block.size as usize];
+                self.archive.seek(SeekFrom::Start(block.offset))?;
+                self.archive.read_exact(&mut compressed)?;
+                self.current = Some((i, decompress_block(self.codec, &compressed)?));
+            }
+            let data = &self.current.as_ref().unwrap().1;
+            let block_end = block.raw_offset + block.raw_size;
+            let from = (start - block.raw_offset) as usize;
+            let to = (end.min(block_end) - block.raw_offset) as usize;
+            if to > data.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "block is shorter than the index says"));
+            }
+            self.buffer.extend_from_slice(&data[from..to]);
+            if end > block_end {
+                self.spans.push_front((block_end, end));
+            }
+            return Ok(());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SpanReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buffer.len() {
+            self.next_buffer()?;
+        }
+        let n = buf.len().min(self.buffer.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn decompress_block(codec: Codec, compressed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    match codec {
+        Codec::Gzip => {
+            GzDecoder::new(compressed).read_to_end(&mut data)?;
+        }
+        Codec::Zstd => {
+            data = zstd::stream::decode_all(compressed)?;
+        }
+    }
+    Ok(data)
+}
@@ -1,22 +1,16 @@
-use log::{info, LevelFilter};
+use log::LevelFilter;
 use time::UtcOffset;
 
-use crate::utils::file;
-
 pub fn init() {
-    init_log();
-    init_archive_dir();
+    init_with_level(LevelFilter::Info);
 }
 
-fn init_log() {
-    let utc = UtcOffset::current_local_offset().unwrap();
-    simple_logger::SimpleLogger::new().with_level(LevelFilter::Info).with_utc_offset(utc).init().unwrap()
+// commands printing their own output only log what went wrong
+pub fn init_with_level(level: LevelFilter) {
+    init_log(level);
 }
 
-fn init_archive_dir() {
-    let archive_dir = file::get_archive_dir_path();
-    if !file::is_exist(archive_dir.as_path()) {
-        info!("init archive dir: {}", archive_dir.to_str().unwrap());
-        let _ = file::create_dir(archive_dir.as_path());
-    }
-}
\ No newline at end of file
+fn init_log(level: LevelFilter) {
+    let utc = UtcOffset::current_local_offset().unwrap();
+    simple_logger::SimpleLogger::new().with_level(level).with_utc_offset(utc).init().unwrap()
+}
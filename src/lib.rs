@@ -1,3 +1,5 @@
+#![allow(clippy::module_inception)]
+
 pub mod version;
 pub mod config;
 pub mod errors;
@@ -5,4 +7,17 @@ pub mod consts;
 pub mod backer;
 pub mod packet;
 pub mod utils;
-pub mod init;
\ No newline at end of file
+pub mod init;
+pub mod repository;
+pub mod crypto;
+pub mod manifest;
+pub mod volume;
+pub mod hook;
+pub mod source;
+pub mod storage;
+pub mod restore;
+pub mod catalog;
+pub mod index;
+pub mod verify;
+pub mod diff;
+pub mod find;
\ No newline at end of file
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::consts;
+use crate::utils::{file, host};
+use crate::utils::file::CompressType;
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+pub const SIGNING_KEY_PREFIX: &str = "backer-signing-key:";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha256: String,
+}
+
+/// Integrity manifest of an uploaded archive: the hash of the archive as stored on the targets,
+/// and the hash of every entry inside it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub hostname: String,
+    pub created: String,
+    pub archive: ManifestFile,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new<P: AsRef<Path>>(archive_path: P, entries: Vec<ManifestEntry>) -> Result<Self> {
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            hostname: host::hostname(),
+            created: chrono::Local::now().to_rfc3339(),
+            archive: ManifestFile {
+                name: file::get_file_name(archive_path.as_ref()).map_err(|e| anyhow!("{}", e))?,
+                size: file::file_size(archive_path.as_ref())?,
+                sha256: file::sha256_file(archive_path.as_ref())?,
+            },
+            entries,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+pub fn manifest_file_name(archive_file_name: &str) -> String {
+    format!("{}.{}", archive_file_name, consts::MANIFEST_SUFFIX)
+}
+
+pub fn signature_file_name(archive_file_name: &str) -> String {
+    format!("{}.{}", archive_file_name, consts::SIGNATURE_SUFFIX)
+}
+
+/// Hash every entry of a zip, tar.gz or tar.zst archive.
+pub fn archive_entries<P: AsRef<Path>>(archive_path: P, compress_type: &CompressType) -> Result<Vec<ManifestEntry>> {
+    let mut entries = vec![];
+    match compress_type {
+        CompressType::Zip => {
+            let mut zip = zip::ZipArchive::new(File::open(archive_path)?)?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i)?;
+                let mut manifest_entry = ManifestEntry { path: entry.name().to_string(), size: entry.size(), sha256: String::new() };
+                if entry.is_file() {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut entry, &mut hasher)?;
+                    manifest_entry.sha256 = format!("{:x}", hasher.finalize());
+                }
+                entries.push(manifest_entry);
+            }
+        }
+        CompressType::Tar | CompressType::TarZstd => {
+            let mut tar = tar::Archive::new(file::tar_decoder(File::open(archive_path)?, compress_type)?);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let mut manifest_entry = ManifestEntry {
+                    path: entry.path()?.to_string_lossy().to_string(),
+                    size: entry.size(),
+                    sha256: String::new(),
+                };
+                let entry_type = entry.header().entry_type();
+                if entry_type.is_file() || entry_type.is_gnu_sparse() {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut entry, &mut hasher)?;
+                    manifest_entry.sha256 = format!("{:x}", hasher.finalize());
+                }
+                entries.push(manifest_entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Write `<archive>.manifest` next to the archive, and `<archive>.sig` when a signing key is
+/// configured. Returns the paths of the written files.
+pub fn write_manifest<P: AsRef<Path>>(manifest: &Manifest, dir: P, signing_key_file: &str) -> Result<Vec<String>> {
+    // an unsigned manifest can't be trusted by verify, don't upload one
+    if signing_key_file.is_empty() {
+        return Err(anyhow!("manifests need a signing key file"));
+    }
+    let signing_key = load_signing_key(signing_key_file)?;
+    let bytes = manifest.to_bytes()?;
+    let manifest_path = dir.as_ref().join(manifest_file_name(manifest.archive.name.as_str()));
+    file::create_write_file(&manifest_path, &bytes).map_err(|e| anyhow!("{}", e))?;
+    let signature = signing_key.sign(&bytes);
+    let signature_path = dir.as_ref().join(signature_file_name(manifest.archive.name.as_str()));
+    file::create_write_file(&signature_path, format!("{}\n", hex::encode(signature.to_bytes())).as_bytes()).map_err(|e| anyhow!("{}", e))?;
+    Ok(vec![manifest_path.to_string_lossy().to_string(), signature_path.to_string_lossy().to_string()])
+}
+
+/// Generate a signing key pair. Returns the secret key (for `manifest.signing-key-file`) and the
+/// public key used to verify manifests.
+pub fn generate_signing_key() -> (String, String) {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    (format!("{}{}", SIGNING_KEY_PREFIX, hex::encode(signing_key.to_bytes())), hex::encode(signing_key.verifying_key().to_bytes()))
+}
+
+pub fn load_signing_key<P: AsRef<Path>>(path: P) -> Result<SigningKey> {
+    let contents = String::from_utf8(file::read_file(path.as_ref()).map_err(|e| anyhow!("{}", e))?)?;
+    let secret = contents.trim().strip_prefix(SIGNING_KEY_PREFIX)
+        .ok_or_else(|| anyhow!("[{}] is not a signing key", path.as_ref().to_string_lossy()))?;
+    Ok(SigningKey::from_bytes(&decode_array(secret)?))
+}
+
+/// Check the detached signature of a manifest with the host's public key.
+pub fn verify_manifest(manifest: &[u8], signature: &[u8], public_key: &str) -> Result<Manifest> {
+    let verifying_key = VerifyingKey::from_bytes(&decode_array(public_key)?)?;
+    let signature = Signature::from_bytes(&decode_array(String::from_utf8(signature.to_vec())?.as_str())?);
+    verifying_key.verify(manifest, &signature).map_err(|_| anyhow!("manifest signature is invalid"))?;
+    Manifest::from_bytes(manifest)
+}
+
+/// Verify a manifest/signature pair and check that `archive_path` is exactly the archive the
+/// manifest describes.
+pub fn verify_archive<A: AsRef<Path>, M: AsRef<Path>, S: AsRef<Path>>(archive_path: A, manifest_path: M, signature_path: S, public_key: &str) -> Result<Manifest> {
+    let manifest_bytes = file::read_file(manifest_path.as_ref()).map_err(|e| anyhow!("{}", e))?;
+    let signature = file::read_file(signature_path.as_ref()).map_err(|e| anyhow!("{}", e))?;
+    let manifest = verify_manifest(&manifest_bytes, &signature, public_key)?;
+    let size = file::file_size(archive_path.as_ref())?;
+    if size != manifest.archive.size {
+        return Err(anyhow!("archive size is {}, manifest says {}", size, manifest.archive.size));
+    }
+    let sha256 = file::sha256_file(archive_path.as_ref())?;
+    if sha256 != manifest.archive.sha256 {
+        return Err(anyhow!("archive sha256 is {}, manifest says {}", sha256, manifest.archive.sha256));
+    }
+    Ok(manifest)
+}
+
+fn decode_array<const N: usize>(value: &str) -> Result<[u8; N]> {
+    let bytes = hex::decode(value.trim())?;
+    bytes.try_into().map_err(|_| anyhow!("expected {} bytes", N))
+}
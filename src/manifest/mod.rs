@@ -0,0 +1 @@
+pub mod manifest;
\ No newline at end of file
@@ -37,7 +37,7 @@ impl BaseMessage for FilesInfoMessage {
     }
 
     fn decode(&mut self, buf: &[u8]) -> Result<()> {
-        let msg = bincode::deserialize::<FilesInfoMessage>(&buf)?;
+        let msg = bincode::deserialize::<FilesInfoMessage>(buf)?;
         self.files = msg.files;
         Ok(())
     }
@@ -95,7 +95,7 @@ impl BaseMessage for FileBuffer {
     }
 
     fn decode(&mut self, buf: &[u8]) -> Result<()> {
-        let buffer = bincode::deserialize::<FileBuffer>(&buf)?;
+        let buffer = bincode::deserialize::<FileBuffer>(buf)?;
         self.is_begin = buffer.is_begin;
         self.is_end = buffer.is_end;
         self.file_name = buffer.file_name;
@@ -115,6 +115,105 @@ impl Default for FileBuffer {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFile {
+    pub name: String,
+    pub size: u64,
+    // unix seconds
+    pub modified: u64,
+    // hex sha256 of the content, empty unless the listing asked for hashes
+    pub sha256: String,
+}
+
+/// Ask for the stored files whose names start with `prefix`. Hashing reads every listed file,
+/// so it's only done when asked for.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListRequest {
+    pub prefix: String,
+    pub with_hash: bool,
+}
+
+impl ListRequest {
+    pub fn new(prefix: String, with_hash: bool) -> Self {
+        Self { prefix, with_hash }
+    }
+}
+
+impl BaseMessage for ListRequest {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let serialize: Vec<u8> = bincode::serialize(&self)?;
+        Ok(serialize)
+    }
+
+    fn decode(&mut self, buf: &[u8]) -> Result<()> {
+        let request = bincode::deserialize::<ListRequest>(buf)?;
+        self.prefix = request.prefix;
+        self.with_hash = request.with_hash;
+        Ok(())
+    }
+}
+
+/// Ask for a stored file, or for `length` bytes of it from `offset` on. Without a length the file
+/// is sent from the offset to its end.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FetchRequest {
+    pub file_name: String,
+    pub offset: u64,
+    pub length: Option<u64>,
+}
+
+impl FetchRequest {
+    pub fn new(file_name: String) -> Self {
+        Self { file_name, offset: 0, length: None }
+    }
+
+    pub fn with_range(file_name: String, offset: u64, length: u64) -> Self {
+        Self { file_name, offset, length: Some(length) }
+    }
+}
+
+impl BaseMessage for FetchRequest {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let serialize: Vec<u8> = bincode::serialize(&self)?;
+        Ok(serialize)
+    }
+
+    fn decode(&mut self, buf: &[u8]) -> Result<()> {
+        let request = bincode::deserialize::<FetchRequest>(buf)?;
+        self.file_name = request.file_name;
+        self.offset = request.offset;
+        self.length = request.length;
+        Ok(())
+    }
+}
+
+/// A page of the files stored on the server, the last page is marked as the end.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileList {
+    pub is_end: bool,
+    pub files: Vec<StoredFile>,
+}
+
+impl FileList {
+    pub fn new(files: Vec<StoredFile>, is_end: bool) -> Self {
+        Self { is_end, files }
+    }
+}
+
+impl BaseMessage for FileList {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let serialize: Vec<u8> = bincode::serialize(&self)?;
+        Ok(serialize)
+    }
+
+    fn decode(&mut self, buf: &[u8]) -> Result<()> {
+        let list = bincode::deserialize::<FileList>(buf)?;
+        self.is_end = list.is_end;
+        self.files = list.files;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
     Phrase(String),
@@ -122,6 +221,14 @@ pub enum Message {
     Authorize(bool),
     FileBuffer(FileBuffer),
     Complete(bool),
+    // ask for a stored file or a range of it, answered with its file buffers or Complete(false)
+    Fetch(FetchRequest),
+    // ask for the stored files whose names start with the prefix, answered with file lists or
+    // Complete(false)
+    List(ListRequest),
+    FileList(FileList),
+    // ask to delete a stored file, answered with Complete
+    Delete(String),
 }
 
 impl Message {
@@ -133,10 +240,10 @@ impl Message {
                 let message_len = buf.read_u16::<NetworkEndian>()?;
                 let mut bytes = vec![0u8; message_len as usize];
                 buf.read_exact(&mut bytes)?;
-                if bytes[0] == 1 {
-                    Ok(Message::Authorize(true))
-                } else if bytes[0] == 0 {
+                if bytes.first() == Some(&1) {
                     Ok(Message::Authorize(true))
+                } else if bytes.first() == Some(&0) {
+                    Ok(Message::Authorize(false))
                 } else {
                     Err(io::Error::new(
                         io::ErrorKind::InvalidData,
@@ -167,10 +274,10 @@ impl Message {
                 let message_len = buf.read_u16::<NetworkEndian>()?;
                 let mut bytes = vec![0u8; message_len as usize];
                 buf.read_exact(&mut bytes)?;
-                if bytes[0] == 1 {
-                    Ok(Message::Complete(true))
-                } else if bytes[0] == 0 {
+                if bytes.first() == Some(&1) {
                     Ok(Message::Complete(true))
+                } else if bytes.first() == Some(&0) {
+                    Ok(Message::Complete(false))
                 } else {
                     Err(io::Error::new(
                         io::ErrorKind::InvalidData,
@@ -178,6 +285,22 @@ impl Message {
                     ))
                 }
             }
+            5 => {
+                let mut request = FetchRequest::default();
+                decode_body(&mut buf, &mut request)?;
+                Ok(Message::Fetch(request))
+            }
+            6 => {
+                let mut request = ListRequest::default();
+                decode_body(&mut buf, &mut request)?;
+                Ok(Message::List(request))
+            }
+            7 => {
+                let mut list = FileList::default();
+                decode_body(&mut buf, &mut list)?;
+                Ok(Message::FileList(list))
+            }
+            8 => Ok(Message::Delete(extract_string(&mut buf)?)),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Invalid Message Type",
@@ -193,14 +316,14 @@ impl Message {
                 // Write the variable length message string, preceded by it's length
                 let message = message.as_bytes();
                 buf.write_u16::<NetworkEndian>(message.len() as u16)?;
-                buf.write_all(&message)?;
+                buf.write_all(message)?;
                 bytes_written += 2 + message.len();
             }
             Message::Auth(message) => {
                 // Write the variable length message string, preceded by it's length
                 let message = message.as_bytes();
                 buf.write_u16::<NetworkEndian>(message.len() as u16)?;
-                buf.write_all(&message)?;
+                buf.write_all(message)?;
                 bytes_written += 2 + message.len();
             }
             Message::Authorize(message) => {
@@ -223,6 +346,21 @@ impl Message {
                 buf.write_all(&bytes)?;
                 bytes_written += 2 + bytes.len();
             }
+            Message::Fetch(message) => {
+                bytes_written += encode_body(buf, message)?;
+            }
+            Message::List(message) => {
+                bytes_written += encode_body(buf, message)?;
+            }
+            Message::FileList(message) => {
+                bytes_written += encode_body(buf, message)?;
+            }
+            Message::Delete(message) => {
+                let message = message.as_bytes();
+                buf.write_u16::<NetworkEndian>(message.len() as u16)?;
+                buf.write_all(message)?;
+                bytes_written += 2 + message.len();
+            }
         }
         Ok(bytes_written)
     }
@@ -236,10 +374,35 @@ impl From<&Message> for u8 {
             Message::Authorize(_) => 2,
             Message::FileBuffer(_) => 3,
             Message::Complete(_) => 4,
+            Message::Fetch(_) => 5,
+            Message::List(_) => 6,
+            Message::FileList(_) => 7,
+            Message::Delete(_) => 8,
         }
     }
 }
 
+// read a message body encoded by `encode_body`: its length, then the message
+fn decode_body(buf: &mut impl Read, message: &mut impl BaseMessage) -> io::Result<()> {
+    let message_len = buf.read_u16::<NetworkEndian>()?;
+    let mut bytes = vec![0u8; message_len as usize];
+    buf.read_exact(&mut bytes)?;
+    message.decode(&bytes).map_err(|e| {
+        error!("decode message failed: {}", e);
+        io::Error::new(io::ErrorKind::InvalidData, "decode message failed")
+    })
+}
+
+fn encode_body(buf: &mut impl Write, message: &impl BaseMessage) -> io::Result<usize> {
+    let message_bytes = message.encode().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if message_bytes.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"));
+    }
+    buf.write_u16::<NetworkEndian>(message_bytes.len() as u16)?;
+    buf.write_all(&message_bytes)?;
+    Ok(2 + message_bytes.len())
+}
+
 fn extract_string(buf: &mut impl Read) -> io::Result<String> {
     // byte order ReadBytesExt
     let length = buf.read_u16::<NetworkEndian>()?;
@@ -253,6 +416,7 @@ fn extract_string(buf: &mut impl Read) -> io::Result<String> {
 pub struct Protocol {
     reader: io::BufReader<TcpStream>,
     stream: TcpStream,
+    authorized: bool,
 }
 
 impl Protocol {
@@ -261,9 +425,19 @@ impl Protocol {
         Ok(Self {
             reader: io::BufReader::new(stream.try_clone()?),
             stream,
+            authorized: false,
         })
     }
 
+    /// Whether the peer of this connection sent the right secret
+    pub fn is_authorized(&self) -> bool {
+        self.authorized
+    }
+
+    pub fn set_authorized(&mut self, authorized: bool) {
+        self.authorized = authorized;
+    }
+
     /// Serialize a message to the server and write it to the TcpStream
     pub fn send_message(&mut self, message: Message) -> io::Result<()> {
         message.write_message(&mut self.stream)?;
@@ -281,4 +455,92 @@ impl Protocol {
     pub fn shutdown(&self) -> io::Result<()> {
         self.stream.shutdown(Shutdown::Both)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) -> Message {
+        let mut bytes = vec![];
+        let written = message.write_message(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len());
+        let mut reader = bytes.as_slice();
+        let decoded = Message::read_message(&mut reader).unwrap();
+        assert!(reader.is_empty(), "{:?} left {} bytes unread", decoded, reader.len());
+        decoded
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        assert!(matches!(round_trip(Message::Phrase("hello".to_string())), Message::Phrase(s) if s == "hello"));
+        assert!(matches!(round_trip(Message::Auth("secret".to_string())), Message::Auth(s) if s == "secret"));
+        assert!(matches!(round_trip(Message::Auth(String::new())), Message::Auth(s) if s.is_empty()));
+    }
+
+    #[test]
+    fn bools_round_trip() {
+        assert!(matches!(round_trip(Message::Authorize(true)), Message::Authorize(true)));
+        assert!(matches!(round_trip(Message::Authorize(false)), Message::Authorize(false)));
+        assert!(matches!(round_trip(Message::Complete(true)), Message::Complete(true)));
+        assert!(matches!(round_trip(Message::Complete(false)), Message::Complete(false)));
+    }
+
+    #[test]
+    fn file_buffer_round_trips() {
+        let mut buffer = FileBuffer::new("a/b.tar.gz".to_string(), vec![1, 2, 3]);
+        buffer.is_begin = true;
+        match round_trip(Message::FileBuffer(buffer)) {
+            Message::FileBuffer(decoded) => {
+                assert!(decoded.is_begin && !decoded.is_end);
+                assert_eq!(decoded.file_name, "a/b.tar.gz");
+                assert_eq!(decoded.buffer, vec![1, 2, 3]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        match round_trip(Message::Fetch(FetchRequest::with_range("a.tar.gz".to_string(), 10, 20))) {
+            Message::Fetch(request) => {
+                assert_eq!(request.file_name, "a.tar.gz");
+                assert_eq!((request.offset, request.length), (10, Some(20)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(Message::Fetch(FetchRequest::new("b.zip".to_string()))) {
+            Message::Fetch(request) => assert_eq!((request.offset, request.length), (0, None)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(Message::List(ListRequest::new("repo/packs/".to_string(), true))) {
+            Message::List(request) => assert!(request.prefix == "repo/packs/" && request.with_hash),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(round_trip(Message::Delete("a.tar.gz".to_string())), Message::Delete(s) if s == "a.tar.gz"));
+    }
+
+    #[test]
+    fn file_list_round_trips() {
+        let file = StoredFile { name: "a.tar.gz".to_string(), size: 3, modified: 1700000000, sha256: "ab".repeat(32) };
+        match round_trip(Message::FileList(FileList::new(vec![file.clone(), file], true))) {
+            Message::FileList(list) => {
+                assert!(list.is_end);
+                assert_eq!(list.files.len(), 2);
+                assert_eq!(list.files[1].name, "a.tar.gz");
+                assert_eq!((list.files[1].size, list.files[1].modified), (3, 1700000000));
+                assert_eq!(list.files[1].sha256, "ab".repeat(32));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(round_trip(Message::FileList(FileList::new(vec![], true))), Message::FileList(list) if list.files.is_empty()));
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        // unknown type, a bool that's neither 0 nor 1, and an empty bool body
+        for bytes in [vec![99u8], vec![2, 0, 1, 7], vec![4, 0, 0]] {
+            assert!(Message::read_message(&mut bytes.as_slice()).is_err(), "{:?}", bytes);
+        }
+    }
 }
\ No newline at end of file
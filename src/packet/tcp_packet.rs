@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::io;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
@@ -229,6 +229,15 @@ impl TcpClient {
         });
     }
 
+    /// Close the connection and let the runtime go without waiting for it, which also works from
+    /// async code, where dropping the client would panic.
+    pub fn close(self) {
+        if let Some(stream) = &self.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.rt.shutdown_background();
+    }
+
     pub fn send_one(addr: SocketAddr, message: Message) {
         let stream = TcpStream::connect(addr);
         match stream {
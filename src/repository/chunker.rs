@@ -0,0 +1,106 @@
+use std::io;
+use std::io::Read;
+
+// gear hash table, generated with splitmix64 so that chunk boundaries are stable across builds
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0x6261_636b_6572_2d63;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Content defined chunker (FastCDC style). Splits a stream at positions chosen by a rolling gear
+/// hash, so that an insertion in a file only changes the chunks around it.
+pub struct Chunker<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    // harder mask used before the average size is reached, easier one after it
+    mask_s: u64,
+    mask_l: u64,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R, min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        let bits = avg_size.next_power_of_two().trailing_zeros();
+        Self {
+            reader,
+            buffer: Vec::with_capacity(max_size),
+            eof: false,
+            min_size,
+            avg_size,
+            max_size,
+            mask_s: !0u64 << (64 - (bits + 1).min(63)),
+            mask_l: !0u64 << (64 - bits.saturating_sub(1).max(1)),
+        }
+    }
+
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 64 * 1024];
+        while !self.eof && self.buffer.len() < self.max_size {
+            let want = buf.len().min(self.max_size - self.buffer.len());
+            let n = self.reader.read(&mut buf[..want])?;
+            if n == 0 {
+                self.eof = true;
+            } else {
+                self.buffer.extend_from_slice(&buf[..n]);
+            }
+        }
+        Ok(())
+    }
+
+    fn cut_point(&self, data: &[u8]) -> usize {
+        let len = data.len();
+        if len <= self.min_size {
+            return len;
+        }
+        let max = len.min(self.max_size);
+        let normal = self.avg_size.min(max);
+        let mut hash: u64 = 0;
+        let mut i = self.min_size;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_s == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < max {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_l == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        max
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill_buffer() {
+            return Some(Err(e));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let cut = self.cut_point(&self.buffer);
+        let chunk: Vec<u8> = self.buffer.drain(..cut).collect();
+        Some(Ok(chunk))
+    }
+}
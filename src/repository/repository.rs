@@ -0,0 +1,423 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Result};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::config::RepositoryConfig;
+use crate::consts;
+use crate::repository::chunker::Chunker;
+use crate::utils::{file, host};
+use crate::utils::file::BackupPath;
+
+pub const REPOSITORY_VERSION: u32 = 1;
+
+const CONFIG_FILE: &str = "config";
+pub const INDEX_DIR: &str = "index";
+pub const PACKS_DIR: &str = "packs";
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepositoryInfo {
+    pub version: u32,
+    pub min_chunk_size: usize,
+    pub avg_chunk_size: usize,
+    pub max_chunk_size: usize,
+}
+
+/// Location of one chunk inside a pack file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blob {
+    pub id: String,
+    pub offset: u64,
+    pub length: u64,
+    pub raw_length: u64,
+}
+
+/// Index of one pack file, stored under `index/<pack id>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackIndex {
+    pub pack: String,
+    pub blobs: Vec<Blob>,
+}
+
+impl PackIndex {
+    /// Size of the pack file, its blobs are written back to back.
+    pub fn pack_size(&self) -> u64 {
+        self.blobs.iter().map(|blob| blob.offset + blob.length).max().unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// One entry of a snapshot tree. Files reference their content by chunk id, in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
+    pub name: String,
+    pub kind: NodeKind,
+    pub size: u64,
+    pub mtime: i64,
+    pub mode: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub time: String,
+    pub hostname: String,
+    pub paths: Vec<String>,
+    pub tree: Vec<Node>,
+}
+
+/// Content addressed, deduplicated backup repository.
+///
+/// ```text
+/// <root>/config                 repository parameters
+/// <root>/packs/<xx>/<pack id>   concatenated zlib compressed chunks
+/// <root>/index/<pack id>        chunk locations of one pack
+/// <root>/snapshots/<id>         snapshot tree referencing chunks
+/// ```
+///
+/// New files are written below `root`. `known` holds the chunks that already exist in the
+/// destination repository, which may live elsewhere (e.g. on backer-server), so only new chunks
+/// end up in the written packs.
+pub struct Repository {
+    root: PathBuf,
+    cfg: RepositoryConfig,
+    known: HashSet<String>,
+    pack_buffer: Vec<u8>,
+    pack_blobs: Vec<Blob>,
+    written_files: Vec<String>,
+}
+
+impl Repository {
+    pub fn new<P: AsRef<Path>>(root: P, cfg: RepositoryConfig, known: HashSet<String>) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        file::create_dir(&root)?;
+        let mut repository = Self {
+            root,
+            cfg,
+            known,
+            pack_buffer: vec![],
+            pack_blobs: vec![],
+            written_files: vec![],
+        };
+        repository.write_info()?;
+        Ok(repository)
+    }
+
+    /// Read the ids of every chunk stored in the repository at `root`.
+    pub fn load_known_chunks<P: AsRef<Path>>(root: P) -> Result<HashSet<String>> {
+        let mut known = HashSet::new();
+        for index in Self::load_indexes(root)? {
+            for blob in index.blobs {
+                known.insert(blob.id);
+            }
+        }
+        Ok(known)
+    }
+
+    pub fn load_indexes<P: AsRef<Path>>(root: P) -> Result<Vec<PackIndex>> {
+        let mut indexes = vec![];
+        let index_dir = root.as_ref().join(INDEX_DIR);
+        if !file::is_dir(&index_dir) {
+            return Ok(indexes);
+        }
+        for entry in fs::read_dir(index_dir)? {
+            let bytes = file::read_file(entry?.path()).map_err(|e| anyhow!("{}", e))?;
+            indexes.push(serde_json::from_slice(&bytes)?);
+        }
+        Ok(indexes)
+    }
+
+    /// Read the index of pack `pack` in the repository at `root`.
+    pub fn load_index<P: AsRef<Path>>(root: P, pack: &str) -> Result<PackIndex> {
+        let bytes = file::read_file(root.as_ref().join(INDEX_DIR).join(pack)).map_err(|e| anyhow!("{}", e))?;
+        let index: PackIndex = serde_json::from_slice(&bytes)?;
+        if index.pack != pack {
+            return Err(anyhow!("index [{}] is for pack [{}]", pack, index.pack));
+        }
+        Ok(index)
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(root: P, id: &str) -> Result<Snapshot> {
+        let bytes = file::read_file(root.as_ref().join(SNAPSHOTS_DIR).join(id)).map_err(|e| anyhow!("{}", e))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Read and decompress one chunk located by `blob` in pack `pack`.
+    pub fn read_chunk<P: AsRef<Path>>(root: P, pack: &str, blob: &Blob) -> Result<Vec<u8>> {
+        let mut f = File::open(pack_path(root.as_ref(), pack))?;
+        let mut compressed = vec![0u8; blob.length as usize];
+        std::io::Seek::seek(&mut f, std::io::SeekFrom::Start(blob.offset))?;
+        f.read_exact(&mut compressed)?;
+        let mut data = Vec::with_capacity(blob.raw_length as usize);
+        ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut data)?;
+        let id = format!("{:x}", Sha256::digest(&data));
+        if id != blob.id {
+            return Err(anyhow!("chunk [{}] is corrupted", blob.id));
+        }
+        Ok(data)
+    }
+
+    /// Store `paths` and write a new snapshot. Returns the snapshot id. A path, or an entry below
+    /// it, that vanishes or can't be read is skipped or fails the backup by the `missing` policy
+    /// of its backup path, as in the archive formats.
+    pub fn backup(&mut self, paths: &[BackupPath]) -> Result<String> {
+        let mut tree = vec![];
+        let mut problems = vec![];
+        for backup_path in paths {
+            let mut report = |problem: String| match backup_path.missing.as_str() {
+                consts::MISSING_PATH_IGNORE => {}
+                consts::MISSING_PATH_WARN => warn!("skip [{}]", problem),
+                _ => problems.push(problem),
+            };
+            if let Some(node) = self.store_path(Path::new(backup_path.path.as_str()), &mut report)? {
+                tree.push(node);
+            }
+        }
+        if !problems.is_empty() {
+            return Err(anyhow!("backup paths missing or unreadable: {}", problems.join("; ")));
+        }
+        self.flush_pack()?;
+        let snapshot = Snapshot {
+            time: chrono::Local::now().to_rfc3339(),
+            hostname: host::hostname(),
+            paths: paths.iter().map(|p| p.path.clone()).collect(),
+            tree,
+        };
+        let bytes = serde_json::to_vec(&snapshot)?;
+        let id = format!("{:x}", Sha256::digest(&bytes));
+        self.write_file(&format!("{}/{}", SNAPSHOTS_DIR, id), &bytes)?;
+        Ok(id)
+    }
+
+    /// Files written by this repository, relative to its root.
+    pub fn written_files(&self) -> &[String] {
+        &self.written_files
+    }
+
+    pub fn known_chunks(&self) -> &HashSet<String> {
+        &self.known
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn write_info(&mut self) -> Result<()> {
+        if file::is_exist(self.root.join(CONFIG_FILE)) {
+            return Ok(());
+        }
+        let info = RepositoryInfo {
+            version: REPOSITORY_VERSION,
+            min_chunk_size: self.cfg.min_chunk_size,
+            avg_chunk_size: self.cfg.avg_chunk_size,
+            max_chunk_size: self.cfg.max_chunk_size,
+        };
+        let bytes = serde_json::to_vec_pretty(&info)?;
+        self.write_file(CONFIG_FILE, &bytes)
+    }
+
+    // none when the entry can't be read, after reporting it
+    fn store_path(&mut self, path: &Path, report: &mut dyn FnMut(String)) -> Result<Option<Node>> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                report(format!("{}: {}", path.display(), e));
+                return Ok(None);
+            }
+        };
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => path.to_string_lossy().to_string(),
+        };
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let mut node = Node {
+            name,
+            kind: NodeKind::File,
+            size: 0,
+            mtime,
+            mode: metadata.permissions().mode(),
+            link_target: None,
+            chunks: vec![],
+            children: vec![],
+        };
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            node.kind = NodeKind::Symlink;
+            match fs::read_link(path) {
+                Ok(target) => node.link_target = Some(target.to_string_lossy().to_string()),
+                Err(e) => {
+                    report(format!("{}: {}", path.display(), e));
+                    return Ok(None);
+                }
+            }
+        } else if file_type.is_dir() {
+            node.kind = NodeKind::Dir;
+            // a dir that can't be listed is kept without its entries
+            let mut entries = vec![];
+            match fs::read_dir(path) {
+                Ok(dir) => {
+                    for entry in dir {
+                        match entry {
+                            Ok(entry) => entries.push(entry.path()),
+                            Err(e) => report(format!("{}: {}", path.display(), e)),
+                        }
+                    }
+                }
+                Err(e) => report(format!("{}: {}", path.display(), e)),
+            }
+            entries.sort();
+            for entry in entries {
+                if let Some(child) = self.store_path(&entry, report)? {
+                    node.children.push(child);
+                }
+            }
+        } else if file_type.is_file() {
+            node.size = metadata.len();
+            let f = match File::open(path) {
+                Ok(f) => f,
+                Err(e) => {
+                    report(format!("{}: {}", path.display(), e));
+                    return Ok(None);
+                }
+            };
+            let chunker = Chunker::new(f, self.cfg.min_chunk_size, self.cfg.avg_chunk_size, self.cfg.max_chunk_size);
+            for chunk in chunker {
+                let chunk = chunk?;
+                node.chunks.push(self.store_chunk(&chunk)?);
+            }
+        } else {
+            warn!("skip [{}], it's neither a file, a dir nor a symlink", path.display());
+            return Ok(None);
+        }
+        Ok(Some(node))
+    }
+
+    fn store_chunk(&mut self, data: &[u8]) -> Result<String> {
+        let id = format!("{:x}", Sha256::digest(data));
+        if self.known.contains(&id) || self.pack_blobs.iter().any(|b| b.id == id) {
+            return Ok(id);
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        self.pack_blobs.push(Blob {
+            id: id.clone(),
+            offset: self.pack_buffer.len() as u64,
+            length: compressed.len() as u64,
+            raw_length: data.len() as u64,
+        });
+        self.pack_buffer.extend_from_slice(&compressed);
+        if self.pack_buffer.len() >= self.cfg.pack_size {
+            self.flush_pack()?;
+        }
+        Ok(id)
+    }
+
+    fn flush_pack(&mut self) -> Result<()> {
+        if self.pack_blobs.is_empty() {
+            return Ok(());
+        }
+        let pack = format!("{:x}", Sha256::digest(&self.pack_buffer));
+        let pack_buffer = std::mem::take(&mut self.pack_buffer);
+        let blobs = std::mem::take(&mut self.pack_blobs);
+        self.write_file(&format!("{}/{}/{}", PACKS_DIR, &pack[..2], pack), &pack_buffer)?;
+        for blob in blobs.iter() {
+            self.known.insert(blob.id.clone());
+        }
+        let index = PackIndex { pack: pack.clone(), blobs };
+        self.write_file(&format!("{}/{}", INDEX_DIR, pack), &serde_json::to_vec(&index)?)
+    }
+
+    fn write_file(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.root.join(name);
+        if let Some(parent) = path.parent() {
+            file::create_dir(parent)?;
+        }
+        file::create_write_file(&path, bytes).map_err(|e| anyhow!("{}", e))?;
+        self.written_files.push(name.to_string());
+        Ok(())
+    }
+}
+
+fn pack_path(root: &Path, pack: &str) -> PathBuf {
+    root.join(PACKS_DIR).join(&pack[..2]).join(pack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    fn backup_path(path: &Path, missing: &str) -> BackupPath {
+        BackupPath::new(path.to_string_lossy().to_string(), String::new(), missing.to_string())
+    }
+
+    #[test]
+    fn known_chunks_are_not_written_again() {
+        let dir = test_dir("repository-dedup");
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data/a.txt"), b"hello").unwrap();
+        let paths = [backup_path(&dir.join("data"), consts::MISSING_PATH_ERROR)];
+        let mut repository = Repository::new(dir.join("repo"), RepositoryConfig::default(), HashSet::new()).unwrap();
+        repository.backup(&paths).unwrap();
+        let index = repository.written_files().iter().find(|name| name.starts_with(INDEX_DIR)).unwrap().clone();
+        let pack = index.rsplit('/').next().unwrap();
+        assert_eq!(Repository::load_index(dir.join("repo"), pack).unwrap().blobs.len(), 1);
+        let known = Repository::load_known_chunks(dir.join("repo")).unwrap();
+        let mut repository = Repository::new(dir.join("next"), RepositoryConfig::default(), known).unwrap();
+        repository.backup(&paths).unwrap();
+        assert!(repository.written_files().iter().all(|name| !name.starts_with(PACKS_DIR) && !name.starts_with(INDEX_DIR)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_paths_follow_the_policy() {
+        let dir = test_dir("repository-missing");
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        let gone = dir.join("gone");
+        let mut repository = Repository::new(dir.join("repo"), RepositoryConfig::default(), HashSet::new()).unwrap();
+        let err = repository.backup(&[backup_path(&gone, consts::MISSING_PATH_ERROR)]).unwrap_err();
+        assert!(err.to_string().contains("gone"), "{}", err);
+        for policy in [consts::MISSING_PATH_WARN, consts::MISSING_PATH_IGNORE] {
+            let id = repository.backup(&[backup_path(&gone, policy), backup_path(&dir.join("a.txt"), policy)]).unwrap();
+            let snapshot = Repository::load_snapshot(dir.join("repo"), id.as_str()).unwrap();
+            assert_eq!(snapshot.tree.iter().map(|node| node.name.as_str()).collect::<Vec<&str>>(), vec!["a.txt"]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_of_another_pack_is_rejected() {
+        let dir = test_dir("repository-index");
+        fs::create_dir_all(dir.join(INDEX_DIR)).unwrap();
+        let index = PackIndex { pack: "b".repeat(64), blobs: vec![] };
+        fs::write(dir.join(INDEX_DIR).join("a".repeat(64)), serde_json::to_vec(&index).unwrap()).unwrap();
+        assert!(Repository::load_index(&dir, "a".repeat(64).as_str()).is_err());
+        assert!(Repository::load_index(&dir, "c".repeat(64).as_str()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1 @@
+pub mod restore;
\ No newline at end of file
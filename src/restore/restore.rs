@@ -0,0 +1,742 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use crate::config::config::BackerConfig;
+use crate::consts;
+use crate::crypto::crypto;
+use crate::crypto::crypto::Identity;
+use crate::index::index;
+use crate::index::index::{SpanReader, TarIndex};
+use crate::storage::range::RangeReader;
+use crate::storage::storage;
+use crate::utils::file;
+use crate::utils::file::CompressType;
+use crate::utils::parallel::Codec;
+use crate::utils::staging::WorkDir;
+use crate::volume::volume;
+
+/// What to do with an archive entry whose path already exists. Existing dirs are merged with the
+/// dirs of the archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Skip,
+    Rename,
+}
+
+impl ConflictPolicy {
+    pub fn from_policy(policy: &str) -> Self {
+        match policy {
+            consts::CONFLICT_OVERWRITE => ConflictPolicy::Overwrite,
+            consts::CONFLICT_RENAME => ConflictPolicy::Rename,
+            _ => ConflictPolicy::Skip,
+        }
+    }
+}
+
+pub struct RestoreOptions {
+    pub target: String,
+    // archive name, with or without its .enc/.volumes suffix, or `latest`
+    pub archive: String,
+    pub destination: PathBuf,
+    // entries below a prefix are restored to its dir instead of the destination
+    pub mappings: Vec<(PathBuf, PathBuf)>,
+    pub includes: Vec<glob::Pattern>,
+    pub excludes: Vec<glob::Pattern>,
+    pub conflict: ConflictPolicy,
+    pub dry_run: bool,
+    pub identities: Vec<Identity>,
+}
+
+impl RestoreOptions {
+    pub fn new(target: String, archive: String, destination: PathBuf) -> Self {
+        Self {
+            target,
+            archive,
+            destination,
+            mappings: vec![],
+            includes: vec![],
+            excludes: vec![],
+            conflict: ConflictPolicy::Skip,
+            dry_run: false,
+            identities: vec![],
+        }
+    }
+}
+
+/// Parse a `PREFIX=DIR` mapping of `backer restore --map`.
+pub fn parse_mapping(mapping: &str) -> Result<(PathBuf, PathBuf)> {
+    let (prefix, dir) = mapping.split_once('=').ok_or_else(|| anyhow!("mapping [{}] isn't PREFIX=DIR", mapping))?;
+    let prefix = prefix.trim_matches('/');
+    if !file::is_safe_relative_path(prefix) || dir.is_empty() {
+        return Err(anyhow!("mapping [{}] isn't PREFIX=DIR", mapping));
+    }
+    Ok((PathBuf::from(prefix), PathBuf::from(dir)))
+}
+
+/// The keys of the encryption config, used before the ones given on the command line.
+pub fn config_identities(cfg: &BackerConfig) -> Result<Vec<Identity>> {
+    let mut identities = vec![];
+    if !cfg.encryption.key_file.is_empty() {
+        identities.push(Identity::load(cfg.encryption.key_file.as_str())?);
+    }
+    if let Some(passphrase) = cfg.encryption.load_passphrase()? {
+        identities.push(Identity::Passphrase(passphrase));
+    }
+    Ok(identities)
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub archive: String,
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+    pub renamed: u64,
+    pub overwritten: u64,
+    pub inconsistent_files: Vec<String>,
+}
+
+impl RestoreReport {
+    pub fn print(&self, dry_run: bool) {
+        let verb = if dry_run { "would restore" } else { "restored" };
+        println!("{} {} files ({} bytes), {} dirs and {} symlinks from {}", verb, self.files, self.bytes, self.dirs, self.symlinks, self.archive);
+        if self.skipped > 0 || self.renamed > 0 || self.overwritten > 0 {
+            println!("existing paths: {} skipped, {} renamed, {} overwritten", self.skipped, self.renamed, self.overwritten);
+        }
+        if !self.inconsistent_files.is_empty() {
+            println!("files that changed while being archived, their content may be inconsistent:");
+            for name in self.inconsistent_files.iter() {
+                println!("  {}", name);
+            }
+        }
+    }
+}
+
+/// Fetch an archive from a target and extract it as configured by `options`.
+pub fn restore(cfg: &BackerConfig, options: &RestoreOptions) -> Result<RestoreReport> {
+    if cfg.backup_format == consts::BACKUP_FORMAT_REPOSITORY {
+        return Err(anyhow!("restoring the repository format is not supported, only archives can be restored"));
+    }
+    let archive = resolve_archive(cfg, options.target.as_str(), options.archive.as_str())?;
+    let compress_type = compress_type_of(archive.as_str())?;
+    let work_dir = WorkDir::create(cfg.staging_dir.as_str())?;
+    let mut extractor = Extractor::new(options);
+    extractor.report.archive = archive.clone();
+    // a few selected entries are read by range instead of fetching the whole archive
+    let extracted = !options.includes.is_empty()
+        && extract_by_range(cfg, &mut extractor, archive.as_str(), &compress_type, work_dir.dir())?;
+    if !extracted {
+        let archive_path = fetch_archive(cfg, options.target.as_str(), &options.identities, archive.as_str(), work_dir.dir())?;
+        match compress_type {
+            CompressType::Zip => extractor.extract_zip(File::open(&archive_path)?)?,
+            _ => extractor.extract_tar(file::tar_decoder(File::open(&archive_path)?, &compress_type)?)?,
+        }
+    }
+    extractor.finish();
+    Ok(extractor.report)
+}
+
+// read the selected entries of the archive by range: zip archives through their central
+// directory, tar archives through their index. false when the target can't serve ranges or the
+// archive can't be read that way (encrypted, tar without index), it's fetched whole then.
+fn extract_by_range(cfg: &BackerConfig, extractor: &mut Extractor, archive: &str, compress_type: &CompressType, dir: &Path) -> Result<bool> {
+    let target = extractor.options.target.as_str();
+    if !storage::supports_range(target) {
+        info!("[{}] doesn't serve ranges, fetch the whole archive", target);
+        return Ok(false);
+    }
+    let parts = match stored_parts(cfg, target, archive, dir) {
+        Some(parts) => parts,
+        None => {
+            info!("no plain [{}] on [{}] to read by range, fetch the whole archive", archive, target);
+            return Ok(false);
+        }
+    };
+    let mut reader = RangeReader::new(cfg, target, parts);
+    let size = reader.size();
+    match compress_type {
+        CompressType::Zip => extractor.extract_zip(&mut reader)?,
+        _ => {
+            let index_name = index::index_file_name(archive);
+            let index_path = dir.join(index_name.as_str());
+            let tar_index = match storage::fetch_file(cfg, target, index_name.as_str(), &index_path).and_then(|_| TarIndex::load(&index_path)) {
+                Ok(tar_index) => tar_index,
+                Err(e) => {
+                    info!("no index of [{}] ({}), fetch the whole archive", archive, e);
+                    return Ok(false);
+                }
+            };
+            let spans = tar_index.spans(|name| extractor.wanted(name));
+            let codec = if *compress_type == CompressType::TarZstd { Codec::Zstd } else { Codec::Gzip };
+            extractor.extract_tar(SpanReader::new(&mut reader, codec, tar_index.blocks, spans))?;
+        }
+    }
+    info!("read {} of {} bytes of [{}] by range", reader.fetched(), size, archive);
+    Ok(true)
+}
+
+// the stored files of a plain archive with their sizes, the archive itself or its volumes
+fn stored_parts(cfg: &BackerConfig, target: &str, archive: &str, dir: &Path) -> Option<Vec<(String, u64)>> {
+    if let Ok(size) = storage::file_size(cfg, target, archive) {
+        return Some(vec![(archive.to_string(), size)]);
+    }
+    let index_name = volume::volume_index_file_name(archive);
+    let index_path = dir.join(index_name.as_str());
+    storage::fetch_file(cfg, target, index_name.as_str(), &index_path).ok()?;
+    let index = volume::VolumeIndex::load(&index_path).ok()?;
+    Some(index.parts.into_iter().map(|part| (part.name, part.size)).collect())
+}
+
+/// The newest archive on `target` for `latest`, otherwise `archive` without the suffixes of its
+/// stored files.
+pub fn resolve_archive(cfg: &BackerConfig, target: &str, archive: &str) -> Result<String> {
+    if archive == consts::ARCHIVE_LATEST {
+        let prefix = format!("{}-", cfg.archive_prefix);
+        let files = storage::list_files(cfg, target, prefix.as_str())?;
+        let archive = files.iter().filter_map(|f| storage::archive_of(cfg.archive_prefix.as_str(), f.name.as_str())).max()
+            .ok_or_else(|| anyhow!("no archive found on [{}]", target))?;
+        info!("latest archive on [{}] is {}", target, archive);
+        check_archive_name(archive.as_str())?;
+        return Ok(archive);
+    }
+    let name = archive;
+    let name = name.strip_suffix(format!(".{}", consts::VOLUME_INDEX_SUFFIX).as_str()).unwrap_or(name);
+    let name = name.strip_suffix(format!(".{}", consts::ENCRYPTED_ARCHIVE_SUFFIX).as_str()).unwrap_or(name);
+    check_archive_name(name)?;
+    Ok(name.to_string())
+}
+
+// archive names come from the command line or the target and are joined to local dirs
+fn check_archive_name(name: &str) -> Result<()> {
+    if !file::is_safe_relative_path(name) || name.contains(['/', '\\']) {
+        return Err(anyhow!("invalid archive name [{}]", name));
+    }
+    Ok(())
+}
+
+/// The compress type of an archive, from its name.
+pub fn compress_type_of(archive: &str) -> Result<CompressType> {
+    for mode in [consts::COMPRESS_MODE_TAR, consts::COMPRESS_MODE_TAR_ZSTD, consts::COMPRESS_MODE_ZIP] {
+        if archive.ends_with(format!(".{}", mode).as_str()) {
+            return Ok(CompressType::from_mode(mode));
+        }
+    }
+    Err(anyhow!("can't tell the compress mode of [{}]", archive))
+}
+
+/// Fetch `archive` from `target` to `dir` and return the path of the plain archive. It's stored as
+/// is or encrypted, either of them possibly split into volumes.
+pub fn fetch_archive(cfg: &BackerConfig, target: &str, identities: &[Identity], archive: &str, dir: &Path) -> Result<PathBuf> {
+    let err = match fetch_stored(cfg, target, archive, dir) {
+        Ok(path) => return Ok(path),
+        Err(e) => e,
+    };
+    let encrypted = format!("{}.{}", archive, consts::ENCRYPTED_ARCHIVE_SUFFIX);
+    let encrypted_path = fetch_stored(cfg, target, encrypted.as_str(), dir)
+        .map_err(|_| anyhow!("fetch archive [{}] from [{}] failed: {}", archive, target, err))?;
+    if identities.is_empty() {
+        return Err(anyhow!("archive [{}] is encrypted, give a key with -k or a passphrase", archive));
+    }
+    let path = dir.join(archive);
+    crypto::decrypt_file(&encrypted_path, &path, identities)
+        .map_err(|e| anyhow!("decrypt archive failed: {}", e))?;
+    let _ = fs::remove_file(&encrypted_path);
+    info!("decrypt archive success");
+    Ok(path)
+}
+
+/// Fetch a stored file to `dir`, or its volumes joined back together. The volumes are checked
+/// against the hashes of their index.
+pub fn fetch_stored(cfg: &BackerConfig, target: &str, name: &str, dir: &Path) -> Result<PathBuf> {
+    check_archive_name(name)?;
+    let path = dir.join(name);
+    let err = match storage::fetch_file(cfg, target, name, &path) {
+        Ok(()) => return Ok(path),
+        Err(e) => e,
+    };
+    let index_name = volume::volume_index_file_name(name);
+    let index_path = dir.join(index_name.as_str());
+    if storage::fetch_file(cfg, target, index_name.as_str(), &index_path).is_err() {
+        return Err(err);
+    }
+    let index = volume::VolumeIndex::load(&index_path)?;
+    for part in index.parts.iter() {
+        storage::fetch_file(cfg, target, part.name.as_str(), dir.join(part.name.as_str()))?;
+    }
+    volume::join_volumes(&index, dir, &path)?;
+    for part in index.parts.iter() {
+        let _ = fs::remove_file(dir.join(part.name.as_str()));
+    }
+    info!("joined {} volumes of [{}]", index.parts.len(), name);
+    Ok(path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+struct Extractor<'a> {
+    options: &'a RestoreOptions,
+    report: RestoreReport,
+    // dirs that were renamed (Some) or skipped (None) because of a conflict, the entries below
+    // them follow
+    redirects: Vec<(PathBuf, Option<PathBuf>)>,
+    // dirs get their mode and mtime once their entries are written
+    dirs: Vec<(PathBuf, Option<u32>, Option<u64>)>,
+    // ownership can only be given away by root
+    restore_owner: bool,
+    // where the files of this restore were written, by the path the archive puts them at. a file
+    // that changed while the archive was built is in it more than once and the last copy wins.
+    written: HashMap<PathBuf, PathBuf>,
+}
+
+impl<'a> Extractor<'a> {
+    fn new(options: &'a RestoreOptions) -> Self {
+        Self {
+            options,
+            report: RestoreReport::default(),
+            redirects: vec![],
+            dirs: vec![],
+            restore_owner: unsafe { libc::geteuid() } == 0,
+            written: HashMap::new(),
+        }
+    }
+
+    fn extract_tar<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().to_string();
+            if self.read_inconsistent_list(name.as_str(), &mut entry)? {
+                continue;
+            }
+            let entry_type = entry.header().entry_type();
+            let kind = if entry_type.is_dir() {
+                EntryKind::Dir
+            } else if entry_type.is_symlink() {
+                EntryKind::Symlink
+            } else if entry_type.is_file() || entry_type.is_gnu_sparse() {
+                EntryKind::File
+            } else {
+                warn!("skip [{}], entries of type {:?} aren't restored", name, entry_type);
+                continue;
+            };
+            let dest = match self.plan(name.as_str(), kind)? {
+                Some(dest) => dest,
+                None => continue,
+            };
+            let size = entry.header().size().unwrap_or(0);
+            if !self.options.dry_run {
+                let mode = entry.header().mode().ok();
+                let mtime = entry.header().mtime().ok();
+                if kind == EntryKind::Dir {
+                    fs::create_dir_all(&dest)?;
+                    self.dirs.push((dest.clone(), mode, mtime));
+                } else {
+                    entry.unpack(&dest).map_err(|e| anyhow!("restore [{}] failed: {}", dest.to_string_lossy(), e))?;
+                }
+                if self.restore_owner {
+                    if let (Ok(uid), Ok(gid)) = (entry.header().uid(), entry.header().gid()) {
+                        chown(&dest, uid as u32, gid as u32);
+                    }
+                }
+            }
+            self.count(kind, size);
+        }
+        Ok(())
+    }
+
+    // zip entries only carry a mode and a time, owners aren't restored. entries are opened by
+    // name, parents before children, so the ones that aren't wanted are never read.
+    fn extract_zip<R: Read + Seek>(&mut self, reader: R) -> Result<()> {
+        let mut zip = zip::ZipArchive::new(reader)?;
+        let mut names = zip.file_names().filter(|name| self.wanted(name)).map(String::from).collect::<Vec<String>>();
+        names.sort();
+        for name in names {
+            let mut entry = zip.by_name(name.as_str())?;
+            if self.read_inconsistent_list(name.as_str(), &mut entry)? {
+                continue;
+            }
+            let kind = if entry.is_dir() {
+                EntryKind::Dir
+            } else if entry.unix_mode().map(|mode| mode & 0o170000 == 0o120000).unwrap_or(false) {
+                EntryKind::Symlink
+            } else {
+                EntryKind::File
+            };
+            let dest = match self.plan(name.as_str(), kind)? {
+                Some(dest) => dest,
+                None => continue,
+            };
+            let size = entry.size();
+            let mtime = entry.last_modified().to_time().ok().map(|time| time.unix_timestamp().max(0) as u64);
+            let mode = entry.unix_mode();
+            if !self.options.dry_run {
+                match kind {
+                    EntryKind::Dir => {
+                        fs::create_dir_all(&dest)?;
+                        self.dirs.push((dest.clone(), mode, mtime));
+                    }
+                    EntryKind::Symlink => {
+                        let mut link_target = String::new();
+                        entry.read_to_string(&mut link_target)?;
+                        std::os::unix::fs::symlink(link_target, &dest)?;
+                    }
+                    EntryKind::File => {
+                        let mut f = File::create(&dest)?;
+                        io::copy(&mut entry, &mut f)?;
+                        if let Some(mtime) = mtime {
+                            f.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+                        }
+                        if let Some(mode) = mode {
+                            f.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+                        }
+                    }
+                }
+            }
+            self.count(kind, size);
+        }
+        Ok(())
+    }
+
+    // the list of inconsistent files is reported instead of restored
+    fn read_inconsistent_list<R: Read>(&mut self, name: &str, entry: &mut R) -> Result<bool> {
+        if name != format!("archive/{}", consts::INCONSISTENT_FILES_ENTRY) {
+            return Ok(false);
+        }
+        let mut list = String::new();
+        entry.read_to_string(&mut list)?;
+        self.report.inconsistent_files.extend(list.lines().filter(|line| !line.is_empty()).map(|line| line.to_string()));
+        Ok(true)
+    }
+
+    // whether an entry has to be read, `plan` has the final say
+    fn wanted(&self, name: &str) -> bool {
+        if name == format!("archive/{}", consts::INCONSISTENT_FILES_ENTRY) {
+            return true;
+        }
+        let name = name.trim_end_matches('/');
+        let relative = name.strip_prefix("archive/").unwrap_or(name);
+        // plan warns about them
+        if !file::is_safe_relative_path(relative) {
+            return true;
+        }
+        self.selected(Path::new(relative))
+    }
+
+    // where an entry is restored to, None when it's filtered out or skipped. existing paths are
+    // handled by the conflict policy here, so the entry can be written right away.
+    fn plan(&mut self, name: &str, kind: EntryKind) -> Result<Option<PathBuf>> {
+        let name = name.trim_end_matches('/');
+        let relative = match name.strip_prefix("archive/") {
+            Some(relative) => relative,
+            // the root dir of the archive
+            None if name == "archive" => return Ok(None),
+            None => name,
+        };
+        if !file::is_safe_relative_path(relative) {
+            warn!("skip [{}], it isn't a plain relative path", name);
+            return Ok(None);
+        }
+        if !self.selected(Path::new(relative)) {
+            return Ok(None);
+        }
+        let (base, mut dest) = self.map(Path::new(relative));
+        for (from, to) in self.redirects.iter() {
+            if let Ok(rest) = dest.strip_prefix(from) {
+                match to {
+                    Some(to) => dest = to.join(rest),
+                    None => return Ok(None),
+                }
+                break;
+            }
+        }
+        // a symlink restored or found below the destination must not lead the entry elsewhere
+        if let Ok(rest) = dest.strip_prefix(&base) {
+            let mut ancestor = base.clone();
+            for component in rest.parent().map(|parent| parent.components()).into_iter().flatten() {
+                ancestor.push(component);
+                if fs::symlink_metadata(&ancestor).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+                    warn!("skip [{}], [{}] is a symlink", name, ancestor.to_string_lossy());
+                    self.report.skipped += 1;
+                    return Ok(None);
+                }
+            }
+        }
+        if kind != EntryKind::Dir {
+            // an earlier copy of the entry is replaced whatever the conflict policy
+            if let Some(written) = self.written.get(&dest).cloned() {
+                if !self.options.dry_run {
+                    fs::remove_file(&written)?;
+                }
+                return Ok(Some(written));
+            }
+        }
+        let resolved = self.resolve_conflict(dest.clone(), kind)?;
+        if let Some(resolved) = resolved.as_ref().filter(|_| kind != EntryKind::Dir) {
+            self.written.insert(dest, resolved.clone());
+        }
+        Ok(resolved)
+    }
+
+    // where an entry goes when `dest` may exist already
+    fn resolve_conflict(&mut self, dest: PathBuf, kind: EntryKind) -> Result<Option<PathBuf>> {
+        let existing = match fs::symlink_metadata(&dest) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                self.print_action("restore", &dest, None);
+                return self.prepare_parent(dest).map(Some);
+            }
+        };
+        if kind == EntryKind::Dir && existing.is_dir() {
+            return Ok(Some(dest));
+        }
+        match self.options.conflict {
+            ConflictPolicy::Skip => {
+                self.print_action("skip existing", &dest, None);
+                if kind == EntryKind::Dir {
+                    self.redirects.push((dest, None));
+                }
+                self.report.skipped += 1;
+                Ok(None)
+            }
+            ConflictPolicy::Overwrite => {
+                self.print_action("overwrite", &dest, None);
+                if !self.options.dry_run {
+                    if existing.is_dir() {
+                        fs::remove_dir_all(&dest)?;
+                    } else {
+                        fs::remove_file(&dest)?;
+                    }
+                }
+                self.report.overwritten += 1;
+                Ok(Some(dest))
+            }
+            ConflictPolicy::Rename => {
+                let renamed = renamed_path(&dest);
+                self.print_action("rename", &dest, Some(&renamed));
+                if kind == EntryKind::Dir {
+                    self.redirects.push((dest, Some(renamed.clone())));
+                }
+                self.report.renamed += 1;
+                self.prepare_parent(renamed).map(Some)
+            }
+        }
+    }
+
+    // an entry is selected when it or one of its parents matches an include, and neither it nor
+    // a parent matches an exclude
+    fn selected(&self, relative: &Path) -> bool {
+        let matches = |patterns: &[glob::Pattern]| {
+            relative.ancestors().filter(|p| !p.as_os_str().is_empty()).any(|p| patterns.iter().any(|pattern| pattern.matches_path(p)))
+        };
+        (self.options.includes.is_empty() || matches(&self.options.includes)) && !matches(&self.options.excludes)
+    }
+
+    // the root the entry is restored below and its path, the longest matching prefix wins
+    fn map(&self, relative: &Path) -> (PathBuf, PathBuf) {
+        let mapping = self.options.mappings.iter()
+            .filter(|(prefix, _)| relative.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count());
+        match mapping {
+            Some((prefix, dir)) => (dir.clone(), dir.join(relative.strip_prefix(prefix).unwrap_or(relative))),
+            None => (self.options.destination.clone(), self.options.destination.join(relative)),
+        }
+    }
+
+    fn prepare_parent(&self, dest: PathBuf) -> Result<PathBuf> {
+        if !self.options.dry_run {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(dest)
+    }
+
+    fn print_action(&self, action: &str, dest: &Path, renamed: Option<&Path>) {
+        if !self.options.dry_run {
+            return;
+        }
+        match renamed {
+            Some(renamed) => println!("{} {} -> {}", action, dest.to_string_lossy(), renamed.to_string_lossy()),
+            None => println!("{} {}", action, dest.to_string_lossy()),
+        }
+    }
+
+    fn count(&mut self, kind: EntryKind, size: u64) {
+        match kind {
+            EntryKind::Dir => self.report.dirs += 1,
+            EntryKind::Symlink => self.report.symlinks += 1,
+            EntryKind::File => {
+                self.report.files += 1;
+                self.report.bytes += size;
+            }
+        }
+    }
+
+    // modes and times of the dirs, deepest first so a read-only dir doesn't stop its children
+    fn finish(&mut self) {
+        self.dirs.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+        for (path, mode, mtime) in self.dirs.iter() {
+            if let Some(mode) = mode {
+                if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777)) {
+                    warn!("restore mode of [{}] failed: {}", path.to_string_lossy(), e);
+                }
+            }
+            if let Some(mtime) = mtime {
+                let res = File::open(path).and_then(|dir| dir.set_modified(UNIX_EPOCH + Duration::from_secs(*mtime)));
+                if let Err(e) = res {
+                    warn!("restore mtime of [{}] failed: {}", path.to_string_lossy(), e);
+                }
+            }
+        }
+    }
+}
+
+// `<path>.restored`, or `<path>.restored.<n>` with the first n that doesn't exist yet
+fn renamed_path(path: &Path) -> PathBuf {
+    let mut renamed = PathBuf::from(format!("{}.{}", path.to_string_lossy(), consts::RESTORED_SUFFIX));
+    let mut n = 1;
+    while fs::symlink_metadata(&renamed).is_ok() {
+        renamed = PathBuf::from(format!("{}.{}.{}", path.to_string_lossy(), consts::RESTORED_SUFFIX, n));
+        n += 1;
+    }
+    renamed
+}
+
+fn chown(path: &Path, uid: u32, gid: u32) {
+    let mut bytes = path.as_os_str().as_bytes().to_vec();
+    bytes.push(0);
+    // lchown, a symlink itself is given away instead of its target
+    let res = unsafe { libc::lchown(bytes.as_ptr() as *const libc::c_char, uid, gid) };
+    if res != 0 {
+        warn!("restore owner of [{}] failed: {}", path.to_string_lossy(), io::Error::last_os_error());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    fn options(destination: &Path) -> RestoreOptions {
+        RestoreOptions::new(consts::BACKUP_TARGET_LOCAL.to_string(), "latest".to_string(), destination.to_path_buf())
+    }
+
+    #[test]
+    fn unsafe_entries_are_skipped() {
+        let dir = test_dir("restore-unsafe");
+        let options = options(&dir.join("dest"));
+        let mut extractor = Extractor::new(&options);
+        for name in ["archive/../escape", "archive/a/../../escape", "archive//etc/passwd", "/etc/passwd", "../escape", "archive/./a", "archive"] {
+            assert_eq!(extractor.plan(name, EntryKind::File).unwrap(), None, "{}", name);
+        }
+        assert_eq!(extractor.plan("archive/a/b.txt", EntryKind::File).unwrap(), Some(dir.join("dest/a/b.txt")));
+        assert!(!dir.join("escape").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_below_a_symlink_are_skipped() {
+        let dir = test_dir("restore-symlink");
+        fs::create_dir_all(dir.join("dest")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), dir.join("dest/link")).unwrap();
+        let options = options(&dir.join("dest"));
+        let mut extractor = Extractor::new(&options);
+        assert_eq!(extractor.plan("archive/link/a.txt", EntryKind::File).unwrap(), None);
+        assert_eq!(extractor.report.skipped, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn longest_mapping_wins() {
+        let dir = test_dir("restore-mapping");
+        let mut options = options(&dir.join("dest"));
+        options.mappings = vec![
+            parse_mapping(format!("data={}", dir.join("data").display()).as_str()).unwrap(),
+            parse_mapping(format!("/data/sub/={}", dir.join("sub").display()).as_str()).unwrap(),
+        ];
+        let extractor = Extractor::new(&options);
+        assert_eq!(extractor.map(Path::new("data/a.txt")).1, dir.join("data/a.txt"));
+        assert_eq!(extractor.map(Path::new("data/sub/a.txt")), (dir.join("sub"), dir.join("sub/a.txt")));
+        assert_eq!(extractor.map(Path::new("database/a.txt")).1, dir.join("dest/database/a.txt"));
+        for mapping in ["data", "../data=/tmp", "data=", "=/tmp"] {
+            assert!(parse_mapping(mapping).is_err(), "{}", mapping);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conflicts_follow_the_policy() {
+        let dir = test_dir("restore-conflict");
+        fs::create_dir_all(dir.join("dest/sub")).unwrap();
+        fs::write(dir.join("dest/a.txt"), b"a").unwrap();
+        fs::write(dir.join("dest/a.txt.restored"), b"a").unwrap();
+        let mut options = options(&dir.join("dest"));
+        options.conflict = ConflictPolicy::Rename;
+        let mut extractor = Extractor::new(&options);
+        assert_eq!(extractor.plan("archive/a.txt", EntryKind::File).unwrap(), Some(dir.join("dest/a.txt.restored.1")));
+        // existing dirs are merged
+        assert_eq!(extractor.plan("archive/sub/", EntryKind::Dir).unwrap(), Some(dir.join("dest/sub")));
+        options.conflict = ConflictPolicy::Skip;
+        let mut extractor = Extractor::new(&options);
+        assert_eq!(extractor.plan("archive/a.txt", EntryKind::File).unwrap(), None);
+        assert_eq!(extractor.report.skipped, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_copy_of_a_duplicated_entry_wins() {
+        let dir = test_dir("restore-duplicate");
+        fs::create_dir_all(dir.join("dest")).unwrap();
+        fs::write(dir.join("dest/a.txt"), b"existing").unwrap();
+        let mut tar = tar::Builder::new(vec![]);
+        for data in [&b"first"[..], &b"second"[..]] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, "archive/a.txt", data).unwrap();
+        }
+        let archive = tar.into_inner().unwrap();
+        for (conflict, restored) in [(ConflictPolicy::Rename, "a.txt.restored"), (ConflictPolicy::Overwrite, "a.txt")] {
+            let _ = fs::remove_file(dir.join("dest/a.txt.restored"));
+            let mut options = options(&dir.join("dest"));
+            options.conflict = conflict;
+            let mut extractor = Extractor::new(&options);
+            extractor.extract_tar(archive.as_slice()).unwrap();
+            assert_eq!(fs::read(dir.join("dest").join(restored)).unwrap(), b"second", "{}", restored);
+            assert!(!dir.join("dest/a.txt.restored.1").exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsafe_archive_names_are_rejected() {
+        let cfg = BackerConfig::default();
+        for name in ["../escape.tar.gz", "/etc/passwd", "a/../../escape.tar.gz", "sub/a.tar.gz", "..", ""] {
+            assert!(resolve_archive(&cfg, consts::BACKUP_TARGET_LOCAL, name).is_err(), "{}", name);
+        }
+        assert_eq!(resolve_archive(&cfg, consts::BACKUP_TARGET_LOCAL, "Archive-1.tar.gz.enc").unwrap(), "Archive-1.tar.gz");
+        let dir = test_dir("restore-archive-name");
+        assert!(fetch_stored(&cfg, consts::BACKUP_TARGET_LOCAL, "../escape.tar.gz", &dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
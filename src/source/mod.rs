@@ -0,0 +1 @@
+pub mod source;
\ No newline at end of file
@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdout, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rusqlite::{Connection, OpenFlags};
+use rusqlite::backup::{Backup, StepResult};
+
+use crate::config::config::{BackupFileConfig, PathMappingConfig, SourceConfig};
+use crate::consts;
+use crate::utils::{file, host};
+
+// retries of a busy backup step, 100ms apart
+const SQLITE_BUSY_RETRIES: u32 = 300;
+
+// stderr kept of a command source, the tail is the interesting part
+const MAX_STDERR_LENGTH: usize = 64 * 1024;
+
+/// Expand the backup-files entries (paths, glob patterns and files-from lists) into the paths to
+/// archive, named below `archive/` by their alias or the path mapping. An entry that matches
+/// nothing, or a path that can't be read, is handled by the entry's missing policy: `error` fails
+/// the job with every problem listed, `warn` logs and skips it.
+pub fn resolve_backup_files(entries: &[BackupFileConfig], mapping: &PathMappingConfig) -> Result<Vec<file::BackupPath>> {
+    let mut paths = vec![];
+    let mut seen = HashSet::new();
+    let mut problems = vec![];
+    for entry in entries {
+        let mut report = |problem: String| match entry.missing.as_str() {
+            consts::MISSING_PATH_IGNORE => {}
+            consts::MISSING_PATH_WARN => warn!("skip backup path: {}", problem),
+            _ => problems.push(problem),
+        };
+        let patterns = if !entry.files_from.is_empty() {
+            match read_files_from(entry.files_from.as_str()) {
+                Ok(patterns) => patterns,
+                Err(e) => {
+                    report(format!("read files-from [{}] failed: {}", entry.files_from, e));
+                    continue;
+                }
+            }
+        } else {
+            vec![entry.path.clone()]
+        };
+        for pattern in patterns {
+            for path in expand_pattern(pattern.as_str(), &mut report) {
+                if let Err(e) = check_readable(&path) {
+                    report(format!("{} can't be read: {}", path.display(), e));
+                    continue;
+                }
+                let name = if !entry.alias.is_empty() {
+                    entry.alias.clone()
+                } else {
+                    archive_name(&path, mapping)?
+                };
+                let path = path.to_string_lossy().to_string();
+                if seen.insert(path.clone()) {
+                    paths.push(file::BackupPath::new(path, name, entry.missing.clone()));
+                }
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!("backup paths missing or unreadable: {}", problems.join("; ")));
+    }
+    Ok(paths)
+}
+
+// name of a backup path below archive/: its file name, its absolute path, or its path relative to
+// the mapping root
+fn archive_name(path: &Path, mapping: &PathMappingConfig) -> Result<String> {
+    let name = match mapping.mode.as_str() {
+        consts::PATH_MAPPING_ABSOLUTE => relative_components(&absolute_path(path)?),
+        consts::PATH_MAPPING_RELATIVE => {
+            let root = absolute_path(Path::new(mapping.root.as_str()))?;
+            match absolute_path(path)?.strip_prefix(&root) {
+                Ok(relative) => relative_components(relative),
+                Err(_) => return Err(anyhow!("{} is not below the path mapping root {}", path.display(), root.display())),
+            }
+        }
+        _ => path.file_name().map(|tail| tail.to_string_lossy().to_string()).unwrap_or_default(),
+    };
+    Ok(name)
+}
+
+// lexically normalized absolute path, symlinks are kept as they are archived
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let path = if path.is_absolute() { path.to_path_buf() } else { env::current_dir()?.join(path) };
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+// the path without its root, e.g. srv/a/data for /srv/a/data, and C/data for C:\data
+fn relative_components(path: &Path) -> String {
+    path.components().filter_map(|component| match component {
+        Component::Prefix(prefix) => Some(prefix.as_os_str().to_string_lossy().trim_end_matches(':').to_string()),
+        Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+        _ => None,
+    }).collect::<Vec<String>>().join("/")
+}
+
+/// Make sure no two backup paths or sources end up at the same place in the archive, or one inside
+/// the other. Every collision is listed in the error.
+pub fn check_archive_names(paths: &[file::BackupPath], sources: &[SourceConfig]) -> Result<()> {
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    let mut collisions = vec![];
+    let names = paths.iter().map(|p| (p.name.as_str(), p.path.as_str()))
+        .chain(sources.iter().map(|source| {
+            let owner = if source.source_type == consts::SOURCE_TYPE_COMMAND { source.command.as_str() } else { source.path.as_str() };
+            (source.name.as_str(), owner)
+        }));
+    for (name, owner) in names {
+        match owners.get(name) {
+            Some(other) => collisions.push(format!("archive/{}: {} and {}", name, other, owner)),
+            None => {
+                owners.insert(name, owner);
+            }
+        }
+    }
+    for (name, owner) in owners.iter() {
+        // an empty name is the archive root, which contains every other name
+        let mut parent = Path::new(*name).parent();
+        while let Some(dir) = parent {
+            if let Some(other) = owners.get(dir.to_string_lossy().as_ref()) {
+                collisions.push(format!("archive/{} of {} is inside {}", name, owner, other));
+            }
+            parent = dir.parent();
+        }
+    }
+    if !collisions.is_empty() {
+        collisions.sort();
+        return Err(anyhow!("archive names collide: {}", collisions.join("; ")));
+    }
+    Ok(())
+}
+
+// one path or pattern per line, blank lines and # comments are skipped. relative paths are relative
+// to the list file.
+fn read_files_from(list: &str) -> io::Result<Vec<String>> {
+    let base = Path::new(list).parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+    Ok(fs::read_to_string(list)?.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line).to_string_lossy().to_string())
+        .collect())
+}
+
+fn expand_pattern<F: FnMut(String)>(pattern: &str, report: &mut F) -> Vec<PathBuf> {
+    if !pattern.contains(['*', '?', '[']) {
+        if fs::symlink_metadata(pattern).is_err() {
+            report(format!("{} doesn't exist", pattern));
+            return vec![];
+        }
+        return vec![PathBuf::from(pattern)];
+    }
+    let matches = match glob::glob(pattern) {
+        Ok(matches) => matches,
+        Err(e) => {
+            report(format!("pattern {} invalid: {}", pattern, e));
+            return vec![];
+        }
+    };
+    let mut paths = vec![];
+    for path in matches {
+        match path {
+            Ok(path) => paths.push(path),
+            Err(e) => report(format!("{} can't be read: {}", e.path().display(), e.error())),
+        }
+    }
+    if paths.is_empty() {
+        report(format!("pattern {} matches nothing", pattern));
+    }
+    paths
+}
+
+// the archive writers skip what they can't read, so check it before the archive is built
+fn check_readable(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        fs::read_dir(path)?;
+    } else if metadata.is_file() {
+        fs::File::open(path)?;
+    }
+    Ok(())
+}
+
+/// Stream entries of the command sources. Each command is only started when the archive reaches
+/// its entry, and its stdout is archived as `archive/<name>`.
+pub fn command_streams(sources: &[SourceConfig]) -> Vec<file::StreamEntry> {
+    sources.iter()
+        .filter(|source| source.source_type == consts::SOURCE_TYPE_COMMAND)
+        .map(|source| file::StreamEntry::new(source.name.clone(), Box::new(CommandReader::new(source.command.clone()))))
+        .collect()
+}
+
+/// Stdout of a shell command. The command is started on the first read, and the end of its
+/// output is an error when it exits with a non-zero status; its stderr is logged either way.
+pub struct CommandReader {
+    command: String,
+    child: Option<Child>,
+    stdout: Option<ChildStdout>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    stderr_reader: Option<JoinHandle<()>>,
+    finished: bool,
+}
+
+impl CommandReader {
+    pub fn new(command: String) -> Self {
+        Self { command, child: None, stdout: None, stderr: Default::default(), stderr_reader: None, finished: false }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        info!("run command source: {}", self.command);
+        let mut child = host::shell(self.command.as_str())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        self.stdout = child.stdout.take();
+        if let Some(mut err) = child.stderr.take() {
+            let stderr = self.stderr.clone();
+            self.stderr_reader = Some(thread::spawn(move || {
+                let mut buffer = [0u8; 8192];
+                while let Ok(n) = err.read(&mut buffer) {
+                    if n == 0 {
+                        break;
+                    }
+                    let mut stderr = stderr.lock().unwrap();
+                    stderr.extend_from_slice(&buffer[..n]);
+                    if stderr.len() > MAX_STDERR_LENGTH {
+                        let overflow = stderr.len() - MAX_STDERR_LENGTH;
+                        stderr.drain(..overflow);
+                    }
+                }
+            }));
+        }
+        self.child = Some(child);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.stdout = None;
+        let status = match self.child.take() {
+            Some(mut child) => child.wait()?,
+            None => return Ok(()),
+        };
+        if let Some(reader) = self.stderr_reader.take() {
+            let _ = reader.join();
+        }
+        let stderr = String::from_utf8_lossy(&self.stderr.lock().unwrap()).trim().to_string();
+        if !stderr.is_empty() {
+            warn!("command source [{}] stderr: {}", self.command, stderr);
+        }
+        if !status.success() {
+            return Err(io::Error::other(format!("command source [{}] exited with {}: {}", self.command, status, stderr)));
+        }
+        info!("command source finished: {}", self.command);
+        Ok(())
+    }
+}
+
+impl Read for CommandReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+        if self.child.is_none() {
+            self.start()?;
+        }
+        let n = match self.stdout.as_mut() {
+            Some(stdout) => stdout.read(buf)?,
+            None => 0,
+        };
+        if n == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for CommandReader {
+    fn drop(&mut self) {
+        // the archive failed before the output was read to the end
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Write a consistent snapshot of every file source to `<dir>/sources/<name>`. Returns the snapshot
+/// paths, to be archived next to the backup files.
+pub fn snapshot_sources<P: AsRef<Path>>(sources: &[SourceConfig], dir: P) -> Result<Vec<String>> {
+    let sources_dir = dir.as_ref().join(consts::SOURCES_DIR);
+    file::create_dir(&sources_dir)?;
+    let mut paths = vec![];
+    for source in sources.iter().filter(|source| source.source_type != consts::SOURCE_TYPE_COMMAND) {
+        let target = sources_dir.join(source.name.as_str());
+        match source.source_type.as_str() {
+            consts::SOURCE_TYPE_SQLITE => sqlite_snapshot(source.path.as_str(), &target)
+                .map_err(|e| anyhow!("snapshot sqlite database [{}] failed: {}", source.path, e))?,
+            _ => return Err(anyhow!("source type invalid: {}", source.source_type)),
+        }
+        info!("snapshot {} source [{}] as {}", source.source_type, source.path, source.name);
+        paths.push(target.to_string_lossy().to_string());
+    }
+    Ok(paths)
+}
+
+// copy the database with the online backup API. all pages are copied in a single step, under one
+// read transaction, so writers (in WAL mode they aren't even blocked) can't leave a torn copy.
+fn sqlite_snapshot<P: AsRef<Path>, Q: AsRef<Path>>(path: P, target: Q) -> Result<()> {
+    if !file::is_file(path.as_ref()) {
+        return Err(anyhow!("database file doesn't exist"));
+    }
+    let src = Connection::open_with_flags(path.as_ref(), OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    src.busy_timeout(Duration::from_secs(30))?;
+    let mut dst = Connection::open(target.as_ref())?;
+    let backup = Backup::new(&src, &mut dst)?;
+    let mut retries = 0;
+    loop {
+        match backup.step(-1)? {
+            StepResult::Done => break,
+            StepResult::Busy | StepResult::Locked => {
+                retries += 1;
+                if retries > SQLITE_BUSY_RETRIES {
+                    return Err(anyhow!("database is busy"));
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            _ => {}
+        }
+    }
+    drop(backup);
+    // a copy of a WAL database is still in WAL mode, switch it back so the snapshot is one file
+    dst.pragma_update_and_check(None, "journal_mode", "DELETE", |row| row.get::<_, String>(0))?;
+    dst.close().map_err(|(_, e)| e)?;
+    // date the snapshot by the database instead of the job, unchanged databases archive the same
+    let modified = [path.as_ref().to_path_buf(), PathBuf::from(format!("{}-wal", path.as_ref().display()))].iter()
+        .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .max();
+    if let Some(modified) = modified {
+        fs::File::options().write(true).open(target.as_ref())?.set_modified(modified)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,3 @@
+pub mod qiniu;
+pub mod range;
+pub mod storage;
\ No newline at end of file
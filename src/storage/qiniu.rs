@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha1::Sha1;
+
+use crate::config::config::QiniuServer;
+use crate::storage::storage::TargetFile;
+
+const RSF_HOST: &str = "https://rsf.qbox.me";
+const LIST_LIMIT: usize = 1000;
+const DOWNLOAD_URL_LIFETIME: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Deserialize)]
+struct ListResponse {
+    #[serde(default)]
+    marker: String,
+    #[serde(default)]
+    items: Vec<ListItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListItem {
+    key: String,
+    fsize: u64,
+    // in units of 100 nanoseconds
+    #[serde(rename = "putTime", default)]
+    put_time: u64,
+}
+
+/// The objects of the bucket whose keys start with `prefix`, paged through the rsf list api.
+pub fn list_files(cfg: &QiniuServer, prefix: &str) -> Result<Vec<TargetFile>> {
+    let mut files = vec![];
+    let mut marker = String::new();
+    loop {
+        let mut query = format!("bucket={}&limit={}&prefix={}", url_encode(cfg.bucket_name.as_str()), LIST_LIMIT, url_encode(prefix));
+        if !marker.is_empty() {
+            query.push_str(format!("&marker={}", url_encode(marker.as_str())).as_str());
+        }
+        let path = format!("/list?{}", query);
+        // management requests sign the path and query followed by a newline
+        let token = sign(cfg, format!("{}\n", path).as_bytes());
+        let body = ureq::post(format!("{}{}", RSF_HOST, path).as_str())
+            .set("Authorization", format!("QBox {}", token).as_str())
+            .set("Content-Type", "application/x-www-form-urlencoded")
+            .call()
+            .map_err(|e| anyhow!("list qiniu bucket [{}] failed: {}", cfg.bucket_name, e))?
+            .into_string()?;
+        let page: ListResponse = serde_json::from_str(body.as_str())?;
+        files.extend(page.items.into_iter().map(|item| TargetFile { name: item.key, size: item.fsize, modified: item.put_time / 10_000_000, sha256: String::new() }));
+        if page.marker.is_empty() {
+            break;
+        }
+        marker = page.marker;
+    }
+    Ok(files)
+}
+
+/// Download `name` from the download domain of the bucket with a signed private url.
+pub fn fetch_file<P: AsRef<Path>>(cfg: &QiniuServer, name: &str, dest: P) -> Result<()> {
+    let response = ureq::get(download_url(cfg, name)?.as_str())
+        .call()
+        .map_err(|e| anyhow!("download [{}] from qiniu failed: {}", name, e))?;
+    let mut writer = BufWriter::new(File::create(dest.as_ref())?);
+    io::copy(&mut response.into_reader(), &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Download `length` bytes of `name` from `offset` on with a range request.
+pub fn read_range(cfg: &QiniuServer, name: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+    if length == 0 {
+        return Ok(vec![]);
+    }
+    let response = ureq::get(download_url(cfg, name)?.as_str())
+        .set("Range", format!("bytes={}-{}", offset, offset + length - 1).as_str())
+        .call()
+        .map_err(|e| anyhow!("download range of [{}] from qiniu failed: {}", name, e))?;
+    // a server ignoring the range answers with the whole file
+    if response.status() != 206 {
+        return Err(anyhow!("qiniu answered a range of [{}] with status {}", name, response.status()));
+    }
+    let mut data = Vec::with_capacity(length as usize);
+    response.into_reader().take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(anyhow!("qiniu sent {} of {} bytes of [{}]", data.len(), length, name));
+    }
+    Ok(data)
+}
+
+// private url of `name` on the download domain, signed until DOWNLOAD_URL_LIFETIME from now
+fn download_url(cfg: &QiniuServer, name: &str) -> Result<String> {
+    if cfg.download_domain.is_empty() {
+        return Err(anyhow!("qiniu download-domain is not configured"));
+    }
+    let domain = cfg.download_domain.trim_end_matches('/');
+    let base_url = if domain.contains("://") {
+        format!("{}/{}", domain, url_encode(name))
+    } else {
+        format!("http://{}/{}", domain, url_encode(name))
+    };
+    let deadline = (SystemTime::now() + DOWNLOAD_URL_LIFETIME).duration_since(UNIX_EPOCH)?.as_secs();
+    let url = format!("{}?e={}", base_url, deadline);
+    let token = sign(cfg, url.as_bytes());
+    Ok(format!("{}&token={}", url, token))
+}
+
+// <access key>:<url safe base64 of the hmac-sha1 of data>
+fn sign(cfg: &QiniuServer, data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(cfg.secret_key.as_bytes()).expect("hmac accepts any key length");
+    mac.update(data);
+    format!("{}:{}", cfg.access_key, URL_SAFE.encode(mac.finalize().into_bytes()))
+}
+
+// percent-encode everything but unreserved characters and path separators
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(format!("%{:02X}", b).as_str());
+        }
+    }
+    encoded
+}
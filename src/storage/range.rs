@@ -0,0 +1,101 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::config::config::BackerConfig;
+use crate::storage::storage;
+
+const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// A file stored on a target, possibly as volumes, read by range. Small reads are served from the
+/// chunk around them, so the many small reads of a zip central directory don't each go to the
+/// target.
+pub struct RangeReader<'a> {
+    cfg: &'a BackerConfig,
+    target: String,
+    // stored file, its offset in the whole file and its size
+    parts: Vec<(String, u64, u64)>,
+    size: u64,
+    pos: u64,
+    chunk: Option<(u64, Vec<u8>)>,
+    fetched: u64,
+    session: storage::RangeSession,
+}
+
+impl<'a> RangeReader<'a> {
+    /// `parts` are the stored files in order with their sizes, a single one when not split.
+    pub fn new(cfg: &'a BackerConfig, target: &str, parts: Vec<(String, u64)>) -> Self {
+        let mut offset = 0;
+        let parts = parts.into_iter().map(|(name, size)| {
+            offset += size;
+            (name, offset - size, size)
+        }).collect();
+        Self { cfg, target: target.to_string(), parts, size: offset, pos: 0, chunk: None, fetched: 0, session: storage::RangeSession::default() }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of bytes read from the target so far.
+    pub fn fetched(&self) -> u64 {
+        self.fetched
+    }
+
+    // bytes from `offset` on, from as many parts as they span
+    fn read_at(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let end = (offset + length).min(self.size);
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        for (name, start, size) in self.parts.iter() {
+            let from = offset.max(*start);
+            let to = end.min(start + size);
+            if from >= to {
+                continue;
+            }
+            let bytes = storage::read_range(self.cfg, self.target.as_str(), name.as_str(), from - start, to - from, &mut self.session)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            data.extend_from_slice(&bytes);
+        }
+        self.fetched += data.len() as u64;
+        Ok(data)
+    }
+}
+
+impl<'a> Read for RangeReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        if buf.len() as u64 >= CHUNK_SIZE {
+            let data = self.read_at(self.pos, buf.len() as u64)?;
+            buf[..data.len()].copy_from_slice(&data);
+            self.pos += data.len() as u64;
+            return Ok(data.len());
+        }
+        let chunk_start = self.pos / CHUNK_SIZE * CHUNK_SIZE;
+        if self.chunk.as_ref().map(|(start, _)| *start != chunk_start).unwrap_or(true) {
+            let data = self.read_at(chunk_start, CHUNK_SIZE)?;
+            self.chunk = Some((chunk_start, data));
+        }
+        let chunk = &self.chunk.as_ref().unwrap().1;
+        let from = (self.pos - chunk_start) as usize;
+        let n = buf.len().min(chunk.len().saturating_sub(from));
+        buf[..n].copy_from_slice(&chunk[from..from + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Seek for RangeReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.size as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
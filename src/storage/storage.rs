@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::config::config::{BackerConfig, BackerServer};
+use crate::consts;
+use crate::manifest::manifest;
+use crate::manifest::manifest::Manifest;
+use crate::packet::message::{FetchRequest, ListRequest, Message, Protocol};
+use crate::storage::qiniu;
+use crate::utils::file;
+use crate::utils::staging::WorkDir;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A file stored on a backup target.
+#[derive(Debug, Clone)]
+pub struct TargetFile {
+    pub name: String,
+    pub size: u64,
+    // unix seconds, 0 when the target doesn't tell
+    pub modified: u64,
+    // hex sha256 of the content, only filled in by `list_hashed_files`
+    pub sha256: String,
+}
+
+/// An archive stored on a target, with the files it's stored as (the archive or its volumes and
+/// the sidecar files).
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredArchive {
+    pub target: String,
+    pub name: String,
+    pub size: u64,
+    pub date: String,
+    pub host: String,
+    pub files: Vec<String>,
+}
+
+/// The archive a stored file belongs to, `<prefix>-<time>.<mode>` for the archive itself and for
+/// its encrypted copy, volumes, manifest and signature. Archive names sort by their time.
+pub fn archive_of(prefix: &str, file_name: &str) -> Option<String> {
+    let prefix = format!("{}-", prefix);
+    let rest = file_name.strip_prefix(prefix.as_str())?;
+    for mode in [consts::COMPRESS_MODE_TAR, consts::COMPRESS_MODE_TAR_ZSTD, consts::COMPRESS_MODE_ZIP] {
+        let mode = format!(".{}", mode);
+        if let Some(pos) = rest.find(mode.as_str()) {
+            let end = pos + mode.len();
+            if end == rest.len() || rest[end..].starts_with('.') {
+                return Some(file_name[..prefix.len() + end].to_string());
+            }
+        }
+    }
+    None
+}
+
+/// The local time an archive was created, from its name.
+pub fn archive_time(prefix: &str, archive: &str) -> Option<chrono::NaiveDateTime> {
+    let rest = archive.strip_prefix(format!("{}-", prefix).as_str())?;
+    let time = rest.split('.').next()?;
+    chrono::NaiveDateTime::parse_from_str(time, "%F_%T").ok()
+}
+
+/// Files stored on `target` whose names start with `prefix`, in no particular order.
+pub fn list_files(cfg: &BackerConfig, target: &str, prefix: &str) -> Result<Vec<TargetFile>> {
+    match target {
+        consts::BACKUP_TARGET_LOCAL => {
+            let mut files = vec![];
+            for entry in fs::read_dir(cfg.local.path.as_str())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if metadata.is_file() && name.starts_with(prefix) {
+                    let modified = metadata.modified().ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map(|duration| duration.as_secs())
+                        .unwrap_or(0);
+                    files.push(TargetFile { name, size: metadata.len(), modified, sha256: String::new() });
+                }
+            }
+            Ok(files)
+        }
+        consts::BACKUP_TARGET_QINIU => qiniu::list_files(&cfg.qiniu, prefix),
+        consts::BACKUP_TARGET_BACKER_SERVER => list_backer_server(&cfg.backer_server, prefix, false),
+        _ => Err(anyhow!("listing files is not supported by target [{}]", target)),
+    }
+}
+
+/// Like `list_files`, with the sha256 of every file. The target reads every listed file to hash
+/// it, only local and backer-server do that.
+pub fn list_hashed_files(cfg: &BackerConfig, target: &str, prefix: &str) -> Result<Vec<TargetFile>> {
+    match target {
+        consts::BACKUP_TARGET_LOCAL => {
+            let mut files = list_files(cfg, target, prefix)?;
+            for f in files.iter_mut() {
+                f.sha256 = file::sha256_file(Path::new(cfg.local.path.as_str()).join(f.name.as_str()))?;
+            }
+            Ok(files)
+        }
+        consts::BACKUP_TARGET_BACKER_SERVER => list_backer_server(&cfg.backer_server, prefix, true),
+        _ => Err(anyhow!("listing hashes is not supported by target [{}]", target)),
+    }
+}
+
+/// The archives stored on `target`, oldest first. The host is read from the manifest of an
+/// archive when it has one.
+pub fn list_archives(cfg: &BackerConfig, target: &str) -> Result<Vec<StoredArchive>> {
+    let prefix = cfg.archive_prefix.as_str();
+    let mut archives: BTreeMap<String, StoredArchive> = BTreeMap::new();
+    for f in list_files(cfg, target, format!("{}-", prefix).as_str())? {
+        let name = match archive_of(prefix, f.name.as_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let archive = archives.entry(name.clone()).or_insert_with(|| StoredArchive {
+            target: target.to_string(),
+            date: archive_time(prefix, name.as_str()).map(|time| time.format("%F %T").to_string()).unwrap_or_default(),
+            name,
+            size: 0,
+            host: String::new(),
+            files: vec![],
+        });
+        archive.size += f.size;
+        archive.files.push(f.name);
+    }
+    let manifests = archives.values().filter(|a| a.files.contains(&manifest::manifest_file_name(a.name.as_str()))).count();
+    if manifests > 0 {
+        let work_dir = WorkDir::create(cfg.staging_dir.as_str())?;
+        for archive in archives.values_mut() {
+            let manifest_name = manifest::manifest_file_name(archive.name.as_str());
+            if !archive.files.contains(&manifest_name) {
+                continue;
+            }
+            let path = work_dir.dir().join(manifest_name.as_str());
+            let res = fetch_file(cfg, target, manifest_name.as_str(), &path)
+                .and_then(|_| file::read_file(&path).map_err(|e| anyhow!("{}", e)))
+                .and_then(|bytes| Manifest::from_bytes(&bytes));
+            match res {
+                Ok(archive_manifest) => archive.host = archive_manifest.hostname,
+                Err(e) => warn!("read manifest [{}] from [{}] failed: {}", manifest_name, target, e),
+            }
+        }
+    }
+    for archive in archives.values_mut() {
+        archive.files.sort();
+    }
+    Ok(archives.into_values().collect())
+}
+
+/// Download the file `name` stored on `target` to `dest`.
+pub fn fetch_file<P: AsRef<Path>>(cfg: &BackerConfig, target: &str, name: &str, dest: P) -> Result<()> {
+    if !file::is_safe_relative_path(name) {
+        return Err(anyhow!("invalid file name [{}]", name));
+    }
+    info!("fetch [{}] from [{}]", name, target);
+    // fetched under a temporary name, so a failed fetch never leaves a truncated file at `dest`
+    let dest = dest.as_ref();
+    let mut partial = dest.as_os_str().to_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let res = match target {
+        consts::BACKUP_TARGET_LOCAL => {
+            fs::copy(Path::new(cfg.local.path.as_str()).join(name), &partial).map(|_| ()).map_err(|e| e.into())
+        }
+        consts::BACKUP_TARGET_QINIU => qiniu::fetch_file(&cfg.qiniu, name, &partial),
+        consts::BACKUP_TARGET_BACKER_SERVER => File::create(&partial).map_err(|e| e.into()).and_then(|f| {
+            let mut writer = BufWriter::new(f);
+            fetch_from_backer_server(&cfg.backer_server, FetchRequest::new(name.to_string()), &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        }),
+        _ => return Err(anyhow!("fetching files is not supported by target [{}]", target)),
+    };
+    if let Err(e) = res.and_then(|_| fs::rename(&partial, dest).map_err(|e| e.into())) {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Delete the file `name` stored on `target`.
+pub fn delete_file(cfg: &BackerConfig, target: &str, name: &str) -> Result<()> {
+    if !file::is_safe_relative_path(name) {
+        return Err(anyhow!("invalid file name [{}]", name));
+    }
+    info!("delete [{}] from [{}]", name, target);
+    match target {
+        consts::BACKUP_TARGET_LOCAL => {
+            fs::remove_file(Path::new(cfg.local.path.as_str()).join(name))?;
+            Ok(())
+        }
+        consts::BACKUP_TARGET_BACKER_SERVER => delete_from_backer_server(&cfg.backer_server, name),
+        _ => Err(anyhow!("deleting files is not supported by target [{}]", target)),
+    }
+}
+
+/// Connections kept open between the range reads of one file, so the backer server isn't
+/// connected and authenticated again for each read.
+#[derive(Default)]
+pub struct RangeSession {
+    backer_server: Option<Protocol>,
+}
+
+/// Whether `target` serves byte ranges of its files, see `read_range`. Restores from the other
+/// targets (aliyun-oss and tencent-oss, whose uploads are still todo) fetch whole archives.
+pub fn supports_range(target: &str) -> bool {
+    target == consts::BACKUP_TARGET_LOCAL || target == consts::BACKUP_TARGET_QINIU || target == consts::BACKUP_TARGET_BACKER_SERVER
+}
+
+/// Read `length` bytes from `offset` on of the file `name` stored on `target`.
+pub fn read_range(cfg: &BackerConfig, target: &str, name: &str, offset: u64, length: u64, session: &mut RangeSession) -> Result<Vec<u8>> {
+    if !file::is_safe_relative_path(name) {
+        return Err(anyhow!("invalid file name [{}]", name));
+    }
+    match target {
+        consts::BACKUP_TARGET_LOCAL => {
+            let mut f = File::open(Path::new(cfg.local.path.as_str()).join(name))?;
+            f.seek(SeekFrom::Start(offset))?;
+            let mut data = vec![0u8; length as usize];
+            f.read_exact(&mut data)?;
+            Ok(data)
+        }
+        consts::BACKUP_TARGET_QINIU => qiniu::read_range(&cfg.qiniu, name, offset, length),
+        consts::BACKUP_TARGET_BACKER_SERVER => {
+            let request = FetchRequest::with_range(name.to_string(), offset, length);
+            let mut data = Vec::with_capacity(length as usize);
+            // a kept connection the server has closed since is opened again once
+            let kept = session.backer_server.take().and_then(|mut protocol| {
+                fetch_on_backer_server(&mut protocol, request.clone(), &mut data).ok().map(|_| protocol)
+            });
+            let protocol = match kept {
+                Some(protocol) => protocol,
+                None => {
+                    data.clear();
+                    let mut protocol = connect_backer_server(&cfg.backer_server)?;
+                    fetch_on_backer_server(&mut protocol, request, &mut data)?;
+                    protocol
+                }
+            };
+            session.backer_server = Some(protocol);
+            if data.len() as u64 != length {
+                return Err(anyhow!("backer server sent {} bytes of [{}] instead of {}", data.len(), name, length));
+            }
+            Ok(data)
+        }
+        _ => Err(anyhow!("reading ranges is not supported by target [{}]", target)),
+    }
+}
+
+/// Size of the file `name` stored on `target`.
+pub fn file_size(cfg: &BackerConfig, target: &str, name: &str) -> Result<u64> {
+    if target == consts::BACKUP_TARGET_LOCAL {
+        return Ok(fs::metadata(Path::new(cfg.local.path.as_str()).join(name))?.len());
+    }
+    list_files(cfg, target, name)?.into_iter().find(|f| f.name == name).map(|f| f.size)
+        .ok_or_else(|| anyhow!("[{}] not found on [{}]", name, target))
+}
+
+fn connect_backer_server(cfg: &BackerServer) -> Result<Protocol> {
+    let stream = TcpStream::connect((cfg.ip.as_str(), cfg.port))?;
+    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    let mut protocol = Protocol::with_stream(stream)?;
+    protocol.send_message(Message::Auth(cfg.secret.clone()))?;
+    match protocol.read_message()? {
+        Message::Authorize(true) => Ok(protocol),
+        _ => Err(anyhow!("backer server refused the secret")),
+    }
+}
+
+// read the pages of the file list until the last one
+fn list_backer_server(cfg: &BackerServer, prefix: &str, with_hash: bool) -> Result<Vec<TargetFile>> {
+    let mut protocol = connect_backer_server(cfg)?;
+    protocol.send_message(Message::List(ListRequest::new(prefix.to_string(), with_hash)))?;
+    let mut files = vec![];
+    loop {
+        match protocol.read_message()? {
+            Message::FileList(list) => {
+                files.extend(list.files.into_iter().map(|f| TargetFile { name: f.name, size: f.size, modified: f.modified, sha256: f.sha256 }));
+                if list.is_end {
+                    break;
+                }
+            }
+            Message::Complete(_) => return Err(anyhow!("backer server can't list its files")),
+            _ => {}
+        }
+    }
+    let _ = protocol.shutdown();
+    Ok(files)
+}
+
+fn fetch_from_backer_server<W: Write>(cfg: &BackerServer, request: FetchRequest, writer: &mut W) -> Result<()> {
+    let mut protocol = connect_backer_server(cfg)?;
+    fetch_on_backer_server(&mut protocol, request, writer)?;
+    let _ = protocol.shutdown();
+    Ok(())
+}
+
+// read the file buffers of the answer until the end. the server answers with Complete when it
+// can't send the file or the range, the connection can be used for the next request either way.
+fn fetch_on_backer_server<W: Write>(protocol: &mut Protocol, request: FetchRequest, writer: &mut W) -> Result<()> {
+    let name = request.file_name.clone();
+    protocol.send_message(Message::Fetch(request))?;
+    loop {
+        match protocol.read_message()? {
+            Message::FileBuffer(buffer) => {
+                writer.write_all(buffer.buffer.as_slice())?;
+                if buffer.is_end {
+                    break;
+                }
+            }
+            Message::Complete(_) => return Err(anyhow!("backer server can't send [{}]", name)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn delete_from_backer_server(cfg: &BackerServer, name: &str) -> Result<()> {
+    let mut protocol = connect_backer_server(cfg)?;
+    protocol.send_message(Message::Delete(name.to_string()))?;
+    let res = loop {
+        match protocol.read_message()? {
+            Message::Complete(true) => break Ok(()),
+            Message::Complete(false) => break Err(anyhow!("backer server can't delete [{}]", name)),
+            _ => {}
+        }
+    };
+    let _ = protocol.shutdown();
+    res
+}
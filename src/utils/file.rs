@@ -1,17 +1,27 @@
 use std::{fs, io};
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Seek, Write};
-use std::path::{Path, PathBuf};
-use flate2::Compression;
-use flate2::write::GzEncoder;
+use std::io::{Cursor, Read, Seek, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use flate2::read::MultiGzDecoder;
+use log::warn;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use walkdir::{WalkDir};
+use zip::result::ZipResult;
 use zip::write::FileOptions;
 
 use crate::consts;
 use crate::errors::CustomError;
+use crate::index::index::{IndexEntry, TarIndex};
+use crate::utils::{parallel, sparse};
+use crate::utils::parallel::{Codec, ParallelEncoder};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
@@ -40,9 +50,139 @@ impl Default for FileInfo {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CompressType {
     Zip,
     Tar,
+    TarZstd,
+}
+
+impl CompressType {
+    pub fn from_mode(mode: &str) -> Self {
+        match mode {
+            consts::COMPRESS_MODE_TAR => CompressType::Tar,
+            consts::COMPRESS_MODE_TAR_ZSTD => CompressType::TarZstd,
+            _ => CompressType::Zip,
+        }
+    }
+}
+
+/// What to do with a file that still changed while being read after all retries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangePolicy {
+    Warn,
+    Fail,
+    MarkInconsistent,
+}
+
+impl ChangePolicy {
+    pub fn from_policy(policy: &str) -> Self {
+        match policy {
+            consts::CHANGE_POLICY_FAIL => ChangePolicy::Fail,
+            consts::CHANGE_POLICY_MARK_INCONSISTENT => ChangePolicy::MarkInconsistent,
+            _ => ChangePolicy::Warn,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressOptions {
+    pub compress_type: CompressType,
+    pub threads: usize,
+    pub change_retries: u32,
+    pub change_policy: ChangePolicy,
+    pub follow_symlinks: bool,
+    pub one_file_system: bool,
+    pub store_policy: StorePolicy,
+    pub deterministic: bool,
+}
+
+impl CompressOptions {
+    pub fn new(compress_type: CompressType) -> Self {
+        Self {
+            compress_type,
+            threads: parallel::compress_threads(0),
+            change_retries: consts::DEFAULT_CHANGE_RETRIES,
+            change_policy: ChangePolicy::Warn,
+            follow_symlinks: false,
+            one_file_system: false,
+            store_policy: StorePolicy::default(),
+            deterministic: false,
+        }
+    }
+}
+
+/// Files that are already compressed (media, archives) and are stored without compressing them
+/// again: by extension, or by the byte entropy of their first block.
+#[derive(Debug, Clone, Default)]
+pub struct StorePolicy {
+    pub extensions: Vec<String>,
+    pub entropy_sampling: bool,
+}
+
+impl StorePolicy {
+    pub fn new(extensions: &[String], entropy_sampling: bool) -> Self {
+        Self {
+            extensions: extensions.iter().map(|ext| ext.trim_start_matches('.').to_lowercase()).collect(),
+            entropy_sampling,
+        }
+    }
+
+    pub fn should_store(&self, path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
+            if self.extensions.contains(&ext.to_string_lossy().to_lowercase()) {
+                return true;
+            }
+        }
+        self.entropy_sampling && sample_entropy(path).map(|e| e >= STORE_ENTROPY).unwrap_or(false)
+    }
+}
+
+// shannon entropy in bits per byte of the first block of a file, compressed data is close to 8.
+// too small files don't tell much and return 0.
+fn sample_entropy(path: &Path) -> io::Result<f64> {
+    let mut sample = Vec::with_capacity(ENTROPY_SAMPLE_SIZE);
+    File::open(path)?.take(ENTROPY_SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    if sample.len() < ENTROPY_MIN_SAMPLE_SIZE {
+        return Ok(0.0);
+    }
+    let mut counts = [0u64; 256];
+    for byte in sample.iter() {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    Ok(counts.iter().filter(|c| **c > 0).map(|c| {
+        let p = *c as f64 / len;
+        -p * p.log2()
+    }).sum())
+}
+
+/// Files read into an archive. Files that changed while being read are listed by their name in
+/// the archive; the inconsistent ones are also listed in `archive/.backer-inconsistent`.
+#[derive(Debug, Clone, Default)]
+pub struct CompressReport {
+    pub files: u64,
+    pub bytes: u64,
+    pub changed_files: Vec<String>,
+    pub inconsistent_files: Vec<String>,
+    pub stored_files: u64,
+    pub stored_bytes: u64,
+    pub entries: Vec<ArchivedEntry>,
+    // where the entries of a tar archive are, none for zip
+    pub tar_index: Option<TarIndex>,
+}
+
+/// An entry written to an archive, as recorded in the catalog. The sha256 of files is only known
+/// when a manifest is written.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArchivedEntry {
+    pub path: String,
+    pub kind: String,
+    pub size: u64,
+    // unix seconds
+    pub mtime: u64,
+    #[serde(default)]
+    pub sha256: String,
 }
 
 pub fn is_exist<P: AsRef<Path>>(path: P) -> bool {
@@ -94,6 +234,13 @@ pub fn create_write_file<P: AsRef<Path>>(path: P, buf: &[u8]) -> Result<(), Box<
     Ok(())
 }
 
+/// Write a new file only its owner can read, failing when the file already exists.
+pub fn create_secret_file<P: AsRef<Path>>(path: P, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
 pub fn create_file<P: AsRef<Path>>(path: P) -> Result<File, Box<dyn Error>> {
     let file = File::create(path)?;
     Ok(file)
@@ -128,6 +275,17 @@ pub fn read_file_info_without_file_data<P: AsRef<Path>>(path: P) -> Result<FileI
     Ok(FileInfo::new(file_name, path.as_ref().to_string_lossy().to_string(), Box::new(vec![])))
 }
 
+pub fn file_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    Ok(fs::metadata(path)?.len())
+}
+
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut f = File::open(path)?;
+    io::copy(&mut f, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 pub fn rm_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
     fs::remove_file(path)
 }
@@ -136,89 +294,696 @@ fn path_to_string<P: AsRef<Path>>(path: P) -> String {
     path.as_ref().to_str().unwrap().to_string()
 }
 
+// a relative path that stays below the directory it is joined to
+pub fn is_safe_relative_path<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    !path.as_os_str().is_empty() && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+// the path of an archive entry below archive/, none for the root and the list of inconsistent files
+pub fn archive_entry_path(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('/');
+    let path = name.strip_prefix("archive/")?;
+    if path == consts::INCONSISTENT_FILES_ENTRY {
+        return None;
+    }
+    Some(path.to_string())
+}
+
+// the user's home dir, or the temp dir when there is none (e.g. in containers)
+fn get_base_dir_path() -> PathBuf {
+    match home::home_dir() {
+        Some(user_home_dir) => user_home_dir,
+        None => std::env::temp_dir(),
+    }
+}
+
 pub fn get_archive_dir_path() -> PathBuf {
-    let user_home_dir = home::home_dir().unwrap();
-    user_home_dir.join(consts::ARCHIVE_DIR_SUFFIX)
+    get_base_dir_path().join(consts::ARCHIVE_DIR_SUFFIX)
+}
+
+pub fn get_repository_cache_dir_path() -> PathBuf {
+    get_base_dir_path().join(consts::REPOSITORY_CACHE_DIR_SUFFIX)
+}
+
+pub fn get_state_dir_path() -> PathBuf {
+    get_base_dir_path().join(consts::STATE_DIR_SUFFIX)
+}
+
+// total size of the files below `paths`, used to estimate the staging space of an archive
+pub fn estimate_size<P: AsRef<Path>>(paths: &[P]) -> u64 {
+    let mut size = 0;
+    for path in paths {
+        for entry in WalkDir::new(path.as_ref()).into_iter().filter_map(|e| e.ok()) {
+            if entry.file_type().is_file() {
+                size += entry.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+        }
+    }
+    size
+}
+
+pub fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<u64> {
+    if let Some(parent) = to.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(from, to)
+}
+
+/// A path to archive and the name of its root below `archive/`, an empty name puts its content
+/// right into `archive/`. `missing` is the policy for entries below it that vanish or can't be
+/// read while the archive is built.
+#[derive(Debug, Clone)]
+pub struct BackupPath {
+    pub path: String,
+    pub name: String,
+    pub missing: String,
+}
+
+impl BackupPath {
+    pub fn new(path: String, name: String, missing: String) -> Self {
+        Self { path, name, missing }
+    }
+
+    // archived under its file name, failing on unreadable entries
+    pub fn from_basename(path: String) -> Self {
+        let name = Path::new(path.as_str()).file_name().map(|tail| tail.to_string_lossy().to_string()).unwrap_or_default();
+        Self { path, name, missing: consts::MISSING_PATH_ERROR.to_string() }
+    }
+}
+
+/// A virtual file archived as `archive/<name>`, its content is read from `reader` while the
+/// archive is written. A read error fails the archive.
+pub struct StreamEntry {
+    pub name: String,
+    pub reader: Box<dyn Read + Send>,
+}
+
+impl StreamEntry {
+    pub fn new(name: String, reader: Box<dyn Read + Send>) -> Self {
+        Self { name, reader }
+    }
 }
 
-pub fn compress_files<P: AsRef<Path>>(paths: Vec<P>, target: P, compress_type: CompressType) -> Result<(), Box<dyn Error>> {
+pub fn compress_files<P: AsRef<Path>>(paths: Vec<BackupPath>, streams: Vec<StreamEntry>, target: P, options: &CompressOptions) -> Result<CompressReport, Box<dyn Error>> {
     let compress_file = File::create(target.as_ref())?;
+    let report = match options.compress_type {
+        CompressType::Zip => zip_compress(paths, streams, compress_file, options)?,
+        CompressType::Tar => tar_compress(paths, streams, compress_file, Codec::Gzip, options)?,
+        CompressType::TarZstd => tar_compress(paths, streams, compress_file, Codec::Zstd, options)?,
+    };
+    Ok(report)
+}
+
+/// Decompressed stream of a tar archive.
+pub fn tar_decoder<R: Read + 'static>(reader: R, compress_type: &CompressType) -> io::Result<Box<dyn Read>> {
     match compress_type {
-        CompressType::Zip => zip_compress(paths, compress_file)?,
-        CompressType::Tar => tar_compress(paths, compress_file)?,
+        CompressType::TarZstd => Ok(Box::new(zstd::Decoder::new(reader)?)),
+        _ => Ok(Box::new(MultiGzDecoder::new(reader))),
+    }
+}
+
+const ENTROPY_SAMPLE_SIZE: usize = 64 * 1024;
+const ENTROPY_MIN_SAMPLE_SIZE: usize = 4 * 1024;
+// bits per byte from which a sampled file is considered compressed already
+const STORE_ENTROPY: f64 = 7.5;
+
+// files from this size on are streamed into zip archives, smaller ones are compressed in parallel
+const ZIP_STREAM_SIZE: u64 = 64 * 1024 * 1024;
+const ZIP64_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+enum EntryKind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+}
+
+struct ArchiveEntry {
+    path: PathBuf,
+    name: String,
+    kind: EntryKind,
+}
+
+enum PendingZipEntry {
+    Dir(String, FileOptions),
+    Symlink(String, PathBuf, FileOptions),
+    // single entry zip, compressed by a worker thread
+    File(String, bool, JoinHandle<ZipResult<(Vec<u8>, u64, bool)>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FileState {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+fn file_state(path: &Path) -> io::Result<FileState> {
+    let metadata = fs::metadata(path)?;
+    Ok(FileState { size: metadata.len(), modified: metadata.modified().ok() })
+}
+
+// read `path` with `read` until its size and mtime are the same before and after the read, at
+// most `retries` + 1 times. returns the result of the last read and whether the file was stable.
+fn read_stable<T, F: FnMut(u64) -> io::Result<T>>(path: &Path, retries: u32, mut read: F) -> io::Result<(T, bool)> {
+    let mut attempt = 0;
+    loop {
+        let before = file_state(path)?;
+        let value = read(before.size)?;
+        if file_state(path)? == before {
+            return Ok((value, true));
+        }
+        if attempt >= retries {
+            return Ok((value, false));
+        }
+        attempt += 1;
+        warn!("[{}] changed while being archived, read it again ({}/{})", path_to_string(path), attempt, retries);
+    }
+}
+
+impl CompressReport {
+    // count a read file, and apply the change policy when it wasn't stable
+    fn add_file(&mut self, name: &str, size: u64, stored: bool, stable: bool, policy: ChangePolicy) -> io::Result<()> {
+        self.files += 1;
+        self.bytes += size;
+        if stored {
+            self.stored_files += 1;
+            self.stored_bytes += size;
+        }
+        if stable {
+            return Ok(());
+        }
+        warn!("[{}] kept changing while being archived", name);
+        self.changed_files.push(name.to_string());
+        match policy {
+            ChangePolicy::Fail => Err(io::Error::other(format!("[{}] changed while being archived", name))),
+            ChangePolicy::MarkInconsistent => {
+                self.inconsistent_files.push(name.to_string());
+                Ok(())
+            }
+            ChangePolicy::Warn => Ok(()),
+        }
+    }
+
+    // record an entry of the walk with the size and mtime of its file
+    fn add_entry(&mut self, entry: &ArchiveEntry) {
+        let (kind, metadata) = match entry.kind {
+            EntryKind::File => (consts::ENTRY_KIND_FILE, fs::metadata(&entry.path)),
+            EntryKind::Dir => (consts::ENTRY_KIND_DIR, fs::metadata(&entry.path)),
+            EntryKind::Symlink(_) => (consts::ENTRY_KIND_SYMLINK, fs::symlink_metadata(&entry.path)),
+        };
+        let (size, mtime) = metadata.map(|m| {
+            let mtime = m.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0);
+            (if m.is_file() { m.len() } else { 0 }, mtime)
+        }).unwrap_or((0, 0));
+        self.entries.push(ArchivedEntry { path: entry.name.clone(), kind: kind.to_string(), size, mtime, sha256: String::new() });
+    }
+
+    fn add_stream(&mut self, name: String, size: u64, mtime: u64) {
+        self.entries.push(ArchivedEntry { path: name, kind: consts::ENTRY_KIND_FILE.to_string(), size, mtime, sha256: String::new() });
+    }
+
+    fn inconsistent_list(&self) -> Vec<u8> {
+        self.inconsistent_files.iter().map(|name| format!("{}\n", name)).collect::<String>().into_bytes()
+    }
+}
+
+// walk the backup paths, the same way for every archive format. the backup paths themselves are
+// always followed. below them symlinks are stored as links, or followed when `follow_symlinks` is
+// set; a link that would loop back to one of its parent dirs, or that is broken, is stored as a
+// link then. other filesystems are skipped with `one_file_system`, special files always are.
+fn collect_entries(paths: Vec<BackupPath>, options: &CompressOptions) -> io::Result<Vec<ArchiveEntry>> {
+    let mut entries = vec![];
+    let mut problems = vec![];
+    for backup_path in paths.into_iter() {
+        // the archive writers would fail on or silently skip what can't be read, the backup path's
+        // policy decides instead
+        let mut report = |problem: String| match backup_path.missing.as_str() {
+            consts::MISSING_PATH_IGNORE => {}
+            consts::MISSING_PATH_WARN => warn!("skip [{}]", problem),
+            _ => problems.push(problem),
+        };
+        let root = Path::new(backup_path.path.as_str());
+        if fs::symlink_metadata(root).is_err() {
+            report(format!("{} disappeared", backup_path.path));
+            continue;
+        }
+        let prefix = if backup_path.name.is_empty() {
+            String::from("archive")
+        } else {
+            format!("archive/{}", backup_path.name)
+        };
+        let mut walk_dir = WalkDir::new(root)
+            .follow_links(options.follow_symlinks)
+            .same_file_system(options.one_file_system);
+        if options.deterministic {
+            walk_dir = walk_dir.sort_by_file_name();
+        }
+        for entry in walk_dir {
+            let (path, kind) = match entry {
+                Ok(entry) => {
+                    let file_type = entry.file_type();
+                    let kind = if file_type.is_symlink() {
+                        match fs::read_link(entry.path()) {
+                            Ok(target) => EntryKind::Symlink(target),
+                            Err(e) => {
+                                report(format!("{}: {}", path_to_string(entry.path()), e));
+                                continue;
+                            }
+                        }
+                    } else if file_type.is_dir() {
+                        EntryKind::Dir
+                    } else if file_type.is_file() {
+                        if let Err(e) = File::open(entry.path()) {
+                            report(format!("{}: {}", path_to_string(entry.path()), e));
+                            continue;
+                        }
+                        EntryKind::File
+                    } else {
+                        warn!("skip [{}], it's neither a file, a dir nor a symlink", path_to_string(entry.path()));
+                        continue;
+                    };
+                    (entry.path().to_path_buf(), kind)
+                }
+                Err(e) => {
+                    let path = match e.path() {
+                        Some(path) => path.to_path_buf(),
+                        None => {
+                            report(e.to_string());
+                            continue;
+                        }
+                    };
+                    match fs::read_link(&path) {
+                        Ok(target) => {
+                            if e.loop_ancestor().is_some() {
+                                warn!("[{}] loops back to a parent dir, store it as a link", path_to_string(&path));
+                            } else {
+                                warn!("can't follow [{}]: {}, store it as a link", path_to_string(&path), e);
+                            }
+                            (path, EntryKind::Symlink(target))
+                        }
+                        Err(_) => {
+                            report(format!("{}: {}", path_to_string(&path), e));
+                            continue;
+                        }
+                    }
+                }
+            };
+            let name = match path.strip_prefix(root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => format!("{}/{}", prefix, relative.to_string_lossy()),
+                _ => prefix.clone(),
+            };
+            entries.push(ArchiveEntry { path, name, kind });
+        }
+    }
+    if !problems.is_empty() {
+        return Err(io::Error::other(format!("backup paths missing or unreadable: {}", problems.join("; "))));
+    }
+    Ok(entries)
+}
+
+// mtime of a deterministic archive entry, at most SOURCE_DATE_EPOCH when that is set
+fn header_mtime(metadata: &fs::Metadata) -> u64 {
+    let mtime = metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    match std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse::<u64>().ok()) {
+        Some(epoch) => mtime.min(epoch),
+        None => mtime,
+    }
+}
+
+// zip times start in 1980, earlier ones are stored as its first day
+fn zip_time(mtime: u64) -> zip::DateTime {
+    time::OffsetDateTime::from_unix_timestamp(mtime as i64).ok()
+        .and_then(|time| zip::DateTime::try_from(time).ok())
+        .unwrap_or_default()
+}
+
+// permissions of a zip entry, normalized like deterministic tar headers: 0755 for dirs and
+// executables, 0644 for everything else
+fn zip_mode(metadata: &fs::Metadata, deterministic: bool) -> u32 {
+    let mode = metadata.permissions().mode() & 0o7777;
+    if !deterministic {
+        return mode;
+    }
+    if metadata.is_dir() || mode & 0o100 != 0 { 0o755 } else { 0o644 }
+}
+
+// returns the single entry archive, the size read and whether the file was stable
+fn compress_zip_entry(path: PathBuf, name: String, options: FileOptions, retries: u32) -> ZipResult<(Vec<u8>, u64, bool)> {
+    let ((bytes, size), stable) = read_stable(&path, retries, |_| {
+        let mut zip_writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip_writer.start_file(name.as_str(), options)?;
+        let mut f = File::open(&path)?;
+        let size = io::copy(&mut f, &mut zip_writer)?;
+        Ok((zip_writer.finish()?.into_inner(), size))
+    })?;
+    Ok((bytes, size, stable))
+}
+
+// large files are streamed straight into the archive instead of a single entry archive in memory.
+// a written entry can't be taken back, so a file that changed meanwhile isn't read again and the
+// change policy applies at once.
+fn zip_stream_file<T: Write + Seek>(zip_writer: &mut zip::ZipWriter<T>, entry: &ArchiveEntry, options: FileOptions) -> io::Result<(u64, bool)> {
+    let before = file_state(&entry.path)?;
+    // zip64 is needed past 4 GiB, leave room for a file that grows while it's read
+    zip_writer.start_file(entry.name.as_str(), options.large_file(before.size >= ZIP64_SIZE))?;
+    let mut f = File::open(&entry.path)?;
+    let size = io::copy(&mut f, zip_writer)?;
+    Ok((size, file_state(&entry.path)? == before))
+}
+
+fn write_pending_zip_entry<T: Write + Seek>(zip_writer: &mut zip::ZipWriter<T>, pending: PendingZipEntry, report: &mut CompressReport, policy: ChangePolicy) -> io::Result<()> {
+    match pending {
+        PendingZipEntry::Dir(name, options) => zip_writer.add_directory(name, options)?,
+        PendingZipEntry::Symlink(name, target, options) => zip_writer.add_symlink(name, target.to_string_lossy(), options)?,
+        PendingZipEntry::File(name, stored, handle) => {
+            let (bytes, size, stable) = handle.join().map_err(|_| io::Error::other("compress thread panicked"))??;
+            report.add_file(name.as_str(), size, stored, stable, policy)?;
+            let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+            zip_writer.raw_copy_file(archive.by_index(0)?)?;
+        }
     }
     Ok(())
 }
 
-fn zip_compress<P: AsRef<Path>, T>(paths: Vec<P>, writer: T) -> io::Result<()> where T: Write + Seek {
+// entries are compressed in parallel into single entry archives, then copied in order without
+// recompressing
+fn zip_compress<T>(paths: Vec<BackupPath>, streams: Vec<StreamEntry>, writer: T, compress_options: &CompressOptions) -> io::Result<CompressReport> where T: Write + Seek {
     let mut zip_writer = zip::ZipWriter::new(writer);
     let options = FileOptions::default()
         .compression_method(zip::CompressionMethod::Bzip2)
-        .unix_permissions(0o755);
-    for src_path in paths.into_iter() {
-        if Path::new(src_path.as_ref()).is_dir() {
-            let walk_dir = WalkDir::new(src_path.as_ref());
-            let mut buffer = Vec::new();
-            let it = &mut walk_dir.into_iter().filter_map(|e| e.ok());
-            for entry in it {
-                let path = entry.path();
-                let tail = Path::new(src_path.as_ref()).file_name().unwrap();
-                let name = path.strip_prefix(Path::new(src_path.as_ref())).unwrap();
-
-                let prefix: String;
-                if tail.is_empty() {
-                    prefix = format!("archive/{}", name.to_str().unwrap());
-                } else {
-                    prefix = format!("archive/{}/{}", tail.to_str().unwrap(), name.to_str().unwrap());
-                }
-                let archive = Path::new(prefix.as_str());
-                if path.is_file() {
-                    #[allow(deprecated)]
-                    zip_writer.start_file_from_path(archive, options)?;
-                    let mut f = File::open(path)?;
-
-                    f.read_to_end(&mut buffer)?;
-                    zip_writer.write_all(&*buffer)?;
-                    buffer.clear();
-                } else {
-                    #[allow(deprecated)]
-                    zip_writer.add_directory_from_path(archive, options)?;
-                }
+        .unix_permissions(0o644);
+    let threads = compress_options.threads.max(1);
+    let retries = compress_options.change_retries;
+    let policy = compress_options.change_policy;
+    let mut report = CompressReport::default();
+    let mut pending: VecDeque<PendingZipEntry> = VecDeque::new();
+    let mut running = 0;
+    let mut newest = 0;
+    for entry in collect_entries(paths, compress_options)? {
+        report.add_entry(&entry);
+        let metadata = fs::symlink_metadata(&entry.path).ok();
+        let options = options.unix_permissions(metadata.as_ref().map(|m| zip_mode(m, compress_options.deterministic)).unwrap_or(0o644));
+        // entries are dated by their files instead of the time they are archived
+        let options = if compress_options.deterministic {
+            let mtime = metadata.as_ref().map(header_mtime).unwrap_or(0);
+            newest = newest.max(mtime);
+            options.last_modified_time(zip_time(mtime))
+        } else {
+            options
+        };
+        match entry.kind {
+            EntryKind::Dir => {
+                pending.push_back(PendingZipEntry::Dir(entry.name, options));
+                continue;
             }
-        } else if Path::new(src_path.as_ref()).is_file() {
-            let path = Path::new(src_path.as_ref());
-            let file_name = get_file_name(path).unwrap();
-            let prefix = format!("archive/{}", file_name);
-            let archive = Path::new(prefix.as_str());
-
-            let mut buffer = Vec::new();
-            #[allow(deprecated)]
-            zip_writer.start_file_from_path(archive, options)?;
-            let mut f = File::open(path)?;
-
-            f.read_to_end(&mut buffer)?;
-            zip_writer.write_all(&*buffer)?;
+            EntryKind::Symlink(target) => {
+                pending.push_back(PendingZipEntry::Symlink(entry.name, target, options));
+                continue;
+            }
+            EntryKind::File => {}
         }
+        let stored = compress_options.store_policy.should_store(&entry.path);
+        let file_options = if stored { options.compression_method(zip::CompressionMethod::Stored) } else { options };
+        if fs::metadata(&entry.path).map(|m| m.len()).unwrap_or(0) >= ZIP_STREAM_SIZE {
+            while let Some(entry) = pending.pop_front() {
+                write_pending_zip_entry(&mut zip_writer, entry, &mut report, policy)?;
+            }
+            running = 0;
+            let (size, stable) = zip_stream_file(&mut zip_writer, &entry, file_options)?;
+            report.add_file(entry.name.as_str(), size, stored, stable, policy)?;
+            continue;
+        }
+        while running >= threads {
+            if let Some(PendingZipEntry::File(..)) = pending.front() {
+                running -= 1;
+            }
+            write_pending_zip_entry(&mut zip_writer, pending.pop_front().unwrap(), &mut report, policy)?;
+        }
+        let name = entry.name.clone();
+        pending.push_back(PendingZipEntry::File(name, stored, thread::spawn(move || compress_zip_entry(entry.path, entry.name, file_options, retries))));
+        running += 1;
+    }
+    while let Some(entry) = pending.pop_front() {
+        write_pending_zip_entry(&mut zip_writer, entry, &mut report, policy)?;
+    }
+    // entries without a file of their own get the newest file time
+    let options = if compress_options.deterministic { options.last_modified_time(zip_time(newest)) } else { options };
+    // the size of a stream is unknown up front, the zip writer patches it into the local header
+    let now = chrono::Local::now().timestamp().max(0) as u64;
+    for mut stream in streams {
+        let name = format!("archive/{}", stream.name);
+        zip_writer.start_file(name.as_str(), options.large_file(true))?;
+        let size = io::copy(&mut stream.reader, &mut zip_writer)?;
+        report.add_stream(name, size, if compress_options.deterministic { newest } else { now });
+    }
+    if !report.inconsistent_files.is_empty() {
+        zip_writer.start_file(format!("archive/{}", consts::INCONSISTENT_FILES_ENTRY), options)?;
+        zip_writer.write_all(&report.inconsistent_list())?;
     }
     zip_writer.finish()?;
-    Ok(())
+    Ok(report)
 }
 
-fn tar_compress<P: AsRef<Path>, T>(paths: Vec<P>, writer: T) -> io::Result<()> where T: Write + Seek {
-    let enc = GzEncoder::new(writer, Compression::default());
+fn tar_compress<T>(paths: Vec<BackupPath>, streams: Vec<StreamEntry>, writer: T, codec: Codec, compress_options: &CompressOptions) -> io::Result<CompressReport> where T: Write + Seek {
+    let enc = ParallelEncoder::new(writer, codec, compress_options.threads);
     let mut tar = tar::Builder::new(enc);
+    let mut report = CompressReport::default();
+    let deterministic = compress_options.deterministic;
+    let mut newest = 0;
+    let mut index_entries = vec![];
 
-    for path in paths.into_iter() {
-        if is_dir(path.as_ref()) {
-            let p = Path::new(path.as_ref());
-            let suffix_path = p.file_name().unwrap().to_str().unwrap().to_string();
-            tar.append_dir_all(format!("archive/{}", suffix_path), path)?;
-        } else if is_file(path.as_ref()) {
-            let file_name = get_file_name(path.as_ref()).unwrap();
-            let mut f = File::open(path.as_ref()).unwrap();
-            tar.append_file(format!("archive/{}", file_name), &mut f)?;
+    for entry in collect_entries(paths, compress_options)? {
+        report.add_entry(&entry);
+        if deterministic {
+            newest = newest.max(fs::symlink_metadata(&entry.path).map(|m| header_mtime(&m)).unwrap_or(0));
+        }
+        let start = tar.get_ref().position();
+        match &entry.kind {
+            EntryKind::Dir => {
+                let mut header = tar_header(&fs::metadata(&entry.path)?, deterministic);
+                header.set_size(0);
+                tar.append_data(&mut header, entry.name.as_str(), io::empty())?;
+                index_entries.push(index_entry(&report, start, tar.get_ref().position()));
+                continue;
+            }
+            EntryKind::Symlink(target) => {
+                let mut header = tar_header(&fs::symlink_metadata(&entry.path)?, deterministic);
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                tar.append_link(&mut header, entry.name.as_str(), target)?;
+                index_entries.push(index_entry(&report, start, tar.get_ref().position()));
+                continue;
+            }
+            EntryKind::File => {}
         }
+        // already compressed files go into store blocks of the stream
+        let stored = compress_options.store_policy.should_store(&entry.path);
+        tar.get_mut().set_store(stored)?;
+        // an entry can't be taken back once written, a file that changed is appended again and
+        // the last copy wins when the archive is extracted
+        let (size, stable) = read_stable(&entry.path, compress_options.change_retries, |size| {
+            tar_append_file(&mut tar, entry.name.as_str(), &entry.path, size, deterministic)
+        })?;
+        // every copy of a changed file is in the span, like in the archive
+        index_entries.push(IndexEntry { size, ..index_entry(&report, start, tar.get_ref().position()) });
+        report.add_file(entry.name.as_str(), size, stored, stable, compress_options.change_policy)?;
+    }
+    tar.get_mut().set_store(false)?;
+    // entries without a file of their own get the newest file time
+    let mtime = if deterministic { newest } else { chrono::Local::now().timestamp().max(0) as u64 };
+    for mut stream in streams {
+        let name = format!("archive/{}", stream.name);
+        let start = tar.get_ref().position();
+        let size = tar_append_stream(&mut tar, name.as_str(), &mut stream.reader, mtime)?;
+        report.add_stream(name, size, mtime);
+        index_entries.push(index_entry(&report, start, tar.get_ref().position()));
+    }
+    if !report.inconsistent_files.is_empty() {
+        let list = report.inconsistent_list();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_size(list.len() as u64);
+        let name = format!("archive/{}", consts::INCONSISTENT_FILES_ENTRY);
+        let start = tar.get_ref().position();
+        tar.append_data(&mut header, name.as_str(), list.as_slice())?;
+        index_entries.push(IndexEntry {
+            path: name,
+            start,
+            end: tar.get_ref().position(),
+            kind: consts::ENTRY_KIND_FILE.to_string(),
+            size: list.len() as u64,
+            mtime,
+        });
+    }
+    let (_, blocks) = tar.into_inner()?.finish_with_blocks()?;
+    report.tar_index = Some(TarIndex::new(blocks, index_entries));
+    Ok(report)
+}
+
+// the index entry of the entry last added to the report, it ends at `end` of the tar stream
+fn index_entry(report: &CompressReport, start: u64, end: u64) -> IndexEntry {
+    let entry = report.entries.last().expect("an entry was added to the report");
+    IndexEntry { path: entry.path.clone(), start, end, kind: entry.kind.clone(), size: entry.size, mtime: entry.mtime }
+}
+
+// header of a file, dir or symlink entry. deterministic headers have no owners, normalized
+// permissions and the file's mtime clamped to SOURCE_DATE_EPOCH.
+fn tar_header(metadata: &fs::Metadata, deterministic: bool) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    if deterministic {
+        header.set_metadata_in_mode(metadata, tar::HeaderMode::Deterministic);
+        header.set_mtime(header_mtime(metadata));
+    } else {
+        header.set_metadata(metadata);
+    }
+    header
+}
+
+// append exactly `size` bytes of the file, the size its header was written with. a file that
+// shrank meanwhile is padded with zeros, one that grew is cut, so the stream stays valid.
+fn tar_append_file<T: Write>(tar: &mut tar::Builder<T>, name: &str, path: &Path, size: u64, deterministic: bool) -> io::Result<u64> {
+    let mut f = File::open(path)?;
+    if let Some(regions) = sparse::data_regions(&f, size)? {
+        return tar_append_sparse_file(tar, name, &mut f, size, &regions, deterministic);
+    }
+    let mut header = tar_header(&f.metadata()?, deterministic);
+    header.set_size(size);
+    tar.append_data(&mut header, name, f.take(size).chain(io::repeat(0)).take(size))?;
+    Ok(size)
+}
+
+// a file with holes is stored as a GNU sparse entry, only its data regions are written
+fn tar_append_sparse_file<T: Write>(tar: &mut tar::Builder<T>, name: &str, f: &mut File, size: u64, regions: &[(u64, u64)], deterministic: bool) -> io::Result<u64> {
+    let mut header = tar_header(&f.metadata()?, deterministic);
+    if header.set_path(name).is_err() {
+        // the name goes in a GNU long name entry in front, the header keeps it truncated
+        let mut long_name = name.as_bytes().to_vec();
+        long_name.push(0);
+        let mut long_header = tar::Header::new_gnu();
+        long_header.set_entry_type(tar::EntryType::GNULongName);
+        long_header.set_mode(0o644);
+        long_header.set_size(long_name.len() as u64);
+        tar.append_data(&mut long_header, "././@LongLink", long_name.as_slice())?;
+        let n = name.len().min(100);
+        header.as_old_mut().name = [0; 100];
+        header.as_old_mut().name[..n].copy_from_slice(&name.as_bytes()[..n]);
+    }
+    let mut entries = regions.to_vec();
+    // a trailing hole is marked by an empty region at the end of the file
+    if entries.last().map(|(offset, length)| offset + length).unwrap_or(0) < size {
+        entries.push((size, 0));
+    }
+    let data_size: u64 = entries.iter().map(|(_, length)| length).sum();
+    header.set_entry_type(tar::EntryType::GNUSparse);
+    header.set_size(data_size);
+    let gnu = header.as_gnu_mut().unwrap();
+    gnu.set_real_size(size);
+    for (slot, (offset, length)) in gnu.sparse.iter_mut().zip(entries.iter()) {
+        slot.set_offset(*offset);
+        slot.set_length(*length);
+    }
+    gnu.set_is_extended(entries.len() > gnu.sparse.len());
+    header.set_cksum();
+
+    let dst = tar.get_mut();
+    dst.write_all(header.as_bytes())?;
+    let extended = entries.iter().skip(4).collect::<Vec<&(u64, u64)>>();
+    let ext_headers = extended.chunks(21).count();
+    for (i, chunk) in extended.chunks(21).enumerate() {
+        let mut ext = tar::GnuExtSparseHeader::new();
+        for (slot, (offset, length)) in ext.sparse.iter_mut().zip(chunk.iter()) {
+            slot.set_offset(*offset);
+            slot.set_length(*length);
+        }
+        ext.set_is_extended(i + 1 < ext_headers);
+        dst.write_all(ext.as_bytes())?;
+    }
+    for (offset, length) in entries {
+        f.seek(io::SeekFrom::Start(offset))?;
+        io::copy(&mut (&mut *f).take(length).chain(io::repeat(0)).take(length), dst)?;
+    }
+    let padding = (512 - data_size % 512) % 512;
+    dst.write_all(&vec![0u8; padding as usize])?;
+    Ok(size)
+}
+
+// tar needs the size in the header in front of the data. the header is written as a stored block
+// with size 0 and patched once the stream is exhausted, so the stream is never buffered.
+fn tar_append_stream<T: Write + Seek>(tar: &mut tar::Builder<ParallelEncoder<T>>, name: &str, reader: &mut dyn Read, mtime: u64) -> io::Result<u64> {
+    let mut header = tar::Header::new_gnu();
+    header.set_path(name)?;
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_size(0);
+    header.set_cksum();
+    let encoder = tar.get_mut();
+    let offset = encoder.write_stored(header.as_bytes())?;
+    let size = io::copy(reader, encoder)?;
+    let padding = (512 - size % 512) % 512;
+    encoder.write_all(&vec![0u8; padding as usize])?;
+    header.set_size(size);
+    header.set_cksum();
+    encoder.patch_stored(offset, header.as_bytes())?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    fn backup_path(path: &Path, missing: &str) -> BackupPath {
+        BackupPath::new(path.to_string_lossy().to_string(), "data".to_string(), missing.to_string())
+    }
+
+    #[test]
+    fn entries_are_named_below_archive() {
+        let dir = test_dir("file-names");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/a.txt"), b"a").unwrap();
+        let options = CompressOptions::new(CompressType::Tar);
+        let mut names = collect_entries(vec![backup_path(&dir, consts::MISSING_PATH_ERROR)], &options).unwrap()
+            .into_iter().map(|entry| entry.name).collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["archive/data", "archive/data/sub", "archive/data/sub/a.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vanished_paths_follow_the_policy() {
+        let dir = test_dir("file-vanished");
+        let gone = dir.join("gone");
+        let options = CompressOptions::new(CompressType::Tar);
+        let err = collect_entries(vec![backup_path(&gone, consts::MISSING_PATH_ERROR)], &options).err().expect("collect should fail");
+        assert!(err.to_string().contains("gone"), "{}", err);
+        for policy in [consts::MISSING_PATH_WARN, consts::MISSING_PATH_IGNORE] {
+            assert!(collect_entries(vec![backup_path(&gone, policy)], &options).unwrap().is_empty());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_entries_follow_the_policy() {
+        // root reads anything
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let dir = test_dir("file-unreadable");
+        fs::write(dir.join("ok.txt"), b"ok").unwrap();
+        fs::write(dir.join("secret.txt"), b"secret").unwrap();
+        fs::set_permissions(dir.join("secret.txt"), fs::Permissions::from_mode(0o000)).unwrap();
+        let options = CompressOptions::new(CompressType::Tar);
+        let err = collect_entries(vec![backup_path(&dir, consts::MISSING_PATH_ERROR)], &options).err().expect("collect should fail");
+        assert!(err.to_string().contains("secret.txt"), "{}", err);
+        let entries = collect_entries(vec![backup_path(&dir, consts::MISSING_PATH_WARN)], &options).unwrap();
+        assert!(entries.iter().any(|entry| entry.name == "archive/data/ok.txt"));
+        assert!(!entries.iter().any(|entry| entry.name == "archive/data/secret.txt"));
+        fs::remove_dir_all(&dir).unwrap();
     }
-    tar.finish()?;
-    Ok(())
 }
\ No newline at end of file
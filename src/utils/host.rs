@@ -0,0 +1,36 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+
+// best effort host name, used to tell apart backups of different hosts on a shared target
+pub fn hostname() -> String {
+    if let Ok(name) = env::var("HOSTNAME") {
+        if !name.is_empty() {
+            return name;
+        }
+    }
+    for path in ["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if let Ok(name) = fs::read_to_string(path) {
+            let name = name.trim();
+            if !name.is_empty() {
+                return name.to_string();
+            }
+        }
+    }
+    String::from("unknown")
+}
+
+// a command line run by the system shell, used by hooks and command sources
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
\ No newline at end of file
@@ -1 +1,6 @@
-pub mod file;
\ No newline at end of file
+pub mod file;
+pub mod host;
+pub mod parallel;
+pub mod sparse;
+pub mod staging;#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::mem;
+use std::thread;
+use std::thread::JoinHandle;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+// used for store blocks, zstd has no stored level
+const ZSTD_FAST_LEVEL: i32 = -5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+/// Number of compression threads, `0` means one per cpu.
+pub fn compress_threads(threads: usize) -> usize {
+    if threads > 0 {
+        return threads;
+    }
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// A block of the output, a gzip member or zstd frame that decompresses on its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Block {
+    // position and size in the output
+    pub offset: u64,
+    pub size: u64,
+    // position and size of its data in the input
+    pub raw_offset: u64,
+    pub raw_size: u64,
+}
+
+/// Block parallel encoder. The input is cut into fixed size blocks that are compressed on up to
+/// `threads` threads, each block into its own gzip member or zstd frame. Concatenated members and
+/// frames are valid streams, so the output stays readable by gzip, zstd and tar.
+pub struct ParallelEncoder<W: Write> {
+    writer: W,
+    codec: Codec,
+    threads: usize,
+    block_size: usize,
+    store: bool,
+    buffer: Vec<u8>,
+    pending: VecDeque<(u64, JoinHandle<io::Result<Vec<u8>>>)>,
+    // input handed to blocks so far, and the blocks written
+    input: u64,
+    blocks: Vec<Block>,
+}
+
+impl<W: Write> ParallelEncoder<W> {
+    pub fn new(writer: W, codec: Codec, threads: usize) -> Self {
+        Self::with_block_size(writer, codec, threads, DEFAULT_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(writer: W, codec: Codec, threads: usize, block_size: usize) -> Self {
+        Self {
+            writer,
+            codec,
+            threads: threads.max(1),
+            block_size,
+            store: false,
+            buffer: Vec::with_capacity(block_size),
+            pending: VecDeque::new(),
+            input: 0,
+            blocks: vec![],
+        }
+    }
+
+    /// Number of input bytes written so far.
+    pub fn position(&self) -> u64 {
+        self.input + self.buffer.len() as u64
+    }
+
+    /// Write the following input into store blocks (not compressed for gzip, the fastest level
+    /// for zstd), or back into normally compressed blocks. The current block ends here.
+    pub fn set_store(&mut self, store: bool) -> io::Result<()> {
+        if store != self.store {
+            if !self.buffer.is_empty() {
+                self.spawn_block()?;
+            }
+            self.store = store;
+        }
+        Ok(())
+    }
+
+    /// Compress the remaining input, write every pending block and return the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_with_blocks().map(|(writer, _)| writer)
+    }
+
+    /// Like `finish`, also returns the blocks of the output in order.
+    pub fn finish_with_blocks(mut self) -> io::Result<(W, Vec<Block>)> {
+        // an empty input still needs one (empty) member to be a valid stream
+        if !self.buffer.is_empty() || (self.blocks.is_empty() && self.pending.is_empty()) {
+            self.spawn_block()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+        self.writer.flush()?;
+        Ok((self.writer, self.blocks))
+    }
+
+    fn spawn_block(&mut self) -> io::Result<()> {
+        if self.pending.len() >= self.threads {
+            self.write_oldest()?;
+        }
+        let block = mem::replace(&mut self.buffer, Vec::with_capacity(self.block_size));
+        let codec = self.codec;
+        let store = self.store;
+        let raw_size = block.len() as u64;
+        self.input += raw_size;
+        self.pending.push_back((raw_size, thread::spawn(move || compress_block(codec, store, &block))));
+        Ok(())
+    }
+
+    fn write_oldest(&mut self) -> io::Result<()> {
+        if let Some((raw_size, handle)) = self.pending.pop_front() {
+            let compressed = handle.join()
+                .map_err(|_| io::Error::other("compress thread panicked"))??;
+            self.writer.write_all(&compressed)?;
+            self.push_block(compressed.len() as u64, raw_size);
+        }
+        Ok(())
+    }
+
+    fn push_block(&mut self, size: u64, raw_size: u64) {
+        let (offset, raw_offset) = self.blocks.last()
+            .map(|last| (last.offset + last.size, last.raw_offset + last.raw_size))
+            .unwrap_or((0, 0));
+        self.blocks.push(Block { offset, size, raw_offset, raw_size });
+    }
+}
+
+impl<W: Write + Seek> ParallelEncoder<W> {
+    /// Write `data` as its own stored (uncompressed) gzip member or zstd frame and return its
+    /// offset in the writer. A stored block only depends on the length of its data, so it can be
+    /// replaced in place with `patch_stored` once the final data is known.
+    pub fn write_stored(&mut self, data: &[u8]) -> io::Result<u64> {
+        if !self.buffer.is_empty() {
+            self.spawn_block()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+        let offset = self.writer.stream_position()?;
+        let block = stored_block(self.codec, data)?;
+        self.writer.write_all(&block)?;
+        self.input += data.len() as u64;
+        self.push_block(block.len() as u64, data.len() as u64);
+        Ok(offset)
+    }
+
+    /// Overwrite the stored block written at `offset` with `data` of the same length.
+    pub fn patch_stored(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.writer.write_all(&stored_block(self.codec, data)?)?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.block_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() >= self.block_size {
+            self.spawn_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn compress_block(codec: Codec, store: bool, block: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::Gzip => {
+            let level = if store { Compression::none() } else { Compression::default() };
+            let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 2), level);
+            encoder.write_all(block)?;
+            encoder.finish()
+        }
+        Codec::Zstd => zstd::bulk::compress(block, if store { ZSTD_FAST_LEVEL } else { ZSTD_LEVEL }),
+    }
+}
+
+fn stored_block(codec: Codec, block: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() + 32), Compression::none());
+            encoder.write_all(block)?;
+            encoder.finish()
+        }
+        Codec::Zstd => {
+            // single segment frame with a 2 byte content size and one raw block, see RFC 8878
+            if block.len() < 256 || block.len() >= 256 + 65536 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "stored block size out of range"));
+            }
+            let mut frame = Vec::with_capacity(block.len() + 10);
+            frame.extend_from_slice(&0xFD2FB528u32.to_le_bytes());
+            frame.push(0x60);
+            frame.extend_from_slice(&((block.len() - 256) as u16).to_le_bytes());
+            let block_header = 1u32 | ((block.len() as u32) << 3);
+            frame.extend_from_slice(&block_header.to_le_bytes()[..3]);
+            frame.extend_from_slice(block);
+            Ok(frame)
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs::File;
+use std::io;
+use std::io::{Seek, SeekFrom};
+
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// Data regions `(offset, length)` of a file with holes, in order. `None` when the file has no
+/// holes or the filesystem can't tell. Regions are widened to 512 byte boundaries (but not past
+/// the end of the file), as tar requires for all but the last one. The file is rewound.
+pub fn data_regions(file: &File, size: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    if size == 0 {
+        return Ok(None);
+    }
+    let regions = seek_data_regions(file, size);
+    (&*file).seek(SeekFrom::Start(0))?;
+    let regions = match regions? {
+        Some(regions) => regions,
+        None => return Ok(None),
+    };
+    let mut aligned: Vec<(u64, u64)> = vec![];
+    for (offset, length) in regions {
+        let start = offset / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+        let end = ((offset + length).div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE).min(size);
+        match aligned.last_mut() {
+            Some(last) if last.0 + last.1 >= start => last.1 = end.max(last.0 + last.1) - last.0,
+            _ => aligned.push((start, end - start)),
+        }
+    }
+    if aligned.len() == 1 && aligned[0] == (0, size) {
+        return Ok(None);
+    }
+    Ok(Some(aligned))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek_data_regions(file: &File, size: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut regions = vec![];
+    let mut offset: u64 = 0;
+    while offset < size {
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // no data after offset, the rest of the file is a hole
+                Some(libc::ENXIO) => break,
+                // SEEK_DATA isn't supported by the filesystem
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        let data = data as u64;
+        if data >= size {
+            break;
+        }
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = (hole as u64).min(size);
+        regions.push((data, hole - data));
+        offset = hole;
+    }
+    Ok(Some(regions))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn seek_data_regions(_file: &File, _size: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
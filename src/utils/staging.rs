@@ -0,0 +1,173 @@
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{anyhow, Result};
+use fs2::FileExt;
+use log::{info, warn};
+
+use crate::consts;
+use crate::utils::file;
+
+/// Working dir of one backup run: `<staging-dir>/backer-run-<time>`.
+///
+/// The staging dir is locked while the run is alive, so leftover run dirs found when preparing
+/// belong to crashed runs and are removed. The run dir itself is removed on drop.
+pub struct Staging {
+    dir: PathBuf,
+    run_dir: PathBuf,
+    lock: File,
+}
+
+impl Staging {
+    pub fn prepare<P: AsRef<Path>>(staging_dir: P) -> Result<Self> {
+        let dir = staging_dir.as_ref().to_path_buf();
+        file::create_dir(&dir).map_err(|e| anyhow!("create staging dir [{}] failed: {}", dir.to_string_lossy(), e))?;
+        let lock = File::create(dir.join(consts::STAGING_LOCK_FILE))?;
+        lock.try_lock_exclusive()
+            .map_err(|_| anyhow!("staging dir [{}] is used by another backup job", dir.to_string_lossy()))?;
+        Self::clean_leftovers(&dir);
+        let run_dir = dir.join(format!("{}{}", consts::STAGING_RUN_PREFIX, chrono::Local::now().format("%Y%m%d%H%M%S")));
+        file::create_dir(&run_dir)?;
+        Ok(Self { dir, run_dir, lock })
+    }
+
+    pub fn run_dir(&self) -> &Path {
+        &self.run_dir
+    }
+
+    /// Fail when the filesystem of the staging dir has less than `required` bytes available.
+    pub fn check_free_space(&self, required: u64) -> Result<()> {
+        let available = fs2::available_space(&self.dir)?;
+        if available < required {
+            return Err(anyhow!(
+                "not enough free space in staging dir [{}]: {} bytes available, about {} bytes required",
+                self.dir.to_string_lossy(), available, required
+            ));
+        }
+        info!("staging dir has {} bytes available, about {} bytes required", available, required);
+        Ok(())
+    }
+
+    fn clean_leftovers(dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with(consts::STAGING_RUN_PREFIX) {
+                continue;
+            }
+            warn!("remove leftover of a crashed backup run: {}", entry.path().to_string_lossy());
+            let res = if entry.path().is_dir() {
+                fs::remove_dir_all(entry.path())
+            } else {
+                fs::remove_file(entry.path())
+            };
+            if let Err(e) = res {
+                warn!("remove [{}] failed: {}", entry.path().to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.run_dir);
+        let _ = self.lock.unlock();
+    }
+}
+
+/// Scratch dir of a command working with stored files, `<staging-dir>/backer-work-<pid>-<n>`.
+///
+/// Every work dir is unique, even within a process, and locked while it's alive. It doesn't take
+/// the lock of the staging dir, so it can be used while a backup job runs. Work dirs left unlocked
+/// by crashed commands are removed when a new one is created, and the work dir itself on drop.
+pub struct WorkDir {
+    dir: PathBuf,
+    lock: File,
+}
+
+static WORK_DIR_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl WorkDir {
+    pub fn create<P: AsRef<Path>>(staging_dir: P) -> Result<Self> {
+        let name = format!("{}{}-{}", consts::WORK_DIR_PREFIX, std::process::id(), WORK_DIR_COUNTER.fetch_add(1, Ordering::Relaxed));
+        let dir = staging_dir.as_ref().join(name);
+        file::create_dir(&dir).map_err(|e| anyhow!("create work dir [{}] failed: {}", dir.to_string_lossy(), e))?;
+        let lock = File::create(dir.join(consts::STAGING_LOCK_FILE))?;
+        lock.try_lock_exclusive()
+            .map_err(|_| anyhow!("work dir [{}] is used by another command", dir.to_string_lossy()))?;
+        Self::clean_leftovers(staging_dir.as_ref());
+        Ok(Self { dir, lock })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // work dirs whose lock can be taken belong to no living command. one that doesn't have a lock
+    // file yet is still being created.
+    fn clean_leftovers(staging_dir: &Path) {
+        let entries = match fs::read_dir(staging_dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with(consts::WORK_DIR_PREFIX) {
+                continue;
+            }
+            let lock = match File::open(entry.path().join(consts::STAGING_LOCK_FILE)) {
+                Ok(lock) => lock,
+                Err(_) => continue,
+            };
+            if lock.try_lock_exclusive().is_err() {
+                continue;
+            }
+            warn!("remove leftover of a crashed command: {}", entry.path().to_string_lossy());
+            if let Err(e) = fs::remove_dir_all(entry.path()) {
+                warn!("remove [{}] failed: {}", entry.path().to_string_lossy(), e);
+            }
+            let _ = lock.unlock();
+        }
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+        let _ = self.lock.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_dirs_are_unique_and_leftovers_are_removed() {
+        let staging_dir = std::env::temp_dir().join(format!("backer-staging-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&staging_dir);
+        let first = WorkDir::create(&staging_dir).unwrap();
+        let second = WorkDir::create(&staging_dir).unwrap();
+        assert_ne!(first.dir(), second.dir());
+        fs::write(first.dir().join("file"), b"first").unwrap();
+        let first_dir = first.dir().to_path_buf();
+        drop(second);
+        // a live work dir survives the others, also their cleanup
+        assert!(first_dir.join("file").exists());
+
+        // a crashed command leaves an unlocked work dir behind
+        let leftover = staging_dir.join(format!("{}0-0", consts::WORK_DIR_PREFIX));
+        fs::create_dir_all(&leftover).unwrap();
+        fs::write(leftover.join(consts::STAGING_LOCK_FILE), b"").unwrap();
+        let third = WorkDir::create(&staging_dir).unwrap();
+        assert!(!leftover.exists());
+        assert!(first_dir.join("file").exists());
+        drop(third);
+        drop(first);
+        assert!(!first_dir.exists());
+        fs::remove_dir_all(&staging_dir).unwrap();
+    }
+}
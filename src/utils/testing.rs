@@ -0,0 +1,10 @@
+use std::fs;
+use std::path::PathBuf;
+
+// an empty dir below the temp dir, names must be unique across the tests of the crate
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("backer-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1 @@
+pub mod verify;
\ No newline at end of file
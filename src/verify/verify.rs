@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::catalog::catalog::Catalog;
+use crate::config::config::BackerConfig;
+use crate::consts;
+use crate::crypto::crypto;
+use crate::crypto::crypto::Identity;
+use crate::manifest::manifest;
+use crate::manifest::manifest::Manifest;
+use crate::restore::restore;
+use crate::storage::storage;
+use crate::storage::storage::StoredArchive;
+use crate::utils::file;
+use crate::utils::file::CompressType;
+use crate::utils::staging::WorkDir;
+use crate::volume::volume;
+
+pub struct VerifyOptions {
+    pub targets: Vec<String>,
+    // `latest`, `all` or the name of an archive
+    pub archives: String,
+    pub identities: Vec<Identity>,
+    // manifests have to be signed by this key when it's set
+    pub public_key: String,
+}
+
+impl VerifyOptions {
+    pub fn new(targets: Vec<String>, archives: String) -> Self {
+        Self { targets, archives, identities: vec![], public_key: String::new() }
+    }
+}
+
+/// Outcome of the checks of one stored archive. A target that can't be listed gets one with an
+/// empty archive.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchiveCheck {
+    pub target: String,
+    pub archive: String,
+    pub ok: bool,
+    // what the hash of the stored archive was compared with: manifest, catalog or volumes
+    pub checked_against: String,
+    pub entries: u64,
+    pub bytes: u64,
+    pub warnings: Vec<String>,
+    pub error: String,
+}
+
+/// Fetch the selected archives back from every target and check them: the stored file against
+/// the hash recorded at upload, and every entry by reading it (against the manifest when there
+/// is one).
+pub fn verify(cfg: &BackerConfig, options: &VerifyOptions) -> Result<Vec<ArchiveCheck>> {
+    if cfg.backup_format == consts::BACKUP_FORMAT_REPOSITORY {
+        return Err(anyhow!("verifying the repository format is not supported, only archives can be verified"));
+    }
+    let mut checks = vec![];
+    for target in options.targets.iter() {
+        let res = storage::list_archives(cfg, target.as_str()).and_then(|archives| select_archives(archives, options));
+        let archives = match res {
+            Ok(archives) => archives,
+            Err(e) => {
+                error!("list archives of [{}] failed: {}", target, e);
+                checks.push(ArchiveCheck { target: target.clone(), error: e.to_string(), ..Default::default() });
+                continue;
+            }
+        };
+        for archive in archives {
+            info!("verify [{}] on [{}]", archive.name, target);
+            let mut check = ArchiveCheck { target: target.clone(), archive: archive.name.clone(), ..Default::default() };
+            match check_archive(cfg, &archive, options, &mut check) {
+                Ok(()) => {
+                    check.ok = true;
+                    info!("[{}] on [{}] is ok, {} entries ({} bytes) read", archive.name, target, check.entries, check.bytes);
+                }
+                Err(e) => {
+                    error!("[{}] on [{}] failed verification: {}", archive.name, target, e);
+                    check.error = e.to_string();
+                }
+            }
+            for warning in check.warnings.iter() {
+                warn!("[{}] on [{}]: {}", archive.name, target, warning);
+            }
+            checks.push(check);
+        }
+    }
+    Ok(checks)
+}
+
+fn select_archives(mut archives: Vec<StoredArchive>, options: &VerifyOptions) -> Result<Vec<StoredArchive>> {
+    match options.archives.as_str() {
+        consts::ARCHIVES_ALL => Ok(archives),
+        // archives are listed oldest first
+        consts::ARCHIVE_LATEST => Ok(archives.pop().into_iter().collect()),
+        name => {
+            let archive = archives.into_iter().find(|archive| archive.name == name)
+                .ok_or_else(|| anyhow!("archive [{}] not found", name))?;
+            Ok(vec![archive])
+        }
+    }
+}
+
+fn check_archive(cfg: &BackerConfig, archive: &StoredArchive, options: &VerifyOptions, check: &mut ArchiveCheck) -> Result<()> {
+    let target = archive.target.as_str();
+    let stored = |name: &str| archive.files.iter().any(|f| *f == name || *f == volume::volume_index_file_name(name));
+    let encrypted_name = format!("{}.{}", archive.name, consts::ENCRYPTED_ARCHIVE_SUFFIX);
+    let (uploaded, encrypted) = if stored(archive.name.as_str()) {
+        (archive.name.clone(), false)
+    } else if stored(encrypted_name.as_str()) {
+        (encrypted_name, true)
+    } else {
+        return Err(anyhow!("the archive itself is missing, only [{}] are stored", archive.files.join(", ")));
+    };
+    let split = archive.files.contains(&volume::volume_index_file_name(uploaded.as_str()));
+    let work_dir = WorkDir::create(cfg.staging_dir.as_str())?;
+    let archive_manifest = load_manifest(cfg, archive, options, work_dir.dir(), check)?;
+
+    // a volume that doesn't match its index fails here
+    let path = restore::fetch_stored(cfg, target, uploaded.as_str(), work_dir.dir())?;
+    let size = file::file_size(&path)?;
+    let sha256 = file::sha256_file(&path)?;
+    let recorded = archive_manifest.as_ref()
+        .filter(|m| m.archive.name == uploaded)
+        .map(|m| (consts::MANIFEST_SUFFIX, m.archive.sha256.clone(), Some(m.archive.size)));
+    let recorded = match recorded {
+        Some(recorded) => Some(recorded),
+        None => catalog_sha256(cfg, uploaded.as_str(), check).map(|sha256| ("catalog", sha256, None)),
+    };
+    match recorded {
+        Some((source, recorded_sha256, recorded_size)) => {
+            if let Some(recorded_size) = recorded_size.filter(|recorded_size| *recorded_size != size) {
+                return Err(anyhow!("[{}] is {} bytes, {} bytes were uploaded", uploaded, size, recorded_size));
+            }
+            if sha256 != recorded_sha256 {
+                return Err(anyhow!("sha256 of [{}] is {}, {} was uploaded", uploaded, sha256, recorded_sha256));
+            }
+            check.checked_against = source.to_string();
+        }
+        None if split => check.checked_against = consts::VOLUME_INDEX_SUFFIX.to_string(),
+        None => check.warnings.push(format!("no hash of [{}] was recorded, only its entries are checked", uploaded)),
+    }
+
+    let path = if encrypted {
+        if options.identities.is_empty() {
+            check.warnings.push(String::from("encrypted and no key given, the entries aren't checked"));
+            return Ok(());
+        }
+        let plain_path = work_dir.dir().join(archive.name.as_str());
+        crypto::decrypt_file(&path, &plain_path, &options.identities).map_err(|e| anyhow!("decrypt failed: {}", e))?;
+        plain_path
+    } else {
+        path
+    };
+    // a file that changed while archived is in a tar archive once per read
+    let mut hashes: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in archive_manifest.map(|m| m.entries).unwrap_or_default() {
+        if !entry.sha256.is_empty() {
+            hashes.entry(entry.path).or_default().push(entry.sha256);
+        }
+    }
+    read_entries(&path, &restore::compress_type_of(archive.name.as_str())?, &hashes, check)
+}
+
+// the manifest of the archive, with its signature checked when a public key is given
+fn load_manifest(cfg: &BackerConfig, archive: &StoredArchive, options: &VerifyOptions, dir: &Path, check: &mut ArchiveCheck) -> Result<Option<Manifest>> {
+    let manifest_name = manifest::manifest_file_name(archive.name.as_str());
+    let signature_name = manifest::signature_file_name(archive.name.as_str());
+    if !archive.files.contains(&manifest_name) {
+        if !options.public_key.is_empty() {
+            return Err(anyhow!("no signed manifest is stored"));
+        }
+        return Ok(None);
+    }
+    let manifest_path = dir.join(manifest_name.as_str());
+    storage::fetch_file(cfg, archive.target.as_str(), manifest_name.as_str(), &manifest_path)?;
+    let bytes = file::read_file(&manifest_path).map_err(|e| anyhow!("{}", e))?;
+    if options.public_key.is_empty() {
+        if archive.files.contains(&signature_name) {
+            check.warnings.push(String::from("the manifest is signed but no public key is given to check it"));
+        }
+        return Manifest::from_bytes(&bytes).map(Some);
+    }
+    if !archive.files.contains(&signature_name) {
+        return Err(anyhow!("the manifest isn't signed"));
+    }
+    let signature_path = dir.join(signature_name.as_str());
+    storage::fetch_file(cfg, archive.target.as_str(), signature_name.as_str(), &signature_path)?;
+    let signature = file::read_file(&signature_path).map_err(|e| anyhow!("{}", e))?;
+    manifest::verify_manifest(&bytes, &signature, options.public_key.as_str()).map(Some)
+}
+
+fn catalog_sha256(cfg: &BackerConfig, uploaded: &str, check: &mut ArchiveCheck) -> Option<String> {
+    if !cfg.catalog.enabled || !file::is_exist(cfg.catalog.path.as_str()) {
+        return None;
+    }
+    match Catalog::open(cfg.catalog.path.as_str()).and_then(|catalog| catalog.archive_sha256(uploaded)) {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            check.warnings.push(format!("read the catalog failed: {}", e));
+            None
+        }
+    }
+}
+
+// read every entry to its end, the decompressors and zip check their checksums on the way. files
+// listed in the manifest are hashed too.
+fn read_entries(path: &Path, compress_type: &CompressType, hashes: &HashMap<String, Vec<String>>, check: &mut ArchiveCheck) -> Result<()> {
+    let read_entry = |name: &str, reader: &mut dyn Read, check: &mut ArchiveCheck| -> Result<()> {
+        let mut hasher = Sha256::new();
+        let size = io::copy(reader, &mut hasher).map_err(|e| anyhow!("read entry [{}] failed: {}", name, e))?;
+        if let Some(recorded) = hashes.get(name) {
+            let sha256 = format!("{:x}", hasher.finalize());
+            if !recorded.contains(&sha256) {
+                return Err(anyhow!("sha256 of entry [{}] is {}, the manifest says {}", name, sha256, recorded.join(" or ")));
+            }
+        }
+        check.entries += 1;
+        check.bytes += size;
+        Ok(())
+    };
+    match compress_type {
+        CompressType::Zip => {
+            let mut zip = zip::ZipArchive::new(File::open(path)?)?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i)?;
+                let name = entry.name().to_string();
+                read_entry(name.as_str(), &mut entry, check)?;
+            }
+        }
+        _ => {
+            let mut tar = tar::Archive::new(file::tar_decoder(File::open(path)?, compress_type)?);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.to_string_lossy().to_string();
+                read_entry(name.as_str(), &mut entry, check)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use std::fmt;
 
-pub const BACKER_VERSION: &'static str = "0.1.1";
-pub const BACKER_SERVER_VERSION: &'static str = "0.1.1";
+pub const BACKER_VERSION: &str = "0.1.1";
+pub const BACKER_SERVER_VERSION: &str = "0.1.1";
 
 pub struct VersionInfo {
     pub name: &'static str,
@@ -0,0 +1 @@
+pub mod volume;
\ No newline at end of file
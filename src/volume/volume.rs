@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::consts;
+use crate::utils::file;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumePart {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Index of a split archive, uploaded as `<archive>.volumes`. Parts are listed in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumeIndex {
+    pub archive: String,
+    pub size: u64,
+    pub sha256: String,
+    pub volume_size: u64,
+    pub parts: Vec<VolumePart>,
+}
+
+pub fn volume_index_file_name(archive_file_name: &str) -> String {
+    format!("{}.{}", archive_file_name, consts::VOLUME_INDEX_SUFFIX)
+}
+
+pub fn volume_part_file_name(archive_file_name: &str, number: usize) -> String {
+    format!("{}.{:03}", archive_file_name, number)
+}
+
+impl VolumeIndex {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = file::read_file(path.as_ref()).map_err(|e| anyhow!("{}", e))?;
+        let index: Self = serde_json::from_slice(&bytes)?;
+        index.check_part_names()?;
+        Ok(index)
+    }
+
+    // the index is downloaded with the archive, its part names are joined to local dirs
+    fn check_part_names(&self) -> Result<()> {
+        for part in self.parts.iter() {
+            if !file::is_safe_relative_path(part.name.as_str()) || part.name.contains(['/', '\\']) {
+                return Err(anyhow!("volume index lists an invalid part name [{}]", part.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split `archive_path` into `<archive>.001`, `<archive>.002`, ... of at most `volume_size` bytes
+/// next to it, and write the `<archive>.volumes` index. Returns the paths of the parts followed by
+/// the index.
+pub fn split_archive<P: AsRef<Path>>(archive_path: P, volume_size: u64) -> Result<Vec<String>> {
+    let archive_path = archive_path.as_ref();
+    let archive_name = file::get_file_name(archive_path).map_err(|e| anyhow!("{}", e))?;
+    let dir = archive_path.parent().unwrap_or(Path::new("."));
+    let mut reader = BufReader::new(File::open(archive_path)?);
+    let mut archive_hasher = Sha256::new();
+    let mut index = VolumeIndex {
+        archive: archive_name.clone(),
+        size: 0,
+        sha256: String::new(),
+        volume_size,
+        parts: vec![],
+    };
+    let mut paths = vec![];
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let part_name = volume_part_file_name(archive_name.as_str(), index.parts.len() + 1);
+        let part_path = dir.join(part_name.as_str());
+        let mut writer = BufWriter::new(File::create(&part_path)?);
+        let mut part_hasher = Sha256::new();
+        let mut part_size: u64 = 0;
+        while part_size < volume_size {
+            let want = buffer.len().min((volume_size - part_size) as usize);
+            let n = reader.read(&mut buffer[..want])?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..n])?;
+            part_hasher.update(&buffer[..n]);
+            archive_hasher.update(&buffer[..n]);
+            part_size += n as u64;
+        }
+        writer.flush()?;
+        drop(writer);
+        if part_size == 0 && !index.parts.is_empty() {
+            file::rm_file(&part_path)?;
+            break;
+        }
+        index.size += part_size;
+        index.parts.push(VolumePart { name: part_name, size: part_size, sha256: format!("{:x}", part_hasher.finalize()) });
+        paths.push(part_path.to_string_lossy().to_string());
+        if part_size < volume_size {
+            break;
+        }
+    }
+    index.sha256 = format!("{:x}", archive_hasher.finalize());
+    let index_path = dir.join(volume_index_file_name(archive_name.as_str()));
+    file::create_write_file(&index_path, &serde_json::to_vec_pretty(&index)?).map_err(|e| anyhow!("{}", e))?;
+    paths.push(index_path.to_string_lossy().to_string());
+    Ok(paths)
+}
+
+/// Join the parts listed in `index`, found in `dir`, back into `output`. Every part and the
+/// joined archive are checked against the hashes in the index.
+pub fn join_volumes<P: AsRef<Path>, Q: AsRef<Path>>(index: &VolumeIndex, dir: P, output: Q) -> Result<()> {
+    index.check_part_names()?;
+    let mut writer = BufWriter::new(File::create(output.as_ref())?);
+    let mut archive_hasher = Sha256::new();
+    let mut size: u64 = 0;
+    for part in index.parts.iter() {
+        let part_path = dir.as_ref().join(part.name.as_str());
+        let mut reader = BufReader::new(File::open(&part_path).map_err(|e| anyhow!("open part [{}] failed: {}", part.name, e))?);
+        let mut part_hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut part_size: u64 = 0;
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..n])?;
+            part_hasher.update(&buffer[..n]);
+            archive_hasher.update(&buffer[..n]);
+            part_size += n as u64;
+        }
+        let part_sha256 = format!("{:x}", part_hasher.finalize());
+        if part_size != part.size || part_sha256 != part.sha256 {
+            return Err(anyhow!("part [{}] doesn't match the volume index", part.name));
+        }
+        size += part_size;
+    }
+    writer.flush()?;
+    let sha256 = format!("{:x}", archive_hasher.finalize());
+    if size != index.size || sha256 != index.sha256 {
+        return Err(anyhow!("joined archive doesn't match the volume index"));
+    }
+    Ok(())
+}
+
+/// Join the parts next to `<archive>.volumes` into `output`.
+pub fn join_volumes_from_index<P: AsRef<Path>, Q: AsRef<Path>>(index_path: P, output: Q) -> Result<()> {
+    let index = VolumeIndex::load(index_path.as_ref())?;
+    let dir = index_path.as_ref().parent().unwrap_or(Path::new("."));
+    join_volumes(&index, dir, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    #[test]
+    fn split_and_join() {
+        let dir = test_dir("volume-split");
+        let archive = dir.join("backup.tar.gz");
+        let data = (0..2500u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(&archive, &data).unwrap();
+        let paths = split_archive(&archive, 1000).unwrap();
+        // three parts and the index
+        assert_eq!(paths.len(), 4);
+        let index = VolumeIndex::load(dir.join("backup.tar.gz.volumes")).unwrap();
+        assert_eq!(index.parts.iter().map(|part| part.size).collect::<Vec<u64>>(), vec![1000, 1000, 500]);
+        let output = dir.join("joined");
+        join_volumes_from_index(dir.join("backup.tar.gz.volumes"), &output).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+
+        // a changed part is caught
+        std::fs::write(dir.join("backup.tar.gz.002"), vec![0u8; 1000]).unwrap();
+        assert!(join_volumes(&index, &dir, &output).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hostile_part_names_are_rejected() {
+        let dir = test_dir("volume-hostile");
+        for name in ["../escape", "/etc/passwd", "a/b", "", ".", "..\\escape"] {
+            let index = VolumeIndex {
+                archive: "backup.tar.gz".to_string(),
+                size: 0,
+                sha256: String::new(),
+                volume_size: 1000,
+                parts: vec![VolumePart { name: name.to_string(), size: 0, sha256: String::new() }],
+            };
+            let index_path = dir.join("backup.tar.gz.volumes");
+            std::fs::write(&index_path, serde_json::to_vec(&index).unwrap()).unwrap();
+            assert!(VolumeIndex::load(&index_path).is_err(), "{}", name);
+            assert!(join_volumes(&index, &dir, dir.join("joined")).is_err(), "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}